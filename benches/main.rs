@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use anna::index::ivf::Ivf;
+use anna::params::*;
 use anna::test_util::{gen_floats, gen_vectors};
 use anna::*;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
@@ -11,19 +12,25 @@ const DATASET_SIZE: usize = 1_000_000;
 
 fn ivf_train(ivf: &mut Ivf) {
     let option = TrainOption {
-        iteration_num: None,
-        nlist: CLUSTER_NUM,
         metric_type: metric::MetricType::L2,
+        params: BuildParams::Ivf(IvfBuildParams {
+            nlist: CLUSTER_NUM,
+            ..Default::default()
+        }),
     };
     ivf.train(&option);
 }
 
 fn ivf_search(ivf: &Ivf) {
     let query = gen_floats(DIM);
-    let deleted = roaring::RoaringBitmap::new();
+    let deleted = roaring::RoaringTreemap::new();
     let option = SearchOption {
-        nprobe: 32,
         topk: 10,
+        params: SearchParams::Ivf(IvfSearchParams {
+            nprobe: 32,
+            ..Default::default()
+        }),
+        refine_factor: None,
     };
     ivf.search(&query, &deleted, &option);
 }
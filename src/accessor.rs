@@ -1,5 +1,7 @@
+use crate::error::{Error, Result};
 use crate::VectorAccessor;
 use datafusion::arrow::array::*;
+use datafusion::arrow::datatypes::{DataType, Float32Type};
 use std::sync::Arc;
 
 pub struct MemoryVectorAccessor {
@@ -28,19 +30,77 @@ impl VectorAccessor for MemoryVectorAccessor {
     }
 }
 
+// creates an accessor for the FixedSizeBinary, FixedSizeList<Float32> or List<Float32> array
+pub fn from_arrow(array: &ArrayRef) -> Result<Arc<dyn VectorAccessor>> {
+    match array.data_type() {
+        DataType::FixedSizeBinary(_) => Ok(Arc::new(ArrowVectorAccessor::try_new(
+            array
+                .as_any()
+                .downcast_ref::<FixedSizeBinaryArray>()
+                .unwrap()
+                .clone(),
+        )?)),
+        DataType::FixedSizeList(_, _) => Ok(Arc::new(FixedSizeListVectorAccessor::try_new(
+            array
+                .as_any()
+                .downcast_ref::<FixedSizeListArray>()
+                .unwrap()
+                .clone(),
+        )?)),
+        DataType::List(_) => Ok(Arc::new(ListVectorAccessor::try_new(
+            array.as_list::<i32>().clone(),
+        )?)),
+        other => Err(Error::InvalidArgument(format!(
+            "unsupported vector type {}",
+            other
+        ))),
+    }
+}
+
+// FixedSizeBinary array of little-endian f32 vectors
 pub struct ArrowVectorAccessor {
     vectors: FixedSizeBinaryArray,
+    dim: usize,
 }
 
 impl ArrowVectorAccessor {
-    pub fn new(array: FixedSizeBinaryArray) -> Self {
-        Self { vectors: array }
+    pub fn try_new(array: FixedSizeBinaryArray) -> Result<Self> {
+        let value_length = array.value_length() as usize;
+        let dim = value_length / std::mem::size_of::<f32>();
+        if dim == 0 || dim * std::mem::size_of::<f32>() != value_length {
+            return Err(Error::InvalidArgument(format!(
+                "FixedSizeBinary({}) is not a valid f32 vector",
+                value_length
+            )));
+        }
+        if array.null_count() > 0 {
+            return Err(Error::InvalidArgument(format!(
+                "vector array contains {} null vectors",
+                array.null_count()
+            )));
+        }
+        if !array.is_empty()
+            && array
+                .value(0)
+                .as_ptr()
+                .align_offset(std::mem::align_of::<f32>())
+                != 0
+        {
+            return Err(Error::InvalidArgument(
+                "vector buffer is not aligned to f32".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            vectors: array,
+            dim,
+        })
     }
 }
 
 impl VectorAccessor for ArrowVectorAccessor {
     fn dim(&self) -> usize {
-        self.vectors.value_length() as usize / std::mem::size_of::<f32>()
+        self.dim
     }
 
     fn len(&self) -> usize {
@@ -49,6 +109,260 @@ impl VectorAccessor for ArrowVectorAccessor {
 
     fn get(&self, index: usize) -> &[f32] {
         let vec = self.vectors.value(index);
-        unsafe { std::slice::from_raw_parts(vec.as_ptr() as *const f32, self.dim()) }
+        // the alignment and length are checked in try_new
+        unsafe { std::slice::from_raw_parts(vec.as_ptr() as *const f32, self.dim) }
+    }
+}
+
+pub struct FixedSizeListVectorAccessor {
+    vectors: FixedSizeListArray,
+    dim: usize,
+}
+
+impl FixedSizeListVectorAccessor {
+    pub fn try_new(array: FixedSizeListArray) -> Result<Self> {
+        let dim = array.value_length() as usize;
+        if dim == 0 {
+            return Err(Error::InvalidArgument(
+                "vector dimension must be positive".to_string(),
+            ));
+        }
+        if array.value_type() != DataType::Float32 {
+            return Err(Error::InvalidArgument(format!(
+                "FixedSizeList<{}> is not a valid f32 vector",
+                array.value_type()
+            )));
+        }
+        if array.null_count() > 0 {
+            return Err(Error::InvalidArgument(format!(
+                "vector array contains {} null vectors",
+                array.null_count()
+            )));
+        }
+
+        Ok(Self {
+            vectors: array,
+            dim,
+        })
+    }
+}
+
+impl VectorAccessor for FixedSizeListVectorAccessor {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> &[f32] {
+        let offset = self.vectors.value_offset(index) as usize;
+        &self.vectors.values().as_primitive::<Float32Type>().values()[offset..offset + self.dim]
+    }
+}
+
+// List array of vectors with the same dimension,
+// the Parquet reader returns List for the FixedSizeList columns
+pub struct ListVectorAccessor {
+    vectors: ListArray,
+    dim: usize,
+}
+
+impl ListVectorAccessor {
+    pub fn try_new(array: ListArray) -> Result<Self> {
+        if array.value_type() != DataType::Float32 {
+            return Err(Error::InvalidArgument(format!(
+                "List<{}> is not a valid f32 vector",
+                array.value_type()
+            )));
+        }
+        if array.null_count() > 0 {
+            return Err(Error::InvalidArgument(format!(
+                "vector array contains {} null vectors",
+                array.null_count()
+            )));
+        }
+
+        let dim = if array.is_empty() {
+            0
+        } else {
+            array.value_length(0) as usize
+        };
+        for i in 0..array.len() {
+            if array.value_length(i) as usize != dim {
+                return Err(Error::InvalidArgument(format!(
+                    "vector dimension mismatch: {} vs {}",
+                    array.value_length(i),
+                    dim
+                )));
+            }
+        }
+
+        Ok(Self {
+            vectors: array,
+            dim,
+        })
+    }
+}
+
+impl VectorAccessor for ListVectorAccessor {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> &[f32] {
+        let offset = self.vectors.value_offsets()[index] as usize;
+        &self.vectors.values().as_primitive::<Float32Type>().values()[offset..offset + self.dim]
+    }
+}
+
+// concatenates the accessors without copying,
+// the global id of a vector is its offset in the concatenated sequence
+pub struct ChunkedVectorAccessor {
+    chunks: Vec<Arc<dyn VectorAccessor>>,
+    // offsets[i] is the global id of the first vector in chunks[i],
+    // offsets[chunks.len()] is the total number of vectors
+    offsets: Vec<usize>,
+    dim: usize,
+}
+
+impl ChunkedVectorAccessor {
+    pub fn try_new(chunks: Vec<Arc<dyn VectorAccessor>>) -> Result<Self> {
+        let dim = match chunks.first() {
+            Some(chunk) => chunk.dim(),
+            None => {
+                return Err(Error::InvalidArgument(
+                    "requires at least 1 chunk".to_string(),
+                ))
+            }
+        };
+
+        let mut offsets = Vec::with_capacity(chunks.len() + 1);
+        offsets.push(0);
+        for chunk in &chunks {
+            if chunk.dim() != dim {
+                return Err(Error::InvalidArgument(format!(
+                    "vector dimension mismatch: {} vs {}",
+                    chunk.dim(),
+                    dim
+                )));
+            }
+            offsets.push(offsets.last().unwrap() + chunk.len());
+        }
+
+        Ok(Self {
+            chunks,
+            offsets,
+            dim,
+        })
+    }
+
+    pub fn from_arrays(arrays: &[ArrayRef]) -> Result<Self> {
+        Self::try_new(arrays.iter().map(from_arrow).collect::<Result<_>>()?)
+    }
+
+    pub fn chunks(&self) -> &[Arc<dyn VectorAccessor>] {
+        &self.chunks
+    }
+
+    // returns (chunk index, offset in the chunk)
+    pub fn locate(&self, id: usize) -> (usize, usize) {
+        let chunk = self.offsets.partition_point(|offset| *offset <= id) - 1;
+        (chunk, id - self.offsets[chunk])
+    }
+
+    pub fn global_id(&self, chunk: usize, offset: usize) -> usize {
+        self.offsets[chunk] + offset
+    }
+}
+
+impl VectorAccessor for ChunkedVectorAccessor {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn len(&self) -> usize {
+        *self.offsets.last().unwrap()
+    }
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> &[f32] {
+        let (chunk, offset) = self.locate(index);
+        self.chunks[chunk].get(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::*;
+    use datafusion::arrow::buffer::Buffer;
+
+    #[test]
+    fn test_arrow_accessors() {
+        let list = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![
+                Some(vec![Some(0.0), Some(1.0)]),
+                Some(vec![Some(2.0), Some(3.0)]),
+            ],
+            2,
+        );
+        let binary = FixedSizeBinaryArray::try_from_iter(
+            vec![[4f32, 5f32], [6f32, 7f32]]
+                .into_iter()
+                .map(|v| v.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>()),
+        )
+        .unwrap();
+
+        let accessor = ChunkedVectorAccessor::from_arrays(&[
+            Arc::new(list.slice(1, 1)),
+            Arc::new(binary),
+            Arc::new(list),
+        ])
+        .unwrap();
+        assert_eq!(accessor.dim(), 2);
+        assert_eq!(accessor.len(), 5);
+        assert_eq!(accessor.locate(3), (2, 0));
+        assert_eq!(accessor.global_id(1, 1), 2);
+        let vectors: Vec<_> = (0..accessor.len())
+            .map(|i| accessor.get(i).to_vec())
+            .collect();
+        assert_eq!(
+            vectors,
+            vec![
+                vec![2.0, 3.0],
+                vec![4.0, 5.0],
+                vec![6.0, 7.0],
+                vec![0.0, 1.0],
+                vec![2.0, 3.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arrow_accessor_validation() {
+        // misaligned buffer
+        let buffer = Buffer::from_slice_ref([0u8; 9]).slice(1);
+        let data = ArrayData::builder(DataType::FixedSizeBinary(8))
+            .len(1)
+            .add_buffer(buffer)
+            .build()
+            .unwrap();
+        assert!(ArrowVectorAccessor::try_new(FixedSizeBinaryArray::from(data)).is_err());
+
+        let binary = FixedSizeBinaryArray::try_from_iter(vec![[0u8; 6]].into_iter()).unwrap();
+        assert!(ArrowVectorAccessor::try_new(binary).is_err());
+
+        let list = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![Some(vec![Some(0.0), Some(1.0)]), None],
+            2,
+        );
+        assert!(FixedSizeListVectorAccessor::try_new(list).is_err());
     }
 }
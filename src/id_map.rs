@@ -0,0 +1,378 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::{Error, Result};
+use crate::{AnnIndex, SearchOption};
+use async_trait::async_trait;
+use datafusion::arrow::array::{Array, AsArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, UInt64Type};
+use log::warn;
+use roaring::{RoaringBitmap, RoaringTreemap};
+use std::{collections::HashMap, hash::Hash, io, pin::Pin, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::RwLock;
+
+// version 2: the deleted ids are RoaringTreemap
+const VERSION: u16 = 2;
+
+// the business key of a vector, e.g. the primary key of the row
+#[async_trait]
+pub trait ExternalId: Clone + Eq + Hash + Send + Sync + 'static {
+    const TYPE: u8;
+
+    fn from_array(array: &dyn Array) -> Result<Vec<Self>>;
+
+    async fn write(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()>;
+
+    async fn read(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self>;
+}
+
+#[async_trait]
+impl ExternalId for u64 {
+    const TYPE: u8 = 1;
+
+    fn from_array(array: &dyn Array) -> Result<Vec<Self>> {
+        let array = cast(array, &DataType::UInt64)
+            .map_err(|err| Error::InvalidArgument(format!("invalid u64 ids: {}", err)))?;
+        if array.null_count() > 0 {
+            return Err(Error::InvalidArgument("ids contain null".to_string()));
+        }
+        Ok(array.as_primitive::<UInt64Type>().values().to_vec())
+    }
+
+    async fn write(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u64_le(*self).await
+    }
+
+    async fn read(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self> {
+        reader.read_u64_le().await
+    }
+}
+
+#[async_trait]
+impl ExternalId for String {
+    const TYPE: u8 = 2;
+
+    fn from_array(array: &dyn Array) -> Result<Vec<Self>> {
+        let array = cast(array, &DataType::Utf8)
+            .map_err(|err| Error::InvalidArgument(format!("invalid string ids: {}", err)))?;
+        if array.null_count() > 0 {
+            return Err(Error::InvalidArgument("ids contain null".to_string()));
+        }
+        Ok(array
+            .as_string::<i32>()
+            .iter()
+            .map(|id| id.unwrap().to_string())
+            .collect())
+    }
+
+    async fn write(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.len() as u32).await?;
+        writer.write_all(self.as_bytes()).await
+    }
+
+    async fn read(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self> {
+        let len = reader.read_u32_le().await? as usize;
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).await?;
+        String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// bidirectional mapping between the external ids and the internal offsets of the VectorAccessor
+pub struct IdMap<K: ExternalId> {
+    ids: Vec<K>,
+    offsets: HashMap<K, usize>,
+}
+
+impl<K: ExternalId> Default for IdMap<K> {
+    fn default() -> Self {
+        Self {
+            ids: Vec::new(),
+            offsets: HashMap::new(),
+        }
+    }
+}
+
+impl<K: ExternalId> IdMap<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ids[i] is the external id of offset i
+    pub fn try_from_ids(ids: Vec<K>) -> Result<Self> {
+        let mut map = Self::new();
+        map.ids.reserve(ids.len());
+        map.offsets.reserve(ids.len());
+        for id in ids {
+            map.push(id)?;
+        }
+        Ok(map)
+    }
+
+    pub fn try_from_array(array: &dyn Array) -> Result<Self> {
+        Self::try_from_ids(K::from_array(array)?)
+    }
+
+    // maps the id to the next offset
+    pub fn push(&mut self, id: K) -> Result<usize> {
+        let offset = self.ids.len();
+        if self.offsets.insert(id.clone(), offset).is_some() {
+            return Err(Error::InvalidArgument("duplicate id".to_string()));
+        }
+        self.ids.push(id);
+        Ok(offset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn id(&self, offset: usize) -> Option<&K> {
+        self.ids.get(offset)
+    }
+
+    pub fn offset(&self, id: &K) -> Option<usize> {
+        self.offsets.get(id).copied()
+    }
+
+    // the unknown ids are ignored
+    pub fn to_bitmap<'a>(&self, ids: impl IntoIterator<Item = &'a K>) -> RoaringTreemap {
+        ids.into_iter()
+            .filter_map(|id| self.offset(id))
+            .map(|offset| offset as u64)
+            .collect()
+    }
+
+    pub async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u8(K::TYPE).await?;
+        writer.write_u64_le(self.ids.len() as u64).await?;
+        for id in &self.ids {
+            id.write(writer).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn deserialize(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self> {
+        let typ = reader.read_u8().await?;
+        if typ != K::TYPE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("id type mismatch: {} vs {}", typ, K::TYPE),
+            ));
+        }
+
+        let len = reader.read_u64_le().await? as usize;
+        let mut ids = Vec::with_capacity(len);
+        for _ in 0..len {
+            ids.push(K::read(reader).await?);
+        }
+        Self::try_from_ids(ids).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// an index searched and updated by the external ids,
+// the id map and the deleted ids are persisted with the index
+pub struct MappedIndex<K: ExternalId> {
+    index: Arc<RwLock<dyn AnnIndex>>,
+    ids: IdMap<K>,
+    deleted: RoaringTreemap,
+}
+
+impl<K: ExternalId> MappedIndex<K> {
+    pub fn new(index: Arc<RwLock<dyn AnnIndex>>, ids: IdMap<K>) -> Self {
+        Self {
+            index,
+            ids,
+            deleted: RoaringTreemap::new(),
+        }
+    }
+
+    pub fn index(&self) -> &Arc<RwLock<dyn AnnIndex>> {
+        &self.index
+    }
+
+    pub fn ids(&self) -> &IdMap<K> {
+        &self.ids
+    }
+
+    // returns false if the id doesn't exist or has been deleted
+    pub fn delete(&mut self, id: &K) -> bool {
+        match self.ids.offset(id) {
+            Some(offset) => self.deleted.insert(offset as u64),
+            None => false,
+        }
+    }
+
+    pub fn is_deleted(&self, id: &K) -> bool {
+        self.ids
+            .offset(id)
+            .is_some_and(|offset| self.deleted.contains(offset as u64))
+    }
+
+    pub async fn search(&self, query_vector: &[f32], option: &SearchOption) -> Vec<K> {
+        let result = self
+            .index
+            .read()
+            .await
+            .search(query_vector, &self.deleted, option);
+        result
+            .into_iter()
+            .filter_map(|offset| self.ids.id(offset).cloned())
+            .collect()
+    }
+
+    // the ids in deleted are filtered out too
+    pub async fn search_with_deleted<'a>(
+        &self,
+        query_vector: &[f32],
+        deleted: impl IntoIterator<Item = &'a K>,
+        option: &SearchOption,
+    ) -> Vec<K> {
+        let deleted = self.ids.to_bitmap(deleted) | &self.deleted;
+        let result = self
+            .index
+            .read()
+            .await
+            .search(query_vector, &deleted, option);
+        result
+            .into_iter()
+            .filter_map(|offset| self.ids.id(offset).cloned())
+            .collect()
+    }
+
+    pub async fn serialize(&self, mut writer: Pin<Box<dyn AsyncWrite + Send>>) -> io::Result<()> {
+        writer.write_u16_le(VERSION).await?;
+        self.ids.serialize(&mut writer).await?;
+
+        let mut deleted = Vec::with_capacity(self.deleted.serialized_size());
+        self.deleted.serialize_into(&mut deleted)?;
+        writer.write_u64_le(deleted.len() as u64).await?;
+        writer.write_all(&deleted).await?;
+
+        self.index.read().await.serialize(writer).await
+    }
+
+    pub async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn AsyncRead + Send>>,
+    ) -> io::Result<()> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} id map, current version is {}",
+                version, VERSION
+            );
+        }
+        self.ids = IdMap::deserialize(&mut reader).await?;
+
+        let len = reader.read_u64_le().await? as usize;
+        let mut deleted = vec![0u8; len];
+        reader.read_exact(&mut deleted).await?;
+        self.deleted = match version {
+            1 => RoaringBitmap::deserialize_from(&deleted[..])?
+                .into_iter()
+                .map(u64::from)
+                .collect(),
+            _ => RoaringTreemap::deserialize_from(&deleted[..])?,
+        };
+
+        self.index.write().await.deserialize(reader).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::id_map::*;
+    use crate::index::{self, IndexType};
+    use crate::params::*;
+    use crate::test_util::gen_vectors;
+    use crate::{metric, TrainOption, VectorAccessor};
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 8;
+    const CLUSTER_NUM: usize = 8;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_mapped_index() {
+        let accessor = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let index = index::new(IndexType::IvfFlat, accessor.clone());
+        index.write().await.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+
+        let ids = IdMap::try_from_ids((0..DATASET_SIZE).map(|i| format!("item-{}", i)).collect())
+            .unwrap();
+        assert!(IdMap::try_from_ids(vec![1u64, 2, 1]).is_err());
+        let mut mapped = MappedIndex::new(index, ids);
+        assert!(mapped.delete(&"item-3".to_string()));
+        assert!(!mapped.delete(&"item-3".to_string()));
+        assert!(!mapped.delete(&"unknown".to_string()));
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("mapped.ivf");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        mapped
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let mut mapped = MappedIndex::<String>::new(
+            index::new(IndexType::IvfFlat, accessor.clone()),
+            IdMap::new(),
+        );
+        mapped
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(mapped.ids().len(), DATASET_SIZE);
+        assert!(mapped.is_deleted(&"item-3".to_string()));
+
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let same_cluster = |result: &[String]| {
+            result
+                .iter()
+                .filter(|id| mapped.ids().offset(id).unwrap() % CLUSTER_NUM == 3)
+                .count()
+        };
+        let result = mapped.search(accessor.get(3), &option).await;
+        assert!(!result.contains(&"item-3".to_string()));
+        assert_eq!(same_cluster(&result), CLUSTER_NUM - 1);
+
+        let deleted = ["item-11".to_string()];
+        let result = mapped
+            .search_with_deleted(accessor.get(3), &deleted, &option)
+            .await;
+        assert!(!result.contains(&"item-11".to_string()));
+        assert_eq!(same_cluster(&result), CLUSTER_NUM - 2);
+    }
+}
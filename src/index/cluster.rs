@@ -1,43 +1,82 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
+
+use roaring::RoaringTreemap;
 
 use crate::VectorAccessor;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+// the elements are the sorted ids of the vectors in the cluster,
+// compressed by roaring and decoded on the fly while iterating
 #[derive(Debug)]
 pub struct Cluster {
     pub centroid: Vec<f32>,
-    pub elements: Vec<usize>,
+    pub elements: RoaringTreemap,
+    // the deleted elements still in the list, removed by compact
+    pub deleted: RoaringTreemap,
 }
 
 impl Cluster {
     pub fn new() -> Cluster {
         Cluster {
             centroid: Vec::new(),
-            elements: Vec::new(),
+            elements: RoaringTreemap::new(),
+            deleted: RoaringTreemap::new(),
         }
     }
 
     pub fn with_centroid(centroid: &[f32]) -> Self {
         Self {
             centroid: Vec::from(centroid),
-            elements: Vec::new(),
+            elements: RoaringTreemap::new(),
+            deleted: RoaringTreemap::new(),
         }
     }
 
     pub fn len(&self) -> usize {
-        self.elements.len()
+        self.elements.len() as usize
     }
 
     pub fn add(&mut self, id: usize) {
-        self.elements.push(id);
+        self.elements.insert(id as u64);
+        self.deleted.remove(id as u64);
+    }
+
+    // marks the element as deleted, returns false if it's not in the cluster
+    pub fn delete(&mut self, id: usize) -> bool {
+        self.elements.contains(id as u64) && self.deleted.insert(id as u64)
+    }
+
+    pub fn is_deleted(&self, id: usize) -> bool {
+        self.deleted.contains(id as u64)
+    }
+
+    pub fn deleted_ratio(&self) -> f32 {
+        if self.elements.is_empty() {
+            return 0.0;
+        }
+        self.deleted.len() as f32 / self.elements.len() as f32
+    }
+
+    // removes the deleted elements from the list, returns the number of them
+    pub fn compact(&mut self) -> usize {
+        let removed = self.deleted.len() as usize;
+        self.elements -= &self.deleted;
+        self.deleted.clear();
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.elements.iter().map(|id| id as usize)
     }
 
+    // moves the larger half of the ids to the new cluster
     pub fn split(&mut self) -> Self {
-        let split_num = self.elements.len() / 2;
+        let split_num = self.len() / 2;
         let mut new = Self::new();
-        new.elements = self
-            .elements
-            .drain((self.elements.len() - split_num)..)
-            .collect();
+        if let Some(first) = self.elements.iter().nth(self.len() - split_num) {
+            new.elements = self.elements.iter().filter(|id| *id >= first).collect();
+            self.elements.remove_range(first..);
+        }
 
         new
     }
@@ -47,17 +86,58 @@ impl Cluster {
             panic!("can't calculate centroid for empty cluster");
         }
 
+        self.centroid.clear();
         self.centroid.resize(accessor.dim(), 0f32);
 
         for id in self.elements.iter() {
-            let vec = accessor.get(*id);
+            let vec = accessor.get(id as usize);
             for i in 0..accessor.dim() {
                 self.centroid[i] += vec[i];
             }
         }
 
         for i in 0..accessor.dim() {
-            self.centroid[i] /= self.elements.len() as f32;
+            self.centroid[i] /= self.len() as f32;
+        }
+    }
+
+    // writes the size, the centroid and the serialized elements,
+    // the deleted elements are left out
+    pub async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        let live = &self.elements - &self.deleted;
+        writer.write_u64_le(live.len()).await?;
+        for v in &self.centroid {
+            writer.write_f32_le(*v).await?;
         }
+
+        let mut elements = Vec::with_capacity(live.serialized_size());
+        live.serialize_into(&mut elements)?;
+        writer.write_u64_le(elements.len() as u64).await?;
+        writer.write_all(&elements).await
+    }
+
+    pub async fn deserialize(
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        dim: usize,
+    ) -> io::Result<Self> {
+        let mut cluster = Cluster::new();
+        let size = reader.read_u64_le().await? as usize;
+        cluster.centroid.reserve(dim);
+        for _ in 0..dim {
+            cluster.centroid.push(reader.read_f32_le().await?);
+        }
+
+        let len = reader.read_u64_le().await? as usize;
+        let mut elements = vec![0u8; len];
+        reader.read_exact(&mut elements).await?;
+        cluster.elements = RoaringTreemap::deserialize_from(&elements[..])?;
+        if cluster.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cluster size mismatch: {} vs {}", cluster.len(), size),
+            ));
+        }
+
+        Ok(cluster)
     }
 }
@@ -0,0 +1,800 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::pq::ProductQuantizer;
+use super::quantizer::select_nearest;
+use super::util;
+use super::vamana::VamanaGraph;
+use crate::accessor::MemoryVectorAccessor;
+use crate::params::{BuildParams, PqBuildParams, SearchParams, VamanaBuildParams};
+use crate::*;
+use log::warn;
+use std::{
+    cmp,
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::io::AsyncWriteExt;
+
+const VERSION: u16 = 1;
+
+// the version of the graph file layout
+const FILE_VERSION: u16 = 1;
+const MAGIC: u32 = u32::from_le_bytes(*b"VMNA");
+
+// the unit of the disk reads, the nodes never cross the sector boundaries
+pub const SECTOR_SIZE: usize = 4096;
+
+const PQ_ITERATION_NUM: usize = 25;
+
+// the vamana graph on disk, the vector and the neighbors of a node are stored
+// in the same sectors so each hop costs one read. only the pq codes are kept in
+// memory, which choose the nodes to read, and the read vectors re-rank the results
+pub struct DiskAnn {
+    // read by the training, the search reads the vectors from the graph file
+    vectors: Arc<dyn VectorAccessor>,
+    path: PathBuf,
+    file: Option<Arc<fs::File>>,
+    metric_type: metric::MetricType,
+    params: Option<(VamanaBuildParams, PqBuildParams)>,
+    layout: Layout,
+    medoid: usize,
+    pq: ProductQuantizer,
+    // the codes of all vectors, indexed by id
+    codes: Vec<u8>,
+}
+
+// the first sector is the header, followed by the blocks of the nodes.
+// a node is its vector, the number of neighbors and max_degree neighbor ids,
+// the nodes are packed into sectors, a larger node occupies whole sectors
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Layout {
+    dim: usize,
+    max_degree: usize,
+    len: usize,
+}
+
+impl Layout {
+    fn node_size(&self) -> usize {
+        (self.dim + 1 + self.max_degree) * 4
+    }
+
+    // the number of nodes in a block, and the size of the block
+    fn block(&self) -> (usize, usize) {
+        let node_size = self.node_size();
+        match SECTOR_SIZE / node_size {
+            0 => (1, node_size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE),
+            nodes => (nodes, SECTOR_SIZE),
+        }
+    }
+
+    // the offset of the block in the file, and the offset of the node in the block
+    fn locate(&self, id: usize) -> (u64, usize) {
+        let (nodes, size) = self.block();
+        let offset = SECTOR_SIZE + id / nodes * size;
+        (offset as u64, id % nodes * self.node_size())
+    }
+
+    fn file_size(&self) -> u64 {
+        let (nodes, size) = self.block();
+        (SECTOR_SIZE + self.len.div_ceil(nodes) * size) as u64
+    }
+}
+
+struct Node {
+    vector: Vec<f32>,
+    neighbors: Vec<u32>,
+}
+
+impl Node {
+    fn encode(vector: &[f32], neighbors: &[u32], buf: &mut [u8]) {
+        let mut words = buf.chunks_exact_mut(4);
+        for (v, word) in vector.iter().zip(&mut words) {
+            word.copy_from_slice(&v.to_le_bytes());
+        }
+        let degree = neighbors.len() as u32;
+        words.next().unwrap().copy_from_slice(&degree.to_le_bytes());
+        for (word, id) in words.zip(neighbors) {
+            word.copy_from_slice(&id.to_le_bytes());
+        }
+    }
+
+    fn decode(layout: &Layout, block: &[u8], offset: usize) -> io::Result<Self> {
+        let mut words = block[offset..offset + layout.node_size()].array_chunks::<4>();
+        let vector = (&mut words)
+            .take(layout.dim)
+            .map(|w| f32::from_le_bytes(*w))
+            .collect();
+        let degree = u32::from_le_bytes(*words.next().unwrap()) as usize;
+        if degree > layout.max_degree {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid node degree {}", degree),
+            ));
+        }
+        let neighbors: Vec<_> = words.take(degree).map(|w| u32::from_le_bytes(*w)).collect();
+        if neighbors.iter().any(|id| *id as usize >= layout.len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "neighbor id out of range",
+            ));
+        }
+        Ok(Self { vector, neighbors })
+    }
+}
+
+impl DiskAnn {
+    // the graph is written to the path by the training,
+    // and read from the path by the deserialization
+    pub fn new(vectors: Arc<dyn VectorAccessor>, path: impl AsRef<Path>) -> Self {
+        Self {
+            vectors,
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            metric_type: metric::MetricType::None,
+            params: None,
+            layout: Layout::default(),
+            medoid: 0,
+            pq: ProductQuantizer::default(),
+            codes: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn pq(&self) -> &ProductQuantizer {
+        &self.pq
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.params.is_some()
+    }
+
+    fn validate(
+        dim: usize,
+        option: &TrainOption,
+    ) -> error::Result<(VamanaBuildParams, PqBuildParams)> {
+        option.validate(dim)?;
+        if option.metric_type != metric::MetricType::L2 {
+            return Err(error::Error::Unsupported(
+                "diskann only supports L2 metric".to_string(),
+            ));
+        }
+        match option.params {
+            BuildParams::Vamana(vamana, pq) => Ok((vamana, pq)),
+            params => Err(error::Error::InvalidArgument(format!(
+                "diskann index requires vamana build params, got {:?}",
+                params
+            ))),
+        }
+    }
+
+    fn code(&self, id: usize) -> &[u8] {
+        let size = self.pq.code_size();
+        &self.codes[id * size..(id + 1) * size]
+    }
+
+    fn file(&self) -> io::Result<Arc<fs::File>> {
+        self.file
+            .clone()
+            .ok_or_else(|| io::Error::other("graph file is not opened"))
+    }
+
+    fn read_node(&self, id: usize) -> io::Result<Node> {
+        let (offset, node_offset) = self.layout.locate(id);
+        let mut block = vec![0u8; self.layout.block().1];
+        util::read_at(self.file()?.as_ref(), &mut block, offset)?;
+        Node::decode(&self.layout, &block, node_offset)
+    }
+
+    async fn read_node_async(&self, id: usize) -> io::Result<Node> {
+        let file = self.file()?;
+        let layout = self.layout;
+        let (offset, node_offset) = layout.locate(id);
+        let block = tokio::task::spawn_blocking(move || {
+            let mut block = vec![0u8; layout.block().1];
+            util::read_at(&file, &mut block, offset).map(|_| block)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Node::decode(&layout, &block, node_offset)
+    }
+
+    // the index can't be searched once the file doesn't match it
+    fn reset(&mut self) {
+        self.params = None;
+        self.file = None;
+    }
+}
+
+// opens the graph file, and checks its header matches the index
+fn open_graph(
+    path: &Path,
+    layout: &Layout,
+    metric_type: metric::MetricType,
+    medoid: usize,
+) -> io::Result<fs::File> {
+    let file = fs::File::open(path)?;
+    let mut header = vec![0u8; SECTOR_SIZE];
+    util::read_at(&file, &mut header, 0)?;
+    if decode_header(&header)? != (*layout, metric_type, medoid) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("graph file {} doesn't match the index", path.display()),
+        ));
+    }
+    if file.metadata()?.len() < layout.file_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("graph file {} is truncated", path.display()),
+        ));
+    }
+    Ok(file)
+}
+
+// the candidates are ordered by the pq distances, each step reads the nearest
+// unread ones, until the nearest list_size candidates are all read
+struct BeamSearch<'a> {
+    index: &'a DiskAnn,
+    query: &'a [f32],
+    table: Vec<f32>,
+    list_size: usize,
+    beam_width: usize,
+    // the pq distance, the id and whether it's read
+    candidates: Vec<(f32, usize, bool)>,
+    seen: HashSet<usize>,
+    // the read nodes with the exact distances
+    results: Vec<(usize, f32)>,
+}
+
+impl<'a> BeamSearch<'a> {
+    fn new(index: &'a DiskAnn, query: &'a [f32], option: &SearchOption) -> Self {
+        let params = match option.params {
+            SearchParams::Vamana(params) => params,
+            params => panic!(
+                "diskann index requires vamana search params, got {:?}",
+                params
+            ),
+        };
+        if !index.is_trained() {
+            panic!("can't search untrained diskann index");
+        }
+
+        let table = index.pq.distance_table(query);
+        let mut candidates = Vec::new();
+        if index.layout.len > 0 {
+            let distance = index.pq.table_distance(&table, index.code(index.medoid));
+            candidates.push((distance, index.medoid, false));
+        }
+        Self {
+            index,
+            query,
+            table,
+            list_size: cmp::max(params.search_list_size, option.topk),
+            beam_width: params.beam_width,
+            candidates,
+            seen: HashSet::from([index.medoid]),
+            results: Vec::new(),
+        }
+    }
+
+    fn next_beam(&mut self) -> Vec<usize> {
+        self.candidates
+            .sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        self.candidates.truncate(self.list_size);
+        self.candidates
+            .iter_mut()
+            .filter(|c| !c.2)
+            .take(self.beam_width)
+            .map(|c| {
+                c.2 = true;
+                c.1
+            })
+            .collect()
+    }
+
+    fn visit(&mut self, id: usize, node: &Node) {
+        let distance = self.index.metric_type.distance(self.query, &node.vector);
+        self.results.push((id, distance));
+        for &neighbor in &node.neighbors {
+            let neighbor = neighbor as usize;
+            if self.seen.insert(neighbor) {
+                let code = self.index.code(neighbor);
+                let distance = self.index.pq.table_distance(&self.table, code);
+                self.candidates.push((distance, neighbor, false));
+            }
+        }
+    }
+
+    // the deleted nodes are traversed, but not returned
+    fn finish(mut self, deleted: &roaring::RoaringTreemap, topk: usize) -> Vec<usize> {
+        self.results.retain(|(id, _)| !deleted.contains(*id as u64));
+        select_nearest(self.results, topk)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+fn encode_header(layout: &Layout, metric_type: metric::MetricType, medoid: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE);
+    header.extend(MAGIC.to_le_bytes());
+    header.extend(FILE_VERSION.to_le_bytes());
+    header.push(metric_type as u8);
+    header.extend((layout.dim as u32).to_le_bytes());
+    header.extend((layout.max_degree as u32).to_le_bytes());
+    header.extend((layout.len as u64).to_le_bytes());
+    header.extend((medoid as u64).to_le_bytes());
+    header.resize(SECTOR_SIZE, 0);
+    header
+}
+
+fn decode_header(header: &[u8]) -> io::Result<(Layout, metric::MetricType, usize)> {
+    let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+    if u32_at(0) != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a graph file",
+        ));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > FILE_VERSION {
+        warn!(
+            "read newer version {} graph file, current version is {}",
+            version, FILE_VERSION
+        );
+    }
+
+    let metric_type = metric::MetricType::from(header[6]);
+    let layout = Layout {
+        dim: u32_at(7) as usize,
+        max_degree: u32_at(11) as usize,
+        len: u64_at(15) as usize,
+    };
+    Ok((layout, metric_type, u64_at(23) as usize))
+}
+
+fn write_graph(
+    path: &Path,
+    layout: &Layout,
+    metric_type: metric::MetricType,
+    vectors: &dyn VectorAccessor,
+    graph: &VamanaGraph,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    writer.write_all(&encode_header(layout, metric_type, graph.medoid()))?;
+
+    let (nodes, size) = layout.block();
+    let node_size = layout.node_size();
+    let mut block = vec![0u8; size];
+    for start in (0..layout.len).step_by(nodes) {
+        block.fill(0);
+        for id in start..cmp::min(start + nodes, layout.len) {
+            let offset = (id - start) * node_size;
+            Node::encode(
+                vectors.get(id),
+                graph.neighbors(id),
+                &mut block[offset..offset + node_size],
+            );
+        }
+        writer.write_all(&block)?;
+    }
+
+    writer.flush()?;
+    writer.get_ref().sync_all()
+}
+
+#[async_trait]
+impl AnnIndex for DiskAnn {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train diskann index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let dim = self.vectors.dim();
+        let (vamana_params, pq_params) = Self::validate(dim, option)?;
+
+        // the pq is trained by the first vectors only
+        let sample: Arc<dyn VectorAccessor> = match transform::train_size(self.vectors.as_ref()) {
+            n if n == self.vectors.len() => self.vectors.clone(),
+            n => {
+                let mut data = Vec::with_capacity(n * dim);
+                for i in 0..n {
+                    data.extend_from_slice(self.vectors.get(i));
+                }
+                Arc::new(MemoryVectorAccessor::new(dim, data))
+            }
+        };
+        let pq = ProductQuantizer::new(dim, pq_params.m, pq_params.nbits);
+        let mut pq = pq.expect("the pq params have been validated");
+        pq.train_with_context(sample, PQ_ITERATION_NUM, ctx)?;
+
+        let graph = VamanaGraph::build(
+            option.metric_type,
+            self.vectors.as_ref(),
+            &vamana_params,
+            ctx,
+        )?;
+        let layout = Layout {
+            dim,
+            max_degree: vamana_params.max_degree,
+            len: self.vectors.len(),
+        };
+        // the old graph is overwritten from here
+        let written = write_graph(
+            &self.path,
+            &layout,
+            option.metric_type,
+            self.vectors.as_ref(),
+            &graph,
+        )
+        .and_then(|_| open_graph(&self.path, &layout, option.metric_type, graph.medoid()));
+        let file = match written {
+            Ok(file) => file,
+            Err(err) => {
+                self.reset();
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!(
+                        "failed to write graph file {}: {}",
+                        self.path.display(),
+                        err
+                    ),
+                )
+                .into());
+            }
+        };
+
+        let size = pq.code_size();
+        let mut codes = vec![0u8; self.vectors.len() * size];
+        for (i, code) in codes.chunks_exact_mut(size).enumerate() {
+            pq.encode(self.vectors.get(i), code);
+        }
+        self.metric_type = option.metric_type;
+        self.params = Some((vamana_params, pq_params));
+        self.layout = layout;
+        self.medoid = graph.medoid();
+        self.pq = pq;
+        self.codes = codes;
+        self.file = Some(Arc::new(file));
+        Ok(())
+    }
+
+    // the nodes that can't be read are skipped with a warning, e.g. the graph
+    // file is truncated, search_async returns the read errors instead
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        let mut search = BeamSearch::new(self, query_vector, option);
+        loop {
+            let beam = search.next_beam();
+            if beam.is_empty() {
+                break;
+            }
+            for id in beam {
+                match self.read_node(id) {
+                    Ok(node) => search.visit(id, &node),
+                    Err(err) => warn!("failed to read node {}: {}", id, err),
+                }
+            }
+        }
+        search.finish(deleted, option.topk)
+    }
+
+    // the same as search, but the nodes of each step are read concurrently
+    // by the blocking pool of tokio, and the read errors are returned
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        let mut search = BeamSearch::new(self, query_vector, option);
+        loop {
+            let beam = search.next_beam();
+            if beam.is_empty() {
+                break;
+            }
+            let nodes =
+                futures::future::try_join_all(beam.iter().map(|id| self.read_node_async(*id)))
+                    .await?;
+            for (id, node) in beam.into_iter().zip(nodes) {
+                search.visit(id, &node);
+            }
+        }
+        Ok(search.finish(deleted, option.topk))
+    }
+
+    fn build_params(&self) -> Option<BuildParams> {
+        self.params
+            .map(|(vamana, pq)| BuildParams::Vamana(vamana, pq))
+    }
+
+    // only the memory part is written, the graph file stays at the path
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u8(self.metric_type as u8).await?;
+        writer.write_u32_le(self.vectors.dim() as u32).await?;
+        self.build_params()
+            .ok_or_else(|| io::Error::other("index is not trained"))?
+            .serialize(&mut writer)
+            .await?;
+        writer.write_u64_le(self.layout.len as u64).await?;
+        writer.write_u64_le(self.medoid as u64).await?;
+
+        self.pq.serialize(&mut writer).await?;
+        writer.write_u64_le(self.codes.len() as u64).await?;
+        writer.write_all(&self.codes).await?;
+
+        writer.flush().await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} diskann index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        let metric_type = metric::MetricType::from(reader.read_u8().await?);
+        let dim = reader.read_u32_le().await? as usize;
+        if dim != self.vectors.dim() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expect {}-d diskann index, got {}-d",
+                    self.vectors.dim(),
+                    dim
+                ),
+            ));
+        }
+        let (vamana, pq) = match BuildParams::deserialize(&mut reader).await? {
+            BuildParams::Vamana(vamana, pq) => (vamana, pq),
+            params => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected build params for diskann index: {:?}", params),
+                ))
+            }
+        };
+        let len = reader.read_u64_le().await? as usize;
+        let medoid = reader.read_u64_le().await? as usize;
+        let layout = Layout {
+            dim,
+            max_degree: vamana.max_degree,
+            len,
+        };
+
+        let product_quantizer = ProductQuantizer::deserialize(&mut reader).await?;
+        let size = reader.read_u64_le().await? as usize;
+        if size != len * product_quantizer.code_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("codes size mismatch: {}", size),
+            ));
+        }
+        let mut codes = vec![0u8; size];
+        reader.read_exact(&mut codes).await?;
+
+        // the file may be replaced since the index was serialized
+        let file = match open_graph(&self.path, &layout, metric_type, medoid) {
+            Ok(file) => file,
+            Err(err) => {
+                self.reset();
+                return Err(err);
+            }
+        };
+        self.metric_type = metric_type;
+        self.params = Some((vamana, pq));
+        self.layout = layout;
+        self.medoid = medoid;
+        self.pq = product_quantizer;
+        self.codes = codes;
+        self.file = Some(Arc::new(file));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::diskann::*;
+    use crate::params::VamanaSearchParams;
+    use crate::test_util::gen_floats;
+    use crate::tune;
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[tokio::test]
+    async fn test_diskann() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("diskann.graph");
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Vamana(
+                VamanaBuildParams {
+                    max_degree: 16,
+                    search_list_size: 32,
+                    batch_size: 128,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 8, nbits: 4 },
+            ),
+        };
+        let mut index = DiskAnn::new(
+            vectors.clone(),
+            temp_dir.path().join("missing/diskann.graph"),
+        );
+        assert!(matches!(
+            index.train_with_context(&option, &TrainContext::default()),
+            Err(error::Error::Io(_))
+        ));
+        assert!(!index.is_trained());
+
+        let mut index = DiskAnn::new(vectors.clone(), &path);
+        index.train(&option);
+        // 20 nodes of 196 bytes per sector
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            ((1 + DATASET_SIZE.div_ceil(20)) * SECTOR_SIZE) as u64
+        );
+
+        let topk = 10;
+        let queries = MemoryVectorAccessor::new(DIM, gen_floats(32 * DIM));
+        let truth = tune::ground_truth(metric::MetricType::L2, vectors.as_ref(), &queries, topk);
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Vamana(VamanaSearchParams {
+                search_list_size: 128,
+                beam_width: 4,
+            }),
+            refine_factor: None,
+        };
+        let deleted = roaring::RoaringTreemap::new();
+        let mut recall = 0.0;
+        for (q, truth) in truth.iter().enumerate() {
+            let result = index.search(queries.get(q), &deleted, &option);
+            let async_result = index
+                .search_async(queries.get(q), &deleted, &option)
+                .await
+                .unwrap();
+            assert_eq!(result, async_result);
+            recall += tune::recall(&result, truth);
+        }
+        let recall = recall / truth.len() as f32;
+        assert!(recall > 0.9, "recall: {}", recall);
+
+        // the exact re-ranking finds the vector itself
+        let mut deleted = roaring::RoaringTreemap::new();
+        deleted.insert(7);
+        for id in [0, 7, DATASET_SIZE - 1] {
+            let result = index.search(vectors.get(id), &deleted, &option);
+            assert_eq!(result.len(), topk);
+            assert_eq!(result[0] == id, id != 7);
+            assert!(!result.contains(&7));
+        }
+
+        let index_path = temp_dir.path().join("diskann.index");
+        let file = tokio::fs::File::create(&index_path).await.unwrap();
+        index
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let mut deserialized = DiskAnn::new(vectors.clone(), &path);
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        deserialized
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(deserialized.build_params(), index.build_params());
+        for q in 0..queries.len() {
+            assert_eq!(
+                deserialized.search(queries.get(q), &deleted, &option),
+                index.search(queries.get(q), &deleted, &option)
+            );
+        }
+
+        // the graph file must match the index
+        let mut other = DiskAnn::new(vectors.clone(), temp_dir.path().join("missing.graph"));
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        assert!(other
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .is_err());
+
+        let mut other = DiskAnn::new(vectors.clone(), temp_dir.path().join("other.graph"));
+        other.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Vamana(
+                VamanaBuildParams {
+                    max_degree: 8,
+                    search_list_size: 16,
+                    batch_size: 128,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 8, nbits: 4 },
+            ),
+        });
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        let err = other
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!other.is_trained());
+        assert!(other.validate_search(&option).is_err());
+
+        // the nodes are gone after the file is truncated
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(SECTOR_SIZE as u64)
+            .unwrap();
+        assert!(deserialized
+            .search(queries.get(0), &deleted, &option)
+            .is_empty());
+        assert!(deserialized
+            .search_async(queries.get(0), &deleted, &option)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout {
+            dim: 1024,
+            max_degree: 64,
+            len: 10,
+        };
+        // 4356 bytes in 2 sectors
+        assert_eq!(layout.block(), (1, 2 * SECTOR_SIZE));
+        assert_eq!(layout.locate(3), ((7 * SECTOR_SIZE) as u64, 0));
+        assert_eq!(layout.file_size(), (21 * SECTOR_SIZE) as u64);
+
+        let layout = Layout { dim: 32, ..layout };
+        assert_eq!(layout.node_size(), 388);
+        assert_eq!(layout.block(), (10, SECTOR_SIZE));
+        assert_eq!(layout.locate(13), ((2 * SECTOR_SIZE) as u64, 3 * 388));
+    }
+}
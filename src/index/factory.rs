@@ -0,0 +1,467 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// builds composed indexes from spec strings like "PCA256,IVF4096,PQ32x8",
+// the components are separated by commas, in order:
+//   pre-transforms, any number of: L2norm, PCA<dim>, RR, OPQ<m>
+//   the index: Flat, IVF<nlist> followed by an encoding (Flat or PQ<m>[x<nbits>]), HNSW<M>
+//   optional re-ranking: Refine(<spec>), only Refine(Flat) can be built
+// the built index is untrained, it should be trained with the build params
+// of the spec, see IndexSpec::train_option
+
+use super::{flat::Flat, ivf::Ivf, ivf_pq::IvfPq, refine, transformed};
+use crate::{
+    error::{Error, Result},
+    metric::MetricType,
+    params::{
+        BuildParams, HnswBuildParams, IvfBuildParams, IvfSearchParams, PqBuildParams, SearchParams,
+    },
+    transform::{normalize, opq, pca, rotation, VectorTransform},
+    AnnIndex, TrainOption, VectorAccessor,
+};
+use std::{fmt, str::FromStr, sync::Arc};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransformSpec {
+    L2Normalize,
+    Pca(usize),
+    RandomRotation,
+    Opq(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodingSpec {
+    Flat,
+    Pq { m: usize, nbits: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaseSpec {
+    Flat,
+    Ivf {
+        nlist: usize,
+        encoding: EncodingSpec,
+    },
+    Hnsw {
+        m: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSpec {
+    pub transforms: Vec<TransformSpec>,
+    pub base: BaseSpec,
+    pub refine: Option<Box<IndexSpec>>,
+}
+
+impl IndexSpec {
+    pub fn build_params(&self) -> BuildParams {
+        self.base.build_params()
+    }
+
+    pub fn train_option(&self, metric_type: MetricType) -> TrainOption {
+        TrainOption {
+            metric_type,
+            params: self.build_params(),
+        }
+    }
+
+    // the default search params of the index
+    pub fn search_params(&self) -> SearchParams {
+        match self.base {
+            BaseSpec::Flat => SearchParams::Flat,
+            BaseSpec::Ivf { .. } => SearchParams::Ivf(IvfSearchParams::default()),
+            BaseSpec::Hnsw { .. } => SearchParams::Hnsw(Default::default()),
+        }
+    }
+
+    pub fn build(&self, vectors: Arc<dyn VectorAccessor>) -> Result<Box<dyn AnnIndex>> {
+        let refine = match self.refine.as_deref() {
+            None => None,
+            // re-ranks with the exact distances to the original vectors
+            Some(IndexSpec {
+                transforms,
+                base: BaseSpec::Flat,
+                refine: None,
+            }) if transforms.is_empty() => Some(vectors.clone()),
+            Some(spec) => return Err(Error::Unsupported(format!("Refine({}) index", spec))),
+        };
+        let index = self.build_base(vectors)?;
+        Ok(match refine {
+            Some(vectors) => Box::new(refine::Refine::new(index, vectors)),
+            None => index,
+        })
+    }
+
+    fn build_base(&self, vectors: Arc<dyn VectorAccessor>) -> Result<Box<dyn AnnIndex>> {
+        if let BaseSpec::Hnsw { .. } = self.base {
+            return Err(Error::Unsupported("HNSW index".to_string()));
+        }
+
+        let mut dim = vectors.dim();
+        let mut transforms: Vec<Box<dyn VectorTransform>> =
+            Vec::with_capacity(self.transforms.len());
+        for spec in &self.transforms {
+            let transform: Box<dyn VectorTransform> = match spec {
+                TransformSpec::L2Normalize => Box::new(normalize::L2Normalize::new(dim)),
+                TransformSpec::Pca(output_dim) => Box::new(pca::Pca::new(dim, *output_dim)?),
+                TransformSpec::RandomRotation => Box::new(rotation::RandomRotation::new(dim)),
+                TransformSpec::Opq(m) => Box::new(opq::Opq::new(dim, *m)?),
+            };
+            dim = transform.output_dim();
+            transforms.push(transform);
+        }
+
+        self.base.build_params().validate(dim)?;
+        if transforms.is_empty() {
+            return self.base.build(vectors);
+        }
+
+        let base = self.base.clone();
+        let index = transformed::TransformedIndex::try_new(
+            vectors,
+            transforms,
+            Box::new(move |vectors| base.build(vectors).expect("the spec has been validated")),
+        )?;
+        Ok(Box::new(index))
+    }
+}
+
+impl BaseSpec {
+    fn build_params(&self) -> BuildParams {
+        match self {
+            BaseSpec::Flat => BuildParams::Flat,
+            BaseSpec::Ivf { nlist, encoding } => {
+                let ivf = IvfBuildParams {
+                    nlist: *nlist,
+                    ..Default::default()
+                };
+                match encoding {
+                    EncodingSpec::Flat => BuildParams::Ivf(ivf),
+                    EncodingSpec::Pq { m, nbits } => BuildParams::IvfPq(
+                        ivf,
+                        PqBuildParams {
+                            m: *m,
+                            nbits: *nbits,
+                        },
+                    ),
+                }
+            }
+            BaseSpec::Hnsw { m } => BuildParams::Hnsw(HnswBuildParams {
+                m: *m,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn build(&self, vectors: Arc<dyn VectorAccessor>) -> Result<Box<dyn AnnIndex>> {
+        self.build_params().validate(vectors.dim())?;
+        match self {
+            BaseSpec::Flat => Ok(Box::new(Flat::new(vectors))),
+            BaseSpec::Ivf { encoding, .. } => match encoding {
+                EncodingSpec::Flat => Ok(Box::new(Ivf::new(vectors))),
+                EncodingSpec::Pq { .. } => Ok(Box::new(IvfPq::new(vectors))),
+            },
+            BaseSpec::Hnsw { .. } => Err(Error::Unsupported("HNSW index".to_string())),
+        }
+    }
+}
+
+impl FromStr for IndexSpec {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let components = split_components(spec)?;
+        let mut components = components.into_iter().peekable();
+
+        let mut transforms = Vec::new();
+        while let Some(transform) = components.peek().and_then(|c| parse_transform(spec, c)) {
+            transforms.push(transform?);
+            components.next();
+        }
+
+        let base = match components.next() {
+            Some("Flat") => BaseSpec::Flat,
+            Some(c) if c.starts_with("IVF") => {
+                let nlist = parse_number(spec, c, "IVF")?;
+                let encoding = match components.next() {
+                    Some("Flat") => EncodingSpec::Flat,
+                    Some(c) if c.starts_with("PQ") => parse_pq(spec, c)?,
+                    Some(c) => return Err(parse_error(spec, &format!("unknown encoding {:?}", c))),
+                    None => {
+                        return Err(parse_error(
+                            spec,
+                            "IVF must be followed by an encoding, e.g. Flat or PQ<m>",
+                        ))
+                    }
+                };
+                BaseSpec::Ivf { nlist, encoding }
+            }
+            Some(c) if c.starts_with("HNSW") => BaseSpec::Hnsw {
+                m: parse_number(spec, c, "HNSW")?,
+            },
+            Some(c) => return Err(parse_error(spec, &format!("unknown component {:?}", c))),
+            None => return Err(parse_error(spec, "missing index component")),
+        };
+
+        let refine = match components.next() {
+            Some(c) if c.starts_with("Refine(") && c.ends_with(')') => {
+                Some(Box::new(c["Refine(".len()..c.len() - 1].parse()?))
+            }
+            Some(c) => return Err(parse_error(spec, &format!("unexpected component {:?}", c))),
+            None => None,
+        };
+        if let Some(c) = components.next() {
+            return Err(parse_error(spec, &format!("unexpected component {:?}", c)));
+        }
+
+        Ok(Self {
+            transforms,
+            base,
+            refine,
+        })
+    }
+}
+
+impl fmt::Display for IndexSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for transform in &self.transforms {
+            match transform {
+                TransformSpec::L2Normalize => write!(f, "L2norm,")?,
+                TransformSpec::Pca(dim) => write!(f, "PCA{},", dim)?,
+                TransformSpec::RandomRotation => write!(f, "RR,")?,
+                TransformSpec::Opq(m) => write!(f, "OPQ{},", m)?,
+            }
+        }
+
+        match &self.base {
+            BaseSpec::Flat => write!(f, "Flat")?,
+            BaseSpec::Ivf { nlist, encoding } => match encoding {
+                EncodingSpec::Flat => write!(f, "IVF{},Flat", nlist)?,
+                EncodingSpec::Pq { m, nbits } => write!(f, "IVF{},PQ{}x{}", nlist, m, nbits)?,
+            },
+            BaseSpec::Hnsw { m } => write!(f, "HNSW{}", m)?,
+        }
+
+        if let Some(refine) = &self.refine {
+            write!(f, ",Refine({})", refine)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_error(spec: &str, msg: &str) -> Error {
+    Error::Parse(format!("invalid index spec {:?}: {}", spec, msg))
+}
+
+// splits the spec by the commas out of parentheses
+fn split_components(spec: &str) -> Result<Vec<&str>> {
+    let mut components = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(parse_error(spec, "unbalanced parentheses")),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                components.push(spec[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(parse_error(spec, "unbalanced parentheses"));
+    }
+    components.push(spec[start..].trim());
+
+    if components.iter().any(|c| c.is_empty()) {
+        return Err(parse_error(spec, "empty component"));
+    }
+    Ok(components)
+}
+
+fn parse_number(spec: &str, component: &str, prefix: &str) -> Result<usize> {
+    component[prefix.len()..]
+        .parse()
+        .map_err(|_| parse_error(spec, &format!("invalid number in {:?}", component)))
+}
+
+// returns None if the component is not a transform
+fn parse_transform(spec: &str, component: &str) -> Option<Result<TransformSpec>> {
+    let spec = match component {
+        "L2norm" => Ok(TransformSpec::L2Normalize),
+        "RR" => Ok(TransformSpec::RandomRotation),
+        c if c.starts_with("PCA") => parse_number(spec, c, "PCA").map(TransformSpec::Pca),
+        c if c.starts_with("OPQ") => parse_number(spec, c, "OPQ").map(TransformSpec::Opq),
+        _ => return None,
+    };
+    Some(spec)
+}
+
+fn parse_pq(spec: &str, component: &str) -> Result<EncodingSpec> {
+    match component[2..].split_once('x') {
+        Some((m, nbits)) => Ok(EncodingSpec::Pq {
+            m: parse_number(spec, m, "")?,
+            nbits: parse_number(spec, nbits, "")?,
+        }),
+        None => Ok(EncodingSpec::Pq {
+            m: parse_number(spec, component, "PQ")?,
+            nbits: 8,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::factory::*;
+    use crate::test_util::gen_vectors;
+    use crate::{metric, SearchOption, TrainOption};
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: IndexSpec = "PCA256,IVF4096,PQ32x8,Refine(Flat)".parse().unwrap();
+        assert_eq!(
+            spec,
+            IndexSpec {
+                transforms: vec![TransformSpec::Pca(256)],
+                base: BaseSpec::Ivf {
+                    nlist: 4096,
+                    encoding: EncodingSpec::Pq { m: 32, nbits: 8 },
+                },
+                refine: Some(Box::new(IndexSpec {
+                    transforms: vec![],
+                    base: BaseSpec::Flat,
+                    refine: None,
+                })),
+            }
+        );
+
+        for spec in [
+            "Flat",
+            "L2norm,RR,OPQ16,IVF1024,Flat",
+            "IVF256,PQ8x4",
+            "HNSW32",
+            "PCA64,IVF16,Flat,Refine(L2norm,Flat)",
+        ] {
+            assert_eq!(spec.parse::<IndexSpec>().unwrap().to_string(), spec);
+        }
+        assert_eq!(
+            " IVF8 , PQ4 ".parse::<IndexSpec>().unwrap().to_string(),
+            "IVF8,PQ4x8"
+        );
+
+        for spec in [
+            "",
+            "PCA",
+            "PCA32",
+            "IVF1024",
+            "IVFx,Flat",
+            "IVF8,SQ8",
+            "Flat,Flat",
+            "Flat,Refine(Flat",
+            "Flat)",
+            "IVF8,,Flat",
+            "Foo",
+        ] {
+            assert!(
+                matches!(spec.parse::<IndexSpec>(), Err(Error::Parse(_))),
+                "{:?}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_spec() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let build = |spec: &str| spec.parse::<IndexSpec>().unwrap().build(vectors.clone());
+
+        assert!(matches!(
+            build("PCA64,Flat"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(build("IVF8,PQ5"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(
+            build("PCA16,IVF8,PQ5x8"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(build("HNSW32"), Err(Error::Unsupported(_))));
+        assert!(matches!(
+            build("IVF8,Flat,Refine(PCA16,Flat)"),
+            Err(Error::Unsupported(_))
+        ));
+
+        let deleted = roaring::RoaringTreemap::new();
+        for spec in [
+            "Flat",
+            "IVF32,Flat",
+            "RR,PCA16,IVF32,Flat",
+            "PCA16,IVF32,PQ4x5",
+            "PCA16,IVF32,PQ4x5,Refine(Flat)",
+        ] {
+            let spec: IndexSpec = spec.parse().unwrap();
+            let mut index = spec.build(vectors.clone()).unwrap();
+            index.train(&spec.train_option(metric::MetricType::L2));
+            assert_eq!(index.build_params(), Some(spec.build_params()));
+
+            let search_option = SearchOption {
+                topk: CLUSTER_NUM,
+                params: match spec.search_params() {
+                    SearchParams::Ivf(_) => SearchParams::Ivf(IvfSearchParams {
+                        nprobe: CLUSTER_NUM / 2,
+                        ..Default::default()
+                    }),
+                    params => params,
+                },
+                refine_factor: None,
+            };
+
+            let query = vectors.get(0);
+            let result = index.search(query, &deleted, &search_option);
+            assert_eq!(result.len(), search_option.topk, "{}", spec);
+            assert!(
+                result
+                    .iter()
+                    .any(|id| metric::l2_distance(query, vectors.get(*id)) == 0.0),
+                "{}",
+                spec
+            );
+        }
+
+        let (index, params) = crate::index::from_spec("IVF8,PQ4x5", vectors.clone()).unwrap();
+        assert_eq!(
+            params,
+            BuildParams::IvfPq(
+                IvfBuildParams {
+                    nlist: 8,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 4, nbits: 5 }
+            )
+        );
+        let mut index = index.try_write().unwrap();
+        assert_eq!(index.build_params(), None);
+        index.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params,
+        });
+        assert_eq!(index.build_params(), Some(params));
+    }
+}
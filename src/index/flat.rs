@@ -0,0 +1,175 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::params::BuildParams;
+use crate::*;
+use log::warn;
+use ordered_float::NotNan;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::{io, pin::Pin};
+use tokio::io::AsyncWriteExt;
+
+// version 2: build params are stored after the metadata
+const VERSION: u16 = 2;
+
+// exhaustive search over all vectors
+pub struct Flat {
+    vectors: Arc<dyn VectorAccessor>,
+    metric_type: metric::MetricType,
+    trained: bool,
+}
+
+impl Flat {
+    pub fn new(vectors: Arc<dyn VectorAccessor>) -> Self {
+        Self {
+            vectors,
+            metric_type: metric::MetricType::None,
+            trained: false,
+        }
+    }
+}
+
+#[async_trait]
+impl crate::AnnIndex for Flat {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train flat index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        ctx.check()?;
+        option.validate(self.vectors.dim())?;
+        if option.params != BuildParams::Flat {
+            return Err(error::Error::InvalidArgument(format!(
+                "flat index requires flat build params, got {:?}",
+                option.params
+            )));
+        }
+
+        self.metric_type = option.metric_type;
+        self.trained = true;
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        let mut topk: BinaryHeap<(NotNan<f32>, usize)> = BinaryHeap::with_capacity(option.topk);
+        for i in 0..self.vectors.len() {
+            if deleted.contains(i as u64) {
+                continue;
+            }
+
+            let distance = self.metric_type.distance(query_vector, self.vectors.get(i));
+            if topk.len() == option.topk {
+                if topk.peek().unwrap().0.total_cmp(&distance).is_gt() {
+                    topk.pop();
+                } else {
+                    continue;
+                }
+            }
+            topk.push((NotNan::new(distance).unwrap(), i));
+        }
+
+        topk.iter().map(|(_, i)| *i).collect()
+    }
+
+    fn build_params(&self) -> Option<BuildParams> {
+        self.trained.then_some(BuildParams::Flat)
+    }
+
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u8(self.metric_type as u8).await?;
+        BuildParams::Flat.serialize(&mut writer).await?;
+        writer.flush().await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} flat index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        self.metric_type = metric::MetricType::from(reader.read_u8().await?);
+        if version > 1 {
+            let params = BuildParams::deserialize(&mut reader).await?;
+            if params != BuildParams::Flat {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected build params for flat index: {:?}", params),
+                ));
+            }
+        }
+        self.trained = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::flat::*;
+    use crate::params::SearchParams;
+    use crate::test_util::gen_vectors;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_flat() {
+        let accessor = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let mut flat = Flat::new(accessor.clone());
+        flat.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Flat,
+        });
+
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Flat,
+            refine_factor: None,
+        };
+        let mut deleted = roaring::RoaringTreemap::new();
+        deleted.insert(CLUSTER_NUM as u64);
+        for i in 0..CLUSTER_NUM {
+            let query = accessor.get(i);
+            let result = flat.search(query, &deleted, &option);
+            let expected = if i == 0 { CLUSTER_NUM - 1 } else { CLUSTER_NUM };
+            let close_count = result
+                .iter()
+                .filter(|id| metric::l2_distance(query, accessor.get(**id)) == 0.0)
+                .count();
+            assert_eq!(close_count, expected, "result: {:?}", result);
+            assert!(!result.contains(&CLUSTER_NUM));
+        }
+    }
+}
@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use super::cluster::Cluster;
+use super::quantizer::{self, CoarseQuantizer};
+use super::rebalance::{self, RebalanceOption, RebalanceReport};
 use super::util;
+use crate::params::{BuildParams, IvfBuildParams, IvfSearchParams, QuantizerType, SearchParams};
 use crate::*;
 use log::warn;
 use ordered_float::NotNan;
@@ -21,12 +24,36 @@ use std::{collections::BinaryHeap, sync::Arc};
 use std::{io, pin::Pin};
 use tokio::io::AsyncWriteExt;
 
-const VERSION: u16 = 1;
+// version 2: element ids and cluster sizes are u64
+// version 3: element ids are serialized roaring treemap
+// version 4: build params are stored after the metadata
+// version 5: build params contain the clustering
+// version 6: build params contain the coarse quantizer
+const VERSION: u16 = 6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchStats {
+    // the number of the scanned lists
+    pub nprobe: usize,
+    // the position of the last scanned list in the probing order
+    pub depth: usize,
+    // the number of the compared vectors
+    pub scanned: usize,
+}
 
 pub struct Ivf {
     vectors: Arc<dyn VectorAccessor>,
     clusters: Vec<Cluster>,
     metric_type: metric::MetricType,
+    compact_threshold: f32,
+    // the report of the last training, not serialized
+    clustering_report: Option<util::ClusteringReport>,
+    // rebuilt whenever the centroids change
+    quantizer: Box<dyn CoarseQuantizer>,
+    // the max L2 distance between each centroid and its elements,
+    // still an upper bound after deletion
+    radii: Vec<f32>,
+    params: IvfBuildParams,
 }
 
 impl Ivf {
@@ -35,44 +62,171 @@ impl Ivf {
             vectors: vectors,
             clusters: Vec::new(),
             metric_type: metric::MetricType::None,
+            compact_threshold: util::DEFAULT_COMPACT_THRESHOLD,
+            clustering_report: None,
+            quantizer: quantizer::new(QuantizerType::Flat),
+            radii: Vec::new(),
+            params: IvfBuildParams::default(),
         }
     }
-}
 
-#[async_trait]
-impl crate::AnnIndex for Ivf {
-    fn train(&mut self, option: &TrainOption) {
+    pub fn clustering_report(&self) -> Option<&util::ClusteringReport> {
+        self.clustering_report.as_ref()
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.clusters.is_empty()
+    }
+
+    // learns the centroids from the sample only, the trained but empty index
+    // could be serialized as a template, and populated by add
+    pub fn train_quantizer(
+        &mut self,
+        sample: Arc<dyn VectorAccessor>,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let params = Self::validate(sample.dim(), option)?;
+        let (clusters, report) =
+            util::train_clusters_with_report(option.metric_type, sample, &params, ctx)?;
         self.metric_type = option.metric_type;
-        self.clusters = util::train_clusters(self.metric_type, self.vectors.clone(), option);
+        self.params = params;
+        self.clusters = clusters;
+        self.clustering_report = Some(report);
+        for cluster in &mut self.clusters {
+            cluster.elements.clear();
+        }
+        self.build_quantizer();
+        self.compute_radii();
+        Ok(())
     }
 
-    fn search(
-        &self,
-        query_vector: &[f32],
-        deleted: &roaring::RoaringBitmap,
-        option: &SearchOption,
-    ) -> Vec<usize> {
-        let mut cluster_distances: Vec<_> = self
+    // adds the vectors of the ids to their nearest clusters
+    pub fn add(&mut self, ids: impl IntoIterator<Item = usize>) -> error::Result<()> {
+        if !self.is_trained() {
+            return Err(error::Error::InvalidArgument(
+                "can't add vectors to untrained ivf index".to_string(),
+            ));
+        }
+        for id in ids {
+            let vector = self.vectors.get(id);
+            let target = util::nearest_cluster(self.metric_type, &self.clusters, vector);
+            let radius = self
+                .metric_type
+                .distance(&self.clusters[target].centroid, vector)
+                .sqrt();
+            self.radii[target] = self.radii[target].max(radius);
+            self.clusters[target].add(id);
+        }
+        Ok(())
+    }
+
+    // see util::delete
+    pub fn delete(&mut self, id: usize) -> bool {
+        util::delete(&mut self.clusters, id, self.compact_threshold)
+    }
+
+    pub fn compact(&mut self) -> usize {
+        util::compact(&mut self.clusters)
+    }
+
+    pub fn deleted_ratios(&self) -> Vec<f32> {
+        util::deleted_ratios(&self.clusters)
+    }
+
+    pub fn set_compact_threshold(&mut self, threshold: f32) -> error::Result<()> {
+        util::validate_compact_threshold(threshold)?;
+        self.compact_threshold = threshold;
+        Ok(())
+    }
+
+    pub fn cluster_sizes(&self) -> Vec<usize> {
+        util::cluster_sizes(&self.clusters)
+    }
+
+    pub fn cluster_elements(&self, cluster: usize) -> impl Iterator<Item = usize> + '_ {
+        util::cluster_elements(&self.clusters[cluster])
+    }
+
+    // see rebalance::rebalance
+    pub fn rebalance(&mut self, option: &RebalanceOption) -> error::Result<RebalanceReport> {
+        let report = rebalance::rebalance(
+            self.metric_type,
+            &mut self.clusters,
+            self.vectors.clone(),
+            option,
+        )?;
+        self.params = rebalance::rebalanced_params(self.params, self.clusters.len());
+        self.build_quantizer();
+        self.compute_radii();
+        Ok(report)
+    }
+
+    fn compute_radii(&mut self) {
+        self.radii = self
             .clusters
             .iter()
-            .enumerate()
-            .map(|(i, c)| (i, self.metric_type.distance(&c.centroid, query_vector)))
+            .map(|c| {
+                c.iter()
+                    .map(|id| self.metric_type.distance(&c.centroid, self.vectors.get(id)))
+                    .fold(0f32, f32::max)
+                    .sqrt()
+            })
             .collect();
+    }
 
-        cluster_distances.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
-        cluster_distances.truncate(option.nprobe);
+    // searches and reports the probed lists
+    pub fn search_with_stats(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> (Vec<usize>, SearchStats) {
+        let params = match option.params {
+            SearchParams::Ivf(params) => params,
+            params => panic!("ivf index requires ivf search params, got {:?}", params),
+        };
+        if params.adaptive && self.metric_type != metric::MetricType::L2 {
+            panic!("adaptive nprobe requires L2 metric");
+        }
 
+        let cluster_distances = self.quantizer.search(
+            self.metric_type,
+            &self.clusters,
+            query_vector,
+            params.nprobe,
+        );
+        let max_radius = cluster_distances
+            .iter()
+            .map(|(i, _)| self.radii[*i])
+            .fold(0f32, f32::max);
+
+        let mut stats = SearchStats::default();
         let mut topk: BinaryHeap<(NotNan<f32>, usize)> = BinaryHeap::with_capacity(option.topk);
-        let clusters = cluster_distances.iter().map(|(i, _)| &self.clusters[*i]);
-        for cluster in clusters {
-            for i in &cluster.elements {
-                if deleted.contains(*i as u32) {
+        for (depth, (c, distance)) in cluster_distances.iter().enumerate() {
+            // |q - x| >= |q - c| - radius for any x in the cluster
+            if params.adaptive && topk.len() == option.topk {
+                let kth = topk.peek().unwrap().0.sqrt();
+                let distance = distance.sqrt();
+                // the rest clusters are farther
+                if distance - max_radius >= kth {
+                    break;
+                }
+                if distance - self.radii[*c] >= kth {
                     continue;
                 }
+            }
 
-                let distance = self
-                    .metric_type
-                    .distance(query_vector, self.vectors.get(*i));
+            let cluster = &self.clusters[*c];
+            stats.nprobe += 1;
+            stats.depth = depth + 1;
+            for i in cluster.iter() {
+                if cluster.is_deleted(i) || deleted.contains(i as u64) {
+                    continue;
+                }
+
+                stats.scanned += 1;
+                let distance = self.metric_type.distance(query_vector, self.vectors.get(i));
 
                 if topk.len() == option.topk {
                     if topk.peek().unwrap().0.total_cmp(&distance).is_gt() {
@@ -81,11 +235,121 @@ impl crate::AnnIndex for Ivf {
                         continue;
                     }
                 }
-                topk.push((NotNan::new(distance).unwrap(), *i));
+                topk.push((NotNan::new(distance).unwrap(), i));
             }
         }
 
-        topk.iter().map(|(_, i)| *i).collect()
+        (topk.iter().map(|(_, i)| *i).collect(), stats)
+    }
+
+    // the nprobe budget for the adaptive search, within which the given
+    // quantile of the queries terminate by the bound
+    pub fn learn_nprobe(&self, queries: &dyn VectorAccessor, topk: usize, quantile: f32) -> usize {
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: self.clusters.len(),
+                adaptive: true,
+            }),
+            refine_factor: None,
+        };
+        let deleted = roaring::RoaringTreemap::new();
+        let mut depths: Vec<_> = (0..queries.len())
+            .map(|i| {
+                self.search_with_stats(queries.get(i), &deleted, &option)
+                    .1
+                    .depth
+            })
+            .collect();
+        if depths.is_empty() {
+            return self.clusters.len();
+        }
+
+        depths.sort_unstable();
+        let index = ((depths.len() as f32 * quantile).ceil() as usize).clamp(1, depths.len());
+        depths[index - 1].max(1)
+    }
+
+    fn build_quantizer(&mut self) {
+        self.quantizer = quantizer::new(self.params.quantizer);
+        self.quantizer.build(self.metric_type, &self.clusters);
+    }
+
+    // returns an empty index over the vectors with the same centroids
+    pub fn clone_empty(&self, vectors: Arc<dyn VectorAccessor>) -> Self {
+        let mut index = Self {
+            vectors,
+            clusters: self
+                .clusters
+                .iter()
+                .map(|c| Cluster::with_centroid(&c.centroid))
+                .collect(),
+            metric_type: self.metric_type,
+            compact_threshold: self.compact_threshold,
+            clustering_report: None,
+            quantizer: quantizer::new(QuantizerType::Flat),
+            radii: Vec::new(),
+            params: self.params,
+        };
+        index.build_quantizer();
+        index.compute_radii();
+        index
+    }
+
+    fn validate(dim: usize, option: &TrainOption) -> error::Result<IvfBuildParams> {
+        option.validate(dim)?;
+        match option.params {
+            BuildParams::Ivf(params) => Ok(params),
+            params => Err(error::Error::InvalidArgument(format!(
+                "ivf index requires ivf build params, got {:?}",
+                params
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl crate::AnnIndex for Ivf {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train ivf index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let params = Self::validate(self.vectors.dim(), option)?;
+        let (clusters, report) = util::train_clusters_with_report(
+            option.metric_type,
+            self.vectors.clone(),
+            &params,
+            ctx,
+        )?;
+        self.metric_type = option.metric_type;
+        self.params = params;
+        self.clusters = clusters;
+        self.clustering_report = Some(report);
+        self.build_quantizer();
+        self.compute_radii();
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        self.search_with_stats(query_vector, deleted, option).0
+    }
+
+    fn build_params(&self) -> Option<BuildParams> {
+        if self.clusters.is_empty() {
+            return None;
+        }
+        Some(BuildParams::Ivf(self.params))
     }
 
     async fn serialize(
@@ -97,15 +361,10 @@ impl crate::AnnIndex for Ivf {
         writer.write_u8(self.metric_type as u8).await?;
         writer.write_u32_le(self.vectors.dim() as u32).await?;
         writer.write_u32_le(self.clusters.len() as u32).await?;
+        BuildParams::Ivf(self.params).serialize(&mut writer).await?;
 
         for cluster in &self.clusters {
-            writer.write_u32_le(cluster.len() as u32).await?;
-            for v in &cluster.centroid {
-                writer.write_f32_le(*v).await?;
-            }
-            for v in &cluster.elements {
-                writer.write_u32_le(*v as u32).await?;
-            }
+            cluster.serialize(&mut writer).await?;
         }
 
         writer.flush().await
@@ -116,7 +375,7 @@ impl crate::AnnIndex for Ivf {
         mut reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
     ) -> Result<(), io::Error> {
         let ivf_version = reader.read_u16_le().await?;
-        if ivf_version != VERSION {
+        if ivf_version > VERSION {
             warn!(
                 "read newer version {} ivf index file, current version is {}",
                 ivf_version, VERSION
@@ -125,23 +384,61 @@ impl crate::AnnIndex for Ivf {
 
         self.metric_type = metric::MetricType::from(reader.read_u8().await?);
         let dim = reader.read_u32_le().await? as usize;
+        if dim != self.vectors.dim() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expect {}-d ivf index, got {}-d", self.vectors.dim(), dim),
+            ));
+        }
         let nlist = reader.read_u32_le().await?;
+        let params = match ivf_version {
+            1..=3 => None,
+            4 => Some(BuildParams::deserialize_format(&mut reader, 1).await?),
+            5 => Some(BuildParams::deserialize_format(&mut reader, 2).await?),
+            _ => Some(BuildParams::deserialize(&mut reader).await?),
+        };
+        self.params = match params {
+            None => IvfBuildParams {
+                nlist: nlist as usize,
+                ..Default::default()
+            },
+            Some(BuildParams::Ivf(params)) => params,
+            Some(params) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected build params for ivf index: {:?}", params),
+                ))
+            }
+        };
         self.clusters = Vec::with_capacity(nlist as usize);
         for _ in 0..nlist {
+            if ivf_version > 2 {
+                self.clusters
+                    .push(Cluster::deserialize(&mut reader, dim).await?);
+                continue;
+            }
+
             let mut cluster = Cluster::new();
             cluster.centroid.reserve(dim);
-            let size = reader.read_u32_le().await? as usize;
+            let size = match ivf_version {
+                1 => reader.read_u32_le().await? as usize,
+                _ => reader.read_u64_le().await? as usize,
+            };
             for _ in 0..dim as usize {
                 cluster.centroid.push(reader.read_f32_le().await?);
             }
-
-            cluster.elements.reserve(size);
             for _ in 0..size {
-                cluster.add(reader.read_u32_le().await? as usize);
+                let id = match ivf_version {
+                    1 => reader.read_u32_le().await? as usize,
+                    _ => reader.read_u64_le().await? as usize,
+                };
+                cluster.add(id);
             }
 
             self.clusters.push(cluster);
         }
+        self.build_quantizer();
+        self.compute_radii();
 
         Ok(())
     }
@@ -149,9 +446,10 @@ impl crate::AnnIndex for Ivf {
 
 #[cfg(test)]
 mod tests {
+    use crate::accessor::MemoryVectorAccessor;
     use crate::index::ivf::*;
-    use crate::test_util::gen_vectors;
-    use roaring::RoaringBitmap;
+    use crate::test_util::{gen_floats, gen_vectors};
+    use roaring::RoaringTreemap;
     use tokio::io::{BufReader, BufWriter};
 
     const DIM: usize = 32;
@@ -164,18 +462,28 @@ mod tests {
         let mut ivf = Ivf::new(accessor.clone());
 
         let option = TrainOption {
-            iteration_num: None,
-            nlist: CLUSTER_NUM,
             metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
         };
+        assert!(ivf.clustering_report().is_none());
         ivf.train(&option);
+        let report = ivf.clustering_report().unwrap();
+        assert_eq!(report.iterations, IvfBuildParams::default().iteration_num);
+        assert!(report.inertia > 0.0);
 
         let option = SearchOption {
-            nprobe: CLUSTER_NUM / 2,
             topk: CLUSTER_NUM + 1,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
         };
 
-        let bitmap = RoaringBitmap::new();
+        let bitmap = RoaringTreemap::new();
         for i in 0..accessor.len() {
             let query = accessor.get(i);
             let result = ivf.search(query, &bitmap, &option);
@@ -203,9 +511,15 @@ mod tests {
         let mut ivf = Ivf::new(accessor.clone());
 
         let option = TrainOption {
-            iteration_num: None,
-            nlist: CLUSTER_NUM,
             metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                quantizer: QuantizerType::Graph {
+                    m: 8,
+                    ef_construction: CLUSTER_NUM,
+                },
+                ..Default::default()
+            }),
         };
         ivf.train(&option);
 
@@ -221,14 +535,20 @@ mod tests {
         ivf.deserialize(Box::pin(buf)).await.unwrap();
 
         assert_eq!(ivf.metric_type, metric::MetricType::L2);
-        assert_eq!(ivf.clusters.len(), option.nlist);
+        assert_eq!(ivf.clusters.len(), CLUSTER_NUM);
+        assert_eq!(ivf.build_params(), Some(option.params));
+        assert_eq!(ivf.quantizer.quantizer_type(), ivf.params.quantizer);
 
         let option = SearchOption {
-            nprobe: CLUSTER_NUM / 2,
             topk: CLUSTER_NUM + 1,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
         };
 
-        let bitmap = RoaringBitmap::new();
+        let bitmap = RoaringTreemap::new();
         for i in 0..accessor.len() {
             let query = accessor.get(i);
             let result = ivf.search(query, &bitmap, &option);
@@ -249,4 +569,308 @@ mod tests {
             assert_eq!(close_count, CLUSTER_NUM, "result: {:?}", result);
         }
     }
+
+    #[tokio::test]
+    async fn test_ivf_deserialize_v1() {
+        let accessor = Arc::new(gen_vectors(4, 2, 2));
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.push(metric::MetricType::L2 as u8);
+        data.extend_from_slice(&2u32.to_le_bytes()); // dim
+        data.extend_from_slice(&1u32.to_le_bytes()); // nlist
+        data.extend_from_slice(&4u32.to_le_bytes()); // cluster size
+        data.extend_from_slice(&0.5f32.to_le_bytes());
+        data.extend_from_slice(&0.5f32.to_le_bytes());
+        for id in 0..4u32 {
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+
+        let mut ivf = Ivf::new(accessor);
+        ivf.deserialize(Box::pin(std::io::Cursor::new(data)))
+            .await
+            .unwrap();
+        assert_eq!(ivf.metric_type, metric::MetricType::L2);
+        assert_eq!(ivf.clusters.len(), 1);
+        assert_eq!(ivf.clusters[0].centroid, vec![0.5, 0.5]);
+        assert_eq!(ivf.clusters[0].iter().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_ivf_template() {
+        let accessor = gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM);
+        let shards: Vec<Arc<dyn VectorAccessor>> = (0..2)
+            .map(|shard| {
+                let mut data = Vec::new();
+                for i in (shard..accessor.len()).step_by(2) {
+                    data.extend_from_slice(accessor.get(i));
+                }
+                Arc::new(MemoryVectorAccessor::new(DIM, data)) as _
+            })
+            .collect();
+
+        let mut template = Ivf::new(Arc::new(MemoryVectorAccessor::new(DIM, Vec::new())));
+        assert!(template.add(0..1).is_err());
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        };
+        let ctx = TrainContext::new();
+        ctx.cancel();
+        let sample: Arc<dyn VectorAccessor> = Arc::new(accessor);
+        assert!(matches!(
+            template.train_quantizer(sample.clone(), &option, &ctx),
+            Err(error::Error::Cancelled)
+        ));
+        assert!(!template.is_trained());
+        template
+            .train_quantizer(sample, &option, &TrainContext::default())
+            .unwrap();
+        assert!(template.is_trained());
+        assert!(template.clusters.iter().all(|c| c.len() == 0));
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("template.ivf");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        template
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let option = SearchOption {
+            topk: CLUSTER_NUM / 2,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: 1,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let bitmap = RoaringTreemap::new();
+        for shard in shards {
+            let mut ivf = Ivf::new(shard.clone());
+            let file = tokio::fs::File::open(&path).await.unwrap();
+            ivf.deserialize(Box::pin(BufReader::new(file)))
+                .await
+                .unwrap();
+            ivf.add(0..shard.len()).unwrap();
+
+            let clone = template.clone_empty(shard.clone());
+            for (a, b) in ivf.clusters.iter().zip(&clone.clusters) {
+                assert_eq!(a.centroid, b.centroid);
+            }
+
+            for i in 0..shard.len() {
+                let query = shard.get(i);
+                let result = ivf.search(query, &bitmap, &option);
+                assert_eq!(result.len(), option.topk);
+                for id in &result {
+                    assert_eq!(ivf.metric_type.distance(query, shard.get(*id)), 0.0);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ivf_delete() {
+        let accessor = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let mut ivf = Ivf::new(accessor.clone());
+        ivf.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+        assert!(ivf.set_compact_threshold(f32::NAN).is_err());
+        assert!(ivf.set_compact_threshold(1.5).is_err());
+        ivf.set_compact_threshold(0.25).unwrap();
+
+        let total = ivf.clusters.iter().map(|c| c.len()).sum::<usize>();
+        let target = ivf
+            .clusters
+            .iter()
+            .position(|c| c.elements.contains(0))
+            .unwrap();
+        let size = ivf.clusters[target].len();
+
+        assert!(ivf.delete(0));
+        assert!(!ivf.delete(0));
+        assert!(!ivf.delete(DATASET_SIZE));
+        assert_eq!(ivf.deleted_ratios()[target], 1.0 / size as f32);
+
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let bitmap = RoaringTreemap::new();
+        // the ids of the found copies of the first vector
+        let search_copies = |ivf: &Ivf| {
+            let result = ivf.search(accessor.get(0), &bitmap, &option);
+            result
+                .into_iter()
+                .filter(|id| accessor.get(*id) == accessor.get(0))
+                .collect::<Vec<_>>()
+        };
+        let result = search_copies(&ivf);
+        assert_eq!(result.len(), CLUSTER_NUM - 1);
+        assert!(!result.contains(&0));
+
+        // exceeding the threshold rewrites the list
+        let elements: Vec<_> = ivf.clusters[target].iter().skip(1).collect();
+        let mut deleted = vec![0];
+        for id in elements {
+            assert!(ivf.delete(id));
+            deleted.push(id);
+            if ivf.deleted_ratios()[target] == 0.0 {
+                break;
+            }
+        }
+        assert!(deleted.len() as f32 > size as f32 * 0.25);
+        assert_eq!(ivf.clusters[target].len(), size - deleted.len());
+
+        let other = (target + 1) % CLUSTER_NUM;
+        let id = ivf.clusters[other].iter().next().unwrap();
+        assert!(ivf.delete(id));
+        deleted.push(id);
+        assert_eq!(ivf.compact(), 1);
+        assert_eq!(
+            ivf.clusters.iter().map(|c| c.len()).sum::<usize>(),
+            total - deleted.len()
+        );
+
+        let deleted_copies = deleted.iter().filter(|id| *id % CLUSTER_NUM == 0).count();
+        assert_eq!(search_copies(&ivf).len(), CLUSTER_NUM - deleted_copies);
+    }
+
+    #[tokio::test]
+    async fn test_ivf_train_cancel() {
+        let accessor = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                iteration_num: 10,
+                ..Default::default()
+            }),
+        };
+
+        let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let ctx = {
+            let progress = progress.clone();
+            TrainContext::new().with_callback(Arc::new(move |p: &TrainProgress| {
+                progress.lock().unwrap().push(*p)
+            }))
+        };
+        let mut ivf = Ivf::new(accessor.clone());
+        ivf.train_with_context(&option, &ctx).unwrap();
+        let steps: Vec<_> = progress.lock().unwrap().iter().map(|p| p.step).collect();
+        assert_eq!(steps, (1..=10).collect::<Vec<_>>());
+        assert!(progress
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|p| p.stage == TrainStage::Clustering && p.total == 10 && p.inertia.is_some()));
+
+        // cancels after the third iteration
+        let ctx = {
+            let progress = progress.clone();
+            let ctx = TrainContext::new();
+            let cancel = ctx.clone();
+            ctx.with_callback(Arc::new(move |p: &TrainProgress| {
+                progress.lock().unwrap().push(*p);
+                if p.step == 3 {
+                    cancel.cancel();
+                }
+            }))
+        };
+        progress.lock().unwrap().clear();
+        let mut ivf = Ivf::new(accessor);
+        assert!(matches!(
+            ivf.train_with_context(&option, &ctx),
+            Err(error::Error::Cancelled)
+        ));
+        assert_eq!(progress.lock().unwrap().len(), 3);
+        assert!(!ivf.is_trained());
+    }
+
+    #[tokio::test]
+    async fn test_ivf_adaptive() {
+        let centers = gen_floats(CLUSTER_NUM * DIM);
+        let noise = gen_floats(DATASET_SIZE * DIM);
+        let data = (0..DATASET_SIZE * DIM)
+            .map(|i| centers[(i / DIM % CLUSTER_NUM) * DIM + i % DIM] * 10.0 + noise[i])
+            .collect();
+        let accessor = Arc::new(MemoryVectorAccessor::new(DIM, data));
+        let mut ivf = Ivf::new(accessor.clone());
+        ivf.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+
+        let topk = 10;
+        let brute_force = |query: &[f32]| {
+            let mut ids: Vec<_> = (0..accessor.len()).collect();
+            ids.sort_by(|a, b| {
+                let a = ivf.metric_type.distance(query, accessor.get(*a));
+                let b = ivf.metric_type.distance(query, accessor.get(*b));
+                a.total_cmp(&b)
+            });
+            ids.truncate(topk);
+            ids.sort();
+            ids
+        };
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                adaptive: true,
+            }),
+            refine_factor: None,
+        };
+
+        // the bound never skips a true neighbor
+        let bitmap = RoaringTreemap::new();
+        let queries = 0..DATASET_SIZE / 8;
+        let mut probed = 0;
+        for i in queries.clone() {
+            let query = accessor.get(i * 8);
+            let (mut result, stats) = ivf.search_with_stats(query, &bitmap, &option);
+            result.sort();
+            assert_eq!(result, brute_force(query));
+            assert!(stats.nprobe <= stats.depth);
+            probed += stats.nprobe;
+        }
+        assert!(
+            probed < queries.len() * CLUSTER_NUM / 2,
+            "probed {}",
+            probed
+        );
+
+        let budget = ivf.learn_nprobe(accessor.as_ref(), topk, 1.0);
+        assert!((1..=CLUSTER_NUM).contains(&budget));
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: budget,
+                adaptive: true,
+            }),
+            refine_factor: None,
+        };
+        for i in queries {
+            let query = accessor.get(i * 8);
+            let (mut result, stats) = ivf.search_with_stats(query, &bitmap, &option);
+            result.sort();
+            assert_eq!(result, brute_force(query));
+            assert!(stats.depth <= budget);
+        }
+    }
 }
@@ -0,0 +1,916 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::cluster::Cluster;
+use super::pq::ProductQuantizer;
+use super::quantizer::{self, CoarseQuantizer};
+use super::util;
+use crate::params::{BuildParams, IvfBuildParams, QuantizerType, SearchParams};
+use crate::*;
+use log::warn;
+use ordered_float::NotNan;
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use tokio::io::AsyncWriteExt;
+
+const VERSION: u16 = 1;
+
+// the version of the list file layout
+const FILE_VERSION: u16 = 1;
+const MAGIC: u32 = u32::from_le_bytes(*b"IVFL");
+const HEADER_SIZE: usize = 64;
+
+// the memory budget of the cached lists in bytes
+pub const DEFAULT_CACHE_CAPACITY: usize = 64 << 20;
+
+// ivf with the inverted lists in a file, the ids and the vectors (or the pq
+// codes) of a list are stored contiguously, so a list costs one read.
+// the lists are read on demand, and the recently used ones are cached
+pub struct IvfDisk {
+    // read by the training, the search only takes the dim of the lists from it
+    vectors: Arc<dyn VectorAccessor>,
+    path: PathBuf,
+    file: Option<Arc<fs::File>>,
+    metric_type: metric::MetricType,
+    params: Option<BuildParams>,
+    // the centroids only, the elements are in the file
+    clusters: Vec<Cluster>,
+    quantizer: Box<dyn CoarseQuantizer>,
+    // the lists store the codes instead of the vectors if it's ivf-pq
+    pq: Option<ProductQuantizer>,
+    lists: Vec<ListLocation>,
+    cache: Mutex<ListCache>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ListLocation {
+    offset: u64,
+    len: usize,
+}
+
+// one of the vectors and the codes is empty
+struct InvertedList {
+    ids: Vec<usize>,
+    vectors: Vec<f32>,
+    codes: Vec<u8>,
+}
+
+impl InvertedList {
+    fn size(&self) -> usize {
+        self.ids.len() * 8 + self.vectors.len() * 4 + self.codes.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // the number and the bytes of the cached lists
+    pub lists: usize,
+    pub size: usize,
+}
+
+// evicts the least recently used lists once the size exceeds the capacity
+struct ListCache {
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    // the list and its last access time
+    entries: HashMap<usize, (Arc<InvertedList>, u64)>,
+    // the access time to the list
+    order: BTreeMap<u64, usize>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ListCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, cluster: usize) -> Option<Arc<InvertedList>> {
+        self.clock += 1;
+        match self.entries.get_mut(&cluster) {
+            Some((list, time)) => {
+                self.order.remove(time);
+                *time = self.clock;
+                self.order.insert(self.clock, cluster);
+                self.hits += 1;
+                Some(list.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    // the list larger than the capacity is not cached
+    fn insert(&mut self, cluster: usize, list: Arc<InvertedList>) {
+        if self.entries.contains_key(&cluster) || list.size() > self.capacity {
+            return;
+        }
+        self.clock += 1;
+        self.size += list.size();
+        self.entries.insert(cluster, (list, self.clock));
+        self.order.insert(self.clock, cluster);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let (_, cluster) = self.order.pop_first().unwrap();
+            let (list, _) = self.entries.remove(&cluster).unwrap();
+            self.size -= list.size();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.size = 0;
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            lists: self.entries.len(),
+            size: self.size,
+        }
+    }
+}
+
+impl IvfDisk {
+    // the lists are written to the path by the training,
+    // and read from the path after the deserialization
+    pub fn new(vectors: Arc<dyn VectorAccessor>, path: impl AsRef<Path>) -> Self {
+        Self {
+            vectors,
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            metric_type: metric::MetricType::None,
+            params: None,
+            clusters: Vec::new(),
+            quantizer: quantizer::new(QuantizerType::Flat),
+            pq: None,
+            lists: Vec::new(),
+            cache: Mutex::new(ListCache::new(DEFAULT_CACHE_CAPACITY)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.params.is_some()
+    }
+
+    pub fn cluster_sizes(&self) -> Vec<usize> {
+        self.lists.iter().map(|l| l.len).collect()
+    }
+
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.lock().unwrap().capacity
+    }
+
+    // evicts the lists immediately if they exceed the new capacity
+    pub fn set_cache_capacity(&self, capacity: usize) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.capacity = capacity;
+        cache.evict();
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn validate(
+        dim: usize,
+        option: &TrainOption,
+    ) -> error::Result<(IvfBuildParams, Option<ProductQuantizer>)> {
+        option.validate(dim)?;
+        match option.params {
+            BuildParams::Ivf(ivf) => Ok((ivf, None)),
+            BuildParams::IvfPq(_, _) if option.metric_type != metric::MetricType::L2 => Err(
+                error::Error::Unsupported("ivf-pq only supports L2 metric".to_string()),
+            ),
+            BuildParams::IvfPq(ivf, pq) => {
+                Ok((ivf, Some(ProductQuantizer::new(dim, pq.m, pq.nbits)?)))
+            }
+            params => Err(error::Error::InvalidArgument(format!(
+                "ivf-disk index requires ivf or ivf-pq build params, got {:?}",
+                params
+            ))),
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        entry_size(self.vectors.dim(), self.pq.as_ref())
+    }
+
+    // the nearest clusters of the query
+    fn probe(&self, query_vector: &[f32], option: &SearchOption) -> Vec<usize> {
+        let nprobe = match option.params {
+            SearchParams::Ivf(params) if params.adaptive => {
+                panic!("ivf-disk index doesn't support adaptive nprobe")
+            }
+            SearchParams::Ivf(params) => params.nprobe,
+            params => panic!(
+                "ivf-disk index requires ivf search params, got {:?}",
+                params
+            ),
+        };
+        if !self.is_trained() {
+            panic!("can't search untrained ivf-disk index");
+        }
+
+        self.quantizer
+            .search(self.metric_type, &self.clusters, query_vector, nprobe)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn scan(
+        &self,
+        query_vector: &[f32],
+        lists: &[Arc<InvertedList>],
+        deleted: &roaring::RoaringTreemap,
+        k: usize,
+    ) -> Vec<usize> {
+        let table = self.pq.as_ref().map(|pq| pq.distance_table(query_vector));
+        let dim = self.vectors.dim();
+        let mut topk: BinaryHeap<(NotNan<f32>, usize)> = BinaryHeap::with_capacity(k);
+        for list in lists {
+            for (i, &id) in list.ids.iter().enumerate() {
+                if deleted.contains(id as u64) {
+                    continue;
+                }
+
+                let distance = match (&self.pq, &table) {
+                    (Some(pq), Some(table)) => {
+                        let size = pq.code_size();
+                        pq.table_distance(table, &list.codes[i * size..(i + 1) * size])
+                    }
+                    _ => self
+                        .metric_type
+                        .distance(query_vector, &list.vectors[i * dim..(i + 1) * dim]),
+                };
+
+                if topk.len() == k {
+                    if topk.peek().unwrap().0.total_cmp(&distance).is_gt() {
+                        topk.pop();
+                    } else {
+                        continue;
+                    }
+                }
+                topk.push((NotNan::new(distance).unwrap(), id));
+            }
+        }
+
+        topk.into_sorted_vec().into_iter().map(|(_, i)| i).collect()
+    }
+
+    fn file(&self) -> io::Result<Arc<fs::File>> {
+        self.file
+            .clone()
+            .ok_or_else(|| io::Error::other("list file is not opened"))
+    }
+
+    fn load_list(&self, cluster: usize) -> io::Result<Arc<InvertedList>> {
+        if let Some(list) = self.cache.lock().unwrap().get(cluster) {
+            return Ok(list);
+        }
+        let list = Arc::new(read_list(
+            self.file()?.as_ref(),
+            self.lists[cluster],
+            self.vectors.dim(),
+            self.entry_size(),
+            self.pq.is_some(),
+        )?);
+        self.cache.lock().unwrap().insert(cluster, list.clone());
+        Ok(list)
+    }
+
+    async fn load_list_async(&self, cluster: usize) -> io::Result<Arc<InvertedList>> {
+        if let Some(list) = self.cache.lock().unwrap().get(cluster) {
+            return Ok(list);
+        }
+        let file = self.file()?;
+        let (location, dim, entry_size) =
+            (self.lists[cluster], self.vectors.dim(), self.entry_size());
+        let is_pq = self.pq.is_some();
+        let list =
+            tokio::task::spawn_blocking(move || read_list(&file, location, dim, entry_size, is_pq))
+                .await
+                .map_err(io::Error::other)??;
+        let list = Arc::new(list);
+        self.cache.lock().unwrap().insert(cluster, list.clone());
+        Ok(list)
+    }
+
+    // the index can't be searched once the file doesn't match it
+    fn reset(&mut self) {
+        self.params = None;
+        self.file = None;
+        self.clear_cache();
+    }
+
+    fn build_quantizer(&mut self) {
+        let typ = self
+            .params
+            .as_ref()
+            .and_then(|p| p.ivf())
+            .map_or(QuantizerType::Flat, |ivf| ivf.quantizer);
+        self.quantizer = quantizer::new(typ);
+        self.quantizer.build(self.metric_type, &self.clusters);
+    }
+}
+
+// the bytes of an element after its id
+fn entry_size(dim: usize, pq: Option<&ProductQuantizer>) -> usize {
+    match pq {
+        Some(pq) => pq.code_size(),
+        None => dim * 4,
+    }
+}
+
+// opens the list file, and checks it holds the lists
+fn open_lists(
+    path: &Path,
+    dim: usize,
+    entry_size: usize,
+    lists: &[ListLocation],
+) -> io::Result<fs::File> {
+    let file = fs::File::open(path)?;
+    let mut header = vec![0u8; HEADER_SIZE];
+    util::read_at(&file, &mut header, 0)?;
+    let expected = encode_header(dim, entry_size, lists.len());
+    if header[..4] != expected[..4] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a list file",
+        ));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > FILE_VERSION {
+        warn!(
+            "read newer version {} list file, current version is {}",
+            version, FILE_VERSION
+        );
+    }
+    if header[6..] != expected[6..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("list file {} doesn't match the index", path.display()),
+        ));
+    }
+
+    let size = lists
+        .iter()
+        .map(|l| l.offset + (l.len * (8 + entry_size)) as u64)
+        .max()
+        .unwrap_or(HEADER_SIZE as u64);
+    if file.metadata()?.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("list file {} is truncated", path.display()),
+        ));
+    }
+    Ok(file)
+}
+
+fn encode_header(dim: usize, entry_size: usize, nlist: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend(MAGIC.to_le_bytes());
+    header.extend(FILE_VERSION.to_le_bytes());
+    header.extend((dim as u32).to_le_bytes());
+    header.extend((entry_size as u32).to_le_bytes());
+    header.extend((nlist as u64).to_le_bytes());
+    header.resize(HEADER_SIZE, 0);
+    header
+}
+
+// writes the ids of each list, followed by their vectors or codes,
+// returns the locations of the lists
+fn write_lists(
+    path: &Path,
+    clusters: &[Cluster],
+    vectors: &dyn VectorAccessor,
+    pq: Option<&ProductQuantizer>,
+) -> io::Result<Vec<ListLocation>> {
+    let entry_size = pq.map_or(vectors.dim() * 4, |pq| pq.code_size());
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    writer.write_all(&encode_header(vectors.dim(), entry_size, clusters.len()))?;
+
+    let mut offset = HEADER_SIZE as u64;
+    let mut locations = Vec::with_capacity(clusters.len());
+    let mut code = vec![0u8; entry_size];
+    for cluster in clusters {
+        for id in cluster.iter() {
+            writer.write_all(&(id as u64).to_le_bytes())?;
+        }
+        for id in cluster.iter() {
+            match pq {
+                Some(pq) => {
+                    pq.encode(vectors.get(id), &mut code);
+                    writer.write_all(&code)?;
+                }
+                None => {
+                    for v in vectors.get(id) {
+                        writer.write_all(&v.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+
+        locations.push(ListLocation {
+            offset,
+            len: cluster.len(),
+        });
+        offset += (cluster.len() * (8 + entry_size)) as u64;
+    }
+
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(locations)
+}
+
+fn read_list(
+    file: &fs::File,
+    location: ListLocation,
+    dim: usize,
+    entry_size: usize,
+    is_pq: bool,
+) -> io::Result<InvertedList> {
+    let mut buf = vec![0u8; location.len * (8 + entry_size)];
+    util::read_at(file, &mut buf, location.offset)?;
+    let (ids, entries) = buf.split_at(location.len * 8);
+    let ids = ids
+        .array_chunks::<8>()
+        .map(|id| u64::from_le_bytes(*id) as usize)
+        .collect();
+    let (vectors, codes) = match is_pq {
+        true => (Vec::new(), entries.to_vec()),
+        false => {
+            let vectors: Vec<_> = entries
+                .array_chunks::<4>()
+                .map(|v| f32::from_le_bytes(*v))
+                .collect();
+            debug_assert_eq!(vectors.len(), location.len * dim);
+            (vectors, Vec::new())
+        }
+    };
+    Ok(InvertedList {
+        ids,
+        vectors,
+        codes,
+    })
+}
+
+#[async_trait]
+impl AnnIndex for IvfDisk {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train ivf-disk index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let (ivf_params, mut pq) = Self::validate(self.vectors.dim(), option)?;
+        let mut clusters = util::train_clusters_with_report(
+            option.metric_type,
+            self.vectors.clone(),
+            &ivf_params,
+            ctx,
+        )?
+        .0;
+        if let Some(pq) = &mut pq {
+            pq.train_with_context(self.vectors.clone(), ivf_params.iteration_num, ctx)?;
+        }
+
+        // the old lists are overwritten from here
+        let dim = self.vectors.dim();
+        let written = write_lists(&self.path, &clusters, self.vectors.as_ref(), pq.as_ref())
+            .and_then(|lists| {
+                let file = open_lists(&self.path, dim, entry_size(dim, pq.as_ref()), &lists)?;
+                Ok((lists, file))
+            });
+        let (lists, file) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                self.reset();
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("failed to write list file {}: {}", self.path.display(), err),
+                )
+                .into());
+            }
+        };
+        for cluster in &mut clusters {
+            cluster.elements.clear();
+        }
+
+        self.metric_type = option.metric_type;
+        self.params = Some(option.params);
+        self.clusters = clusters;
+        self.pq = pq;
+        self.lists = lists;
+        self.file = Some(Arc::new(file));
+        self.clear_cache();
+        self.build_quantizer();
+        Ok(())
+    }
+
+    // the lists that can't be read are skipped with a warning, e.g. the list file
+    // is truncated, search_async returns the read errors instead
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        let lists: Vec<_> = self
+            .probe(query_vector, option)
+            .into_iter()
+            .filter_map(|c| match self.load_list(c) {
+                Ok(list) => Some(list),
+                Err(err) => {
+                    warn!("failed to read list {}: {}", c, err);
+                    None
+                }
+            })
+            .collect();
+        self.scan(query_vector, &lists, deleted, option.topk)
+    }
+
+    // the same as search, but the missed lists are read concurrently
+    // by the blocking pool of tokio, and the read errors are returned
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        let clusters = self.probe(query_vector, option);
+        let lists =
+            futures::future::try_join_all(clusters.into_iter().map(|c| self.load_list_async(c)))
+                .await?;
+        Ok(self.scan(query_vector, &lists, deleted, option.topk))
+    }
+
+    fn build_params(&self) -> Option<BuildParams> {
+        self.params
+    }
+
+    fn validate_search(&self, option: &SearchOption) -> error::Result<()> {
+        option.validate()?;
+        let params = self
+            .params
+            .ok_or_else(|| error::Error::InvalidArgument("index is not trained".to_string()))?;
+        option.params.compatible_with(&params)?;
+        match option.params {
+            SearchParams::Ivf(params) if params.adaptive => Err(error::Error::InvalidArgument(
+                "ivf-disk index doesn't support adaptive nprobe".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // only the centroids and the locations of the lists are written,
+    // the lists stay in the file at the path
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u8(self.metric_type as u8).await?;
+        writer.write_u32_le(self.vectors.dim() as u32).await?;
+        self.params
+            .ok_or_else(|| io::Error::other("index is not trained"))?
+            .serialize(&mut writer)
+            .await?;
+        writer.write_u32_le(self.clusters.len() as u32).await?;
+        for (cluster, list) in self.clusters.iter().zip(&self.lists) {
+            crate::transform::write_floats(&mut writer, &cluster.centroid).await?;
+            writer.write_u64_le(list.offset).await?;
+            writer.write_u64_le(list.len as u64).await?;
+        }
+        if let Some(pq) = &self.pq {
+            pq.serialize(&mut writer).await?;
+        }
+
+        writer.flush().await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} ivf-disk index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        let metric_type = metric::MetricType::from(reader.read_u8().await?);
+        let dim = reader.read_u32_le().await? as usize;
+        if dim != self.vectors.dim() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expect {}-d ivf-disk index, got {}-d",
+                    self.vectors.dim(),
+                    dim
+                ),
+            ));
+        }
+        let params = BuildParams::deserialize(&mut reader).await?;
+        if params.ivf().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected build params for ivf-disk index: {:?}", params),
+            ));
+        }
+
+        let nlist = reader.read_u32_le().await? as usize;
+        let mut clusters = Vec::with_capacity(nlist);
+        let mut lists = Vec::with_capacity(nlist);
+        for _ in 0..nlist {
+            let centroid = crate::transform::read_floats(&mut reader).await?;
+            if centroid.len() != dim {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid centroid size {}", centroid.len()),
+                ));
+            }
+            clusters.push(Cluster::with_centroid(&centroid));
+            lists.push(ListLocation {
+                offset: reader.read_u64_le().await?,
+                len: reader.read_u64_le().await? as usize,
+            });
+        }
+        let pq = match params.pq() {
+            Some(_) => Some(ProductQuantizer::deserialize(&mut reader).await?),
+            None => None,
+        };
+
+        // the file may be replaced since the index was serialized
+        let file = match open_lists(&self.path, dim, entry_size(dim, pq.as_ref()), &lists) {
+            Ok(file) => file,
+            Err(err) => {
+                self.reset();
+                return Err(err);
+            }
+        };
+        self.metric_type = metric_type;
+        self.params = Some(params);
+        self.clusters = clusters;
+        self.pq = pq;
+        self.lists = lists;
+        self.file = Some(Arc::new(file));
+        self.clear_cache();
+        self.build_quantizer();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::ivf_disk::*;
+    use crate::params::{IvfSearchParams, PqBuildParams};
+    use crate::test_util::gen_floats;
+    use crate::tune;
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[tokio::test]
+    async fn test_ivf_disk() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("ivf.lists");
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        };
+        let mut index = IvfDisk::new(vectors.clone(), temp_dir.path().join("missing/ivf.lists"));
+        assert!(matches!(
+            index.train_with_context(&option, &TrainContext::default()),
+            Err(error::Error::Io(_))
+        ));
+        assert!(!index.is_trained());
+
+        let mut index = IvfDisk::new(vectors.clone(), &path);
+        index.train(&option);
+        assert_eq!(index.cluster_sizes().iter().sum::<usize>(), DATASET_SIZE);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (HEADER_SIZE + DATASET_SIZE * (8 + DIM * 4)) as u64
+        );
+
+        // scanning all lists is exact
+        let topk = 10;
+        let queries = MemoryVectorAccessor::new(DIM, gen_floats(16 * DIM));
+        let truth = tune::ground_truth(metric::MetricType::L2, vectors.as_ref(), &queries, topk);
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let deleted = roaring::RoaringTreemap::new();
+        for (q, truth) in truth.iter().enumerate() {
+            let result = index.search(queries.get(q), &deleted, &option);
+            assert_eq!(&result, truth);
+            let async_result = index
+                .search_async(queries.get(q), &deleted, &option)
+                .await
+                .unwrap();
+            assert_eq!(result, async_result);
+        }
+        let stats = index.cache_stats();
+        assert_eq!(stats.lists, CLUSTER_NUM);
+        assert_eq!(stats.misses, CLUSTER_NUM as u64);
+
+        // only the recently used lists are kept under the capacity
+        let list_size = (8 + DIM * 4) * DATASET_SIZE / CLUSTER_NUM;
+        index.set_cache_capacity(list_size * 4);
+        let stats = index.cache_stats();
+        assert!(stats.size <= list_size * 4 && stats.lists < CLUSTER_NUM);
+        let option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let query = queries.get(0);
+        let result = index.search(query, &deleted, &option);
+        let stats = index.cache_stats();
+        assert_eq!(index.search(query, &deleted, &option), result);
+        assert_eq!(index.cache_stats().hits, stats.hits + 2);
+        assert_eq!(index.cache_stats().misses, stats.misses);
+
+        let index_path = temp_dir.path().join("ivf.index");
+        let file = tokio::fs::File::create(&index_path).await.unwrap();
+        index
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+        let mut deserialized = IvfDisk::new(vectors.clone(), &path);
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        deserialized
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(deserialized.build_params(), index.build_params());
+        assert_eq!(deserialized.cache_stats(), CacheStats::default());
+        assert_eq!(deserialized.search(query, &deleted, &option), result);
+
+        // the lists are gone after the file is truncated
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(HEADER_SIZE as u64)
+            .unwrap();
+        deserialized.clear_cache();
+        assert!(deserialized.search(query, &deleted, &option).is_empty());
+        assert!(deserialized
+            .search_async(query, &deleted, &option)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ivf_disk_pq() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("ivf_pq.lists");
+        let mut index = IvfDisk::new(vectors.clone(), &path);
+        index.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::IvfPq(
+                IvfBuildParams {
+                    nlist: CLUSTER_NUM,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 8, nbits: 4 },
+            ),
+        });
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (HEADER_SIZE + DATASET_SIZE * (8 + 8)) as u64
+        );
+
+        let mut deleted = roaring::RoaringTreemap::new();
+        deleted.insert(0);
+        let option = SearchOption {
+            topk: 10,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let result = index.search(vectors.get(0), &deleted, &option);
+        assert_eq!(result.len(), option.topk);
+        assert!(!result.contains(&0));
+        let async_result = index
+            .search_async(vectors.get(0), &deleted, &option)
+            .await
+            .unwrap();
+        assert_eq!(result, async_result);
+
+        let index_path = temp_dir.path().join("ivf_pq.index");
+        let file = tokio::fs::File::create(&index_path).await.unwrap();
+        index
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+        let mut deserialized = IvfDisk::new(vectors.clone(), &path);
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        deserialized
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(deserialized.build_params(), index.build_params());
+        assert_eq!(
+            deserialized.search(vectors.get(0), &deleted, &option),
+            result
+        );
+
+        // the lists at the path are of another index
+        let flat_path = temp_dir.path().join("ivf.lists");
+        let mut flat = IvfDisk::new(vectors.clone(), &flat_path);
+        flat.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+        flat.search(vectors.get(0), &deleted, &option);
+        assert!(flat.cache_stats().lists > 0);
+        let file = tokio::fs::File::open(&index_path).await.unwrap();
+        assert!(flat
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .is_err());
+        assert!(!flat.is_trained());
+        assert_eq!(flat.cache_stats().lists, 0);
+        assert!(flat.validate_search(&option).is_err());
+    }
+}
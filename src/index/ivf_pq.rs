@@ -0,0 +1,509 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::cluster::Cluster;
+use super::pq::ProductQuantizer;
+use super::quantizer::{self, CoarseQuantizer};
+use super::rebalance::{self, RebalanceOption, RebalanceReport};
+use super::util;
+use crate::params::{BuildParams, IvfBuildParams, PqBuildParams, QuantizerType, SearchParams};
+use crate::*;
+use log::warn;
+use ordered_float::NotNan;
+use std::{collections::BinaryHeap, sync::Arc};
+use std::{io, pin::Pin};
+use tokio::io::AsyncWriteExt;
+
+// version 2: build params are stored after the metadata
+// version 3: build params contain the clustering
+// version 4: build params contain the coarse quantizer
+const VERSION: u16 = 4;
+
+// ivf with the vectors encoded by a product quantizer,
+// the distances are computed from the codes only
+pub struct IvfPq {
+    vectors: Arc<dyn VectorAccessor>,
+    clusters: Vec<Cluster>,
+    metric_type: metric::MetricType,
+    compact_threshold: f32,
+    // the report of the last training, not serialized
+    clustering_report: Option<util::ClusteringReport>,
+    // rebuilt whenever the centroids change
+    quantizer: Box<dyn CoarseQuantizer>,
+    params: Option<(IvfBuildParams, PqBuildParams)>,
+    pq: ProductQuantizer,
+    // the codes of all vectors, indexed by id
+    codes: Vec<u8>,
+}
+
+impl IvfPq {
+    pub fn new(vectors: Arc<dyn VectorAccessor>) -> Self {
+        Self {
+            vectors,
+            clusters: Vec::new(),
+            metric_type: metric::MetricType::None,
+            compact_threshold: util::DEFAULT_COMPACT_THRESHOLD,
+            clustering_report: None,
+            quantizer: quantizer::new(QuantizerType::Flat),
+            params: None,
+            pq: ProductQuantizer::default(),
+            codes: Vec::new(),
+        }
+    }
+
+    pub fn pq(&self) -> &ProductQuantizer {
+        &self.pq
+    }
+
+    pub fn clustering_report(&self) -> Option<&util::ClusteringReport> {
+        self.clustering_report.as_ref()
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.params.is_some()
+    }
+
+    // learns the centroids and codebooks from the sample only, the trained but
+    // empty index could be serialized as a template, and populated by add
+    pub fn train_quantizer(
+        &mut self,
+        sample: Arc<dyn VectorAccessor>,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let (ivf_params, pq_params) = Self::validate(sample.dim(), option)?;
+        let (mut clusters, report) =
+            util::train_clusters_with_report(option.metric_type, sample.clone(), &ivf_params, ctx)?;
+        for cluster in &mut clusters {
+            cluster.elements.clear();
+        }
+        let mut pq = ProductQuantizer::new(sample.dim(), pq_params.m, pq_params.nbits)?;
+        pq.train_with_context(sample, ivf_params.iteration_num, ctx)?;
+
+        self.metric_type = option.metric_type;
+        self.clusters = clusters;
+        self.clustering_report = Some(report);
+        self.pq = pq;
+        self.params = Some((ivf_params, pq_params));
+        self.build_quantizer();
+        self.codes.clear();
+        Ok(())
+    }
+
+    // encodes the vectors of the ids, and adds them to their nearest clusters
+    pub fn add(&mut self, ids: impl IntoIterator<Item = usize>) -> error::Result<()> {
+        if !self.is_trained() {
+            return Err(error::Error::InvalidArgument(
+                "can't add vectors to untrained ivf-pq index".to_string(),
+            ));
+        }
+
+        let size = self.pq.code_size();
+        self.codes.resize(self.vectors.len() * size, 0);
+        for id in ids {
+            let vector = self.vectors.get(id);
+            let target = util::nearest_cluster(self.metric_type, &self.clusters, vector);
+            self.clusters[target].add(id);
+            self.pq
+                .encode(vector, &mut self.codes[id * size..(id + 1) * size]);
+        }
+        Ok(())
+    }
+
+    // see util::delete
+    pub fn delete(&mut self, id: usize) -> bool {
+        util::delete(&mut self.clusters, id, self.compact_threshold)
+    }
+
+    pub fn compact(&mut self) -> usize {
+        util::compact(&mut self.clusters)
+    }
+
+    pub fn deleted_ratios(&self) -> Vec<f32> {
+        util::deleted_ratios(&self.clusters)
+    }
+
+    pub fn set_compact_threshold(&mut self, threshold: f32) -> error::Result<()> {
+        util::validate_compact_threshold(threshold)?;
+        self.compact_threshold = threshold;
+        Ok(())
+    }
+
+    pub fn cluster_sizes(&self) -> Vec<usize> {
+        util::cluster_sizes(&self.clusters)
+    }
+
+    pub fn cluster_elements(&self, cluster: usize) -> impl Iterator<Item = usize> + '_ {
+        util::cluster_elements(&self.clusters[cluster])
+    }
+
+    // see rebalance::rebalance
+    pub fn rebalance(&mut self, option: &RebalanceOption) -> error::Result<RebalanceReport> {
+        let report = rebalance::rebalance(
+            self.metric_type,
+            &mut self.clusters,
+            self.vectors.clone(),
+            option,
+        )?;
+        self.params = self
+            .params
+            .map(|(ivf, pq)| (rebalance::rebalanced_params(ivf, self.clusters.len()), pq));
+        self.build_quantizer();
+        Ok(report)
+    }
+
+    fn build_quantizer(&mut self) {
+        self.quantizer = quantizer::new(
+            self.params
+                .map_or(QuantizerType::Flat, |(ivf, _)| ivf.quantizer),
+        );
+        self.quantizer.build(self.metric_type, &self.clusters);
+    }
+
+    // returns an empty index over the vectors with the same centroids and codebooks
+    pub fn clone_empty(&self, vectors: Arc<dyn VectorAccessor>) -> Self {
+        let mut index = Self {
+            vectors,
+            clusters: self
+                .clusters
+                .iter()
+                .map(|c| Cluster::with_centroid(&c.centroid))
+                .collect(),
+            metric_type: self.metric_type,
+            compact_threshold: self.compact_threshold,
+            clustering_report: None,
+            quantizer: quantizer::new(QuantizerType::Flat),
+            params: self.params,
+            pq: self.pq.clone(),
+            codes: Vec::new(),
+        };
+        index.build_quantizer();
+        index
+    }
+
+    fn validate(
+        dim: usize,
+        option: &TrainOption,
+    ) -> error::Result<(IvfBuildParams, PqBuildParams)> {
+        option.validate(dim)?;
+        if option.metric_type != metric::MetricType::L2 {
+            return Err(error::Error::Unsupported(
+                "ivf-pq only supports L2 metric".to_string(),
+            ));
+        }
+        match option.params {
+            BuildParams::IvfPq(ivf, pq) => Ok((ivf, pq)),
+            params => Err(error::Error::InvalidArgument(format!(
+                "ivf-pq index requires ivf-pq build params, got {:?}",
+                params
+            ))),
+        }
+    }
+
+    fn code(&self, id: usize) -> &[u8] {
+        let size = self.pq.code_size();
+        &self.codes[id * size..(id + 1) * size]
+    }
+}
+
+#[async_trait]
+impl crate::AnnIndex for IvfPq {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train ivf-pq index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        let (ivf_params, pq_params) = Self::validate(self.vectors.dim(), option)?;
+        let (clusters, report) = util::train_clusters_with_report(
+            option.metric_type,
+            self.vectors.clone(),
+            &ivf_params,
+            ctx,
+        )?;
+
+        let pq = ProductQuantizer::new(self.vectors.dim(), pq_params.m, pq_params.nbits);
+        let mut pq = pq.expect("the pq params have been validated");
+        pq.train_with_context(self.vectors.clone(), ivf_params.iteration_num, ctx)?;
+
+        self.metric_type = option.metric_type;
+        self.clusters = clusters;
+        self.clustering_report = Some(report);
+        self.pq = pq;
+        self.params = Some((ivf_params, pq_params));
+        self.build_quantizer();
+        let size = self.pq.code_size();
+        self.codes = vec![0u8; self.vectors.len() * size];
+        for (i, code) in self.codes.chunks_exact_mut(size).enumerate() {
+            self.pq.encode(self.vectors.get(i), code);
+        }
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        let nprobe = match option.params {
+            // the bound doesn't hold for the distances of the codes
+            SearchParams::Ivf(params) if params.adaptive => {
+                panic!("ivf-pq index doesn't support adaptive nprobe")
+            }
+            SearchParams::Ivf(params) => params.nprobe,
+            params => panic!("ivf-pq index requires ivf search params, got {:?}", params),
+        };
+
+        let cluster_distances =
+            self.quantizer
+                .search(self.metric_type, &self.clusters, query_vector, nprobe);
+
+        let table = self.pq.distance_table(query_vector);
+        let mut topk: BinaryHeap<(NotNan<f32>, usize)> = BinaryHeap::with_capacity(option.topk);
+        let clusters = cluster_distances.iter().map(|(i, _)| &self.clusters[*i]);
+        for cluster in clusters {
+            for i in cluster.iter() {
+                if cluster.is_deleted(i) || deleted.contains(i as u64) {
+                    continue;
+                }
+
+                let distance = self.pq.table_distance(&table, self.code(i));
+
+                if topk.len() == option.topk {
+                    if topk.peek().unwrap().0.total_cmp(&distance).is_gt() {
+                        topk.pop();
+                    } else {
+                        continue;
+                    }
+                }
+                topk.push((NotNan::new(distance).unwrap(), i));
+            }
+        }
+
+        topk.iter().map(|(_, i)| *i).collect()
+    }
+
+    fn build_params(&self) -> Option<BuildParams> {
+        self.params.map(|(ivf, pq)| BuildParams::IvfPq(ivf, pq))
+    }
+
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u8(self.metric_type as u8).await?;
+        writer.write_u32_le(self.vectors.dim() as u32).await?;
+        writer.write_u32_le(self.clusters.len() as u32).await?;
+        self.build_params()
+            .ok_or_else(|| io::Error::other("index is not trained"))?
+            .serialize(&mut writer)
+            .await?;
+        for cluster in &self.clusters {
+            cluster.serialize(&mut writer).await?;
+        }
+
+        self.pq.serialize(&mut writer).await?;
+        writer.write_u64_le(self.codes.len() as u64).await?;
+        writer.write_all(&self.codes).await?;
+
+        writer.flush().await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} ivf-pq index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        self.metric_type = metric::MetricType::from(reader.read_u8().await?);
+        let dim = reader.read_u32_le().await? as usize;
+        if dim != self.vectors.dim() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expect {}-d ivf-pq index, got {}-d",
+                    self.vectors.dim(),
+                    dim
+                ),
+            ));
+        }
+        let nlist = reader.read_u32_le().await? as usize;
+        let params = match version {
+            1 => None,
+            2 => Some(BuildParams::deserialize_format(&mut reader, 1).await?),
+            3 => Some(BuildParams::deserialize_format(&mut reader, 2).await?),
+            _ => Some(BuildParams::deserialize(&mut reader).await?),
+        };
+        let params = match params {
+            None => None,
+            Some(BuildParams::IvfPq(ivf, pq)) => Some((ivf, pq)),
+            Some(params) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected build params for ivf-pq index: {:?}", params),
+                ))
+            }
+        };
+        self.clusters = Vec::with_capacity(nlist);
+        for _ in 0..nlist {
+            self.clusters
+                .push(Cluster::deserialize(&mut reader, dim).await?);
+        }
+
+        self.pq = ProductQuantizer::deserialize(&mut reader).await?;
+        let len = reader.read_u64_le().await? as usize;
+        self.codes = vec![0u8; len];
+        reader.read_exact(&mut self.codes).await?;
+        self.params = Some(params.unwrap_or((
+            IvfBuildParams {
+                nlist,
+                ..Default::default()
+            },
+            PqBuildParams {
+                m: self.pq.m(),
+                nbits: self.pq.nbits(),
+            },
+        )));
+        // the codes of a template are empty
+        if len != 0 && len != self.vectors.len() * self.pq.code_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("codes size mismatch: {}", len),
+            ));
+        }
+        self.build_quantizer();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::ivf_pq::*;
+    use crate::params::IvfSearchParams;
+    use crate::test_util::gen_vectors;
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    fn check_recall(index: &IvfPq, accessor: &dyn VectorAccessor) {
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let bitmap = roaring::RoaringTreemap::new();
+        let mut close_count = 0;
+        for i in 0..accessor.len() {
+            let query = accessor.get(i);
+            let result = index.search(query, &bitmap, &option);
+            assert_eq!(result.len(), option.topk);
+            close_count += result
+                .iter()
+                .filter(|id| metric::l2_distance(query, accessor.get(**id)) == 0.0)
+                .count();
+        }
+
+        let recall = close_count as f32 / (accessor.len() * CLUSTER_NUM) as f32;
+        assert!(recall > 0.9, "recall: {}", recall);
+    }
+
+    #[tokio::test]
+    async fn test_ivf_pq() {
+        let accessor = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let params = BuildParams::IvfPq(
+            IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            },
+            PqBuildParams { m: 8, nbits: 5 },
+        );
+
+        let mut index = IvfPq::new(accessor.clone());
+        assert_eq!(index.build_params(), None);
+        let ctx = TrainContext::default();
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(Default::default()),
+        };
+        assert!(matches!(
+            index.train_with_context(&option, &ctx),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        // too few vectors to train the codebooks
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::IvfPq(
+                IvfBuildParams {
+                    nlist: 4,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 8, nbits: 8 },
+            ),
+        };
+        let mut small = IvfPq::new(Arc::new(gen_vectors(16, DIM, 4)));
+        assert!(matches!(
+            small.train_with_context(&option, &ctx),
+            Err(error::Error::InvalidArgument(_))
+        ));
+        assert_eq!(small.build_params(), None);
+
+        index.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params,
+        });
+        assert_eq!(index.clusters.len(), CLUSTER_NUM);
+        assert_eq!(index.build_params(), Some(params));
+        check_recall(&index, accessor.as_ref());
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("ivf_pq.index");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        index
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let mut index = IvfPq::new(accessor.clone());
+        index
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(index.build_params(), Some(params));
+        check_recall(&index, accessor.as_ref());
+
+        let mut shard = index.clone_empty(accessor.clone());
+        assert_eq!(shard.build_params(), Some(params));
+        shard.add(0..accessor.len()).unwrap();
+        check_recall(&shard, accessor.as_ref());
+    }
+}
@@ -13,14 +13,25 @@
 // limitations under the License.
 
 pub mod cluster;
+pub mod diskann;
+pub mod factory;
+pub mod flat;
 pub mod ivf;
+pub mod ivf_disk;
+pub mod ivf_pq;
+pub mod pq;
+pub mod quantizer;
+pub mod rebalance;
+pub mod refine;
+pub mod transformed;
 pub mod util;
+pub mod vamana;
 
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
 
-use crate::{AnnIndex, VectorAccessor};
+use crate::{error::Result, params::BuildParams, AnnIndex, VectorAccessor};
 
 #[derive(Debug, Clone, Copy)]
 pub enum IndexType {
@@ -35,3 +46,14 @@ pub fn new(typ: IndexType, accessor: Arc<dyn VectorAccessor>) -> Arc<RwLock<dyn
         _ => unimplemented!("unsupported index type"),
     }
 }
+
+// builds the untrained index described by the spec, see factory::IndexSpec,
+// returns the build params of the spec to train it with
+pub fn from_spec(
+    spec: &str,
+    accessor: Arc<dyn VectorAccessor>,
+) -> Result<(Arc<RwLock<dyn AnnIndex>>, BuildParams)> {
+    let spec = spec.parse::<factory::IndexSpec>()?;
+    let index = spec.build(accessor)?;
+    Ok((Arc::new(RwLock::new(index)), spec.build_params()))
+}
@@ -0,0 +1,260 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::util;
+use crate::{
+    accessor::MemoryVectorAccessor,
+    error::{Error, Result},
+    metric::{self, MetricType},
+    params::{IvfBuildParams, PqBuildParams},
+    TrainContext, TrainProgress, TrainStage, VectorAccessor,
+};
+use std::{cmp, io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// splits the vector into m sub-vectors, and encodes each of them by
+// the nearest centroid of the codebook of its subspace
+#[derive(Default, Clone)]
+pub struct ProductQuantizer {
+    dim: usize,
+    m: usize,
+    nbits: usize,
+    // m codebooks of ksub centroids of dsub floats
+    codebooks: Vec<f32>,
+}
+
+impl ProductQuantizer {
+    pub fn new(dim: usize, m: usize, nbits: usize) -> Result<Self> {
+        PqBuildParams { m, nbits }.validate(dim)?;
+
+        Ok(Self {
+            dim,
+            m,
+            nbits,
+            codebooks: Vec::new(),
+        })
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn m(&self) -> usize {
+        self.m
+    }
+
+    pub fn nbits(&self) -> usize {
+        self.nbits
+    }
+
+    pub fn ksub(&self) -> usize {
+        1 << self.nbits
+    }
+
+    pub fn dsub(&self) -> usize {
+        self.dim / self.m
+    }
+
+    pub fn code_size(&self) -> usize {
+        self.m
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.codebooks.is_empty()
+    }
+
+    pub fn centroid(&self, subspace: usize, code: u8) -> &[f32] {
+        let dsub = self.dsub();
+        let start = (subspace * self.ksub() + code as usize) * dsub;
+        &self.codebooks[start..start + dsub]
+    }
+
+    pub fn train(&mut self, vectors: Arc<dyn VectorAccessor>, iteration_num: usize) -> Result<()> {
+        self.train_with_context(vectors, iteration_num, &TrainContext::default())
+    }
+
+    // reports the progress per subspace
+    pub fn train_with_context(
+        &mut self,
+        vectors: Arc<dyn VectorAccessor>,
+        iteration_num: usize,
+        ctx: &TrainContext,
+    ) -> Result<()> {
+        if vectors.dim() != self.dim {
+            return Err(Error::InvalidArgument(format!(
+                "expect vectors of dim {}, got {}",
+                self.dim,
+                vectors.dim()
+            )));
+        }
+        if vectors.len() < self.ksub() {
+            return Err(Error::InvalidArgument(format!(
+                "at least {} vectors are required to train, got {}",
+                self.ksub(),
+                vectors.len()
+            )));
+        }
+
+        let (dsub, ksub) = (self.dsub(), self.ksub());
+        let params = IvfBuildParams {
+            nlist: ksub,
+            iteration_num,
+            ..Default::default()
+        };
+        // the k-means only trains on the first vectors
+        let n = cmp::min(vectors.len(), ksub * util::MAX_CLUSTER_SIZE);
+        let mut codebooks = Vec::with_capacity(self.m * ksub * dsub);
+        for subspace in 0..self.m {
+            let mut data = Vec::with_capacity(n * dsub);
+            for i in 0..n {
+                data.extend_from_slice(&vectors.get(i)[subspace * dsub..(subspace + 1) * dsub]);
+            }
+            let sub_vectors = Arc::new(MemoryVectorAccessor::new(dsub, data));
+
+            let (clusters, _) = util::train_clusters_with_report(
+                MetricType::L2,
+                sub_vectors,
+                &params,
+                &ctx.without_callback(),
+            )?;
+            for cluster in &clusters {
+                codebooks.extend_from_slice(&cluster.centroid);
+            }
+            ctx.report(TrainProgress {
+                stage: TrainStage::Quantization,
+                step: subspace + 1,
+                total: self.m,
+                inertia: None,
+            })?;
+        }
+        self.codebooks = codebooks;
+
+        Ok(())
+    }
+
+    pub fn encode(&self, vector: &[f32], code: &mut [u8]) {
+        let dsub = self.dsub();
+        for (subspace, code) in code.iter_mut().enumerate().take(self.m) {
+            let sub_vector = &vector[subspace * dsub..(subspace + 1) * dsub];
+            let mut min_distance = f32::MAX;
+            for c in 0..self.ksub() {
+                let distance = metric::l2_distance(sub_vector, self.centroid(subspace, c as u8));
+                if distance < min_distance {
+                    min_distance = distance;
+                    *code = c as u8;
+                }
+            }
+        }
+    }
+
+    pub fn decode(&self, code: &[u8], vector: &mut [f32]) {
+        let dsub = self.dsub();
+        for (subspace, c) in code.iter().enumerate().take(self.m) {
+            vector[subspace * dsub..(subspace + 1) * dsub]
+                .copy_from_slice(self.centroid(subspace, *c));
+        }
+    }
+
+    // the L2 distances from the sub-vectors of the query to all centroids,
+    // the distance to a code is the sum of m table lookups
+    pub fn distance_table(&self, query: &[f32]) -> Vec<f32> {
+        let dsub = self.dsub();
+        let mut table = Vec::with_capacity(self.m * self.ksub());
+        for subspace in 0..self.m {
+            let sub_query = &query[subspace * dsub..(subspace + 1) * dsub];
+            for c in 0..self.ksub() {
+                table.push(metric::l2_distance(
+                    sub_query,
+                    self.centroid(subspace, c as u8),
+                ));
+            }
+        }
+        table
+    }
+
+    pub fn table_distance(&self, table: &[f32], code: &[u8]) -> f32 {
+        let ksub = self.ksub();
+        code.iter()
+            .enumerate()
+            .map(|(subspace, c)| table[subspace * ksub + *c as usize])
+            .sum()
+    }
+
+    pub async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.dim as u32).await?;
+        writer.write_u32_le(self.m as u32).await?;
+        writer.write_u8(self.nbits as u8).await?;
+        crate::transform::write_floats(writer, &self.codebooks).await
+    }
+
+    pub async fn deserialize(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self> {
+        let dim = reader.read_u32_le().await? as usize;
+        let m = reader.read_u32_le().await? as usize;
+        let nbits = reader.read_u8().await? as usize;
+        let mut pq = Self::new(dim, m, nbits)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        pq.codebooks = crate::transform::read_floats(reader).await?;
+        if pq.is_trained() && pq.codebooks.len() != m * pq.ksub() * pq.dsub() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid codebooks size {}", pq.codebooks.len()),
+            ));
+        }
+        Ok(pq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::pq::*;
+    use crate::test_util::gen_vectors;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_product_quantizer() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        assert!(ProductQuantizer::new(DIM, 3, 8).is_err());
+        assert!(ProductQuantizer::new(DIM, 4, 9).is_err());
+
+        let mut pq = ProductQuantizer::new(DIM, 4, 5).unwrap();
+        pq.train(vectors.clone(), 25).unwrap();
+
+        let mut data = Vec::new();
+        pq.serialize(&mut data).await.unwrap();
+        let pq = ProductQuantizer::deserialize(&mut std::io::Cursor::new(data))
+            .await
+            .unwrap();
+
+        let mut code = vec![0u8; pq.code_size()];
+        let mut decoded = vec![0f32; DIM];
+        let mut error = 0f32;
+        for i in 0..vectors.len() {
+            let vector = vectors.get(i);
+            pq.encode(vector, &mut code);
+            pq.decode(&code, &mut decoded);
+            let distance = metric::l2_distance(vector, &decoded);
+            error += distance;
+
+            let table = pq.distance_table(vector);
+            assert!((pq.table_distance(&table, &code) - distance).abs() < 1e-4);
+        }
+
+        // the variance of the uniform distributed vectors is DIM / 12
+        let error = error / vectors.len() as f32;
+        assert!(error < DIM as f32 / 12.0 / 4.0, "error: {}", error);
+    }
+}
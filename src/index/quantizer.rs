@@ -0,0 +1,301 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::cluster::Cluster;
+use crate::{metric::MetricType, params::QuantizerType};
+use ordered_float::OrderedFloat;
+use std::{
+    cmp::{self, Reverse},
+    collections::{BinaryHeap, HashSet},
+};
+
+// finds the nearest clusters of the query by their centroids
+pub trait CoarseQuantizer: Send + Sync {
+    fn quantizer_type(&self) -> QuantizerType;
+
+    // indexes the centroids, called whenever the centroids change
+    fn build(&mut self, metric_type: MetricType, clusters: &[Cluster]);
+
+    // returns at most nprobe clusters and their distances, the nearest first
+    fn search(
+        &self,
+        metric_type: MetricType,
+        clusters: &[Cluster],
+        query: &[f32],
+        nprobe: usize,
+    ) -> Vec<(usize, f32)>;
+}
+
+pub fn new(typ: QuantizerType) -> Box<dyn CoarseQuantizer> {
+    match typ {
+        QuantizerType::Flat => Box::new(FlatQuantizer),
+        QuantizerType::Graph { m, ef_construction } => {
+            Box::new(GraphQuantizer::new(m, ef_construction))
+        }
+    }
+}
+
+// keeps the k nearest ones sorted, selecting them before sorting
+// costs O(n + k log k) instead of O(n log n),
+// the ties are broken by the index as util::nearest_cluster does
+pub fn select_nearest(mut distances: Vec<(usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    let cmp = |a: &(usize, f32), b: &(usize, f32)| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0));
+    if k < distances.len() {
+        distances.select_nth_unstable_by(k, cmp);
+        distances.truncate(k);
+    }
+    distances.sort_unstable_by(cmp);
+    distances
+}
+
+pub struct FlatQuantizer;
+
+impl CoarseQuantizer for FlatQuantizer {
+    fn quantizer_type(&self) -> QuantizerType {
+        QuantizerType::Flat
+    }
+
+    fn build(&mut self, _metric_type: MetricType, _clusters: &[Cluster]) {}
+
+    fn search(
+        &self,
+        metric_type: MetricType,
+        clusters: &[Cluster],
+        query: &[f32],
+        nprobe: usize,
+    ) -> Vec<(usize, f32)> {
+        let distances = clusters
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i, metric_type.distance(&c.centroid, query)))
+            .collect();
+        select_nearest(distances, nprobe)
+    }
+}
+
+// a navigable small world graph of the centroids, each centroid is linked to
+// the diverse near ones found by searching the graph built so far
+pub struct GraphQuantizer {
+    m: usize,
+    ef_construction: usize,
+    neighbors: Vec<Vec<u32>>,
+    // the centroid nearest to the mean of all centroids
+    entry: usize,
+}
+
+impl GraphQuantizer {
+    pub fn new(m: usize, ef_construction: usize) -> Self {
+        Self {
+            m,
+            ef_construction,
+            neighbors: Vec::new(),
+            entry: 0,
+        }
+    }
+
+    pub fn neighbors(&self, cluster: usize) -> &[u32] {
+        &self.neighbors[cluster]
+    }
+
+    // the best-first search from the entry,
+    // returns the ef nearest visited ones, the nearest first
+    fn search_graph(
+        &self,
+        metric_type: MetricType,
+        clusters: &[Cluster],
+        query: &[f32],
+        entry: usize,
+        ef: usize,
+    ) -> Vec<(usize, f32)> {
+        let distance = |i: usize| metric_type.distance(&clusters[i].centroid, query);
+
+        let mut visited = HashSet::new();
+        visited.insert(entry);
+        let entry_distance = OrderedFloat(distance(entry));
+        let mut candidates = BinaryHeap::from([Reverse((entry_distance, entry))]);
+        let mut results = BinaryHeap::from([(entry_distance, entry)]);
+        while let Some(Reverse((d, i))) = candidates.pop() {
+            if results.len() >= ef && d > results.peek().unwrap().0 {
+                break;
+            }
+
+            for &neighbor in &self.neighbors[i] {
+                let neighbor = neighbor as usize;
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let d = OrderedFloat(distance(neighbor));
+                if results.len() < ef || d < results.peek().unwrap().0 {
+                    candidates.push(Reverse((d, neighbor)));
+                    results.push((d, neighbor));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(d, i)| (i, d.0))
+            .collect()
+    }
+
+    // keeps the candidates nearer to the base than to the selected ones,
+    // which links the base to different directions, then fills up by distance
+    fn select_neighbors(
+        metric_type: MetricType,
+        clusters: &[Cluster],
+        candidates: &[(usize, f32)],
+        m: usize,
+    ) -> Vec<u32> {
+        let mut selected: Vec<usize> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for &(i, d) in candidates {
+            if selected.len() == m {
+                break;
+            }
+            let diverse = selected
+                .iter()
+                .all(|&s| metric_type.distance(&clusters[s].centroid, &clusters[i].centroid) > d);
+            match diverse {
+                true => selected.push(i),
+                false => pruned.push(i),
+            }
+        }
+
+        let remain = m - selected.len();
+        selected.extend(pruned.into_iter().take(remain));
+        selected.into_iter().map(|i| i as u32).collect()
+    }
+}
+
+impl CoarseQuantizer for GraphQuantizer {
+    fn quantizer_type(&self) -> QuantizerType {
+        QuantizerType::Graph {
+            m: self.m,
+            ef_construction: self.ef_construction,
+        }
+    }
+
+    fn build(&mut self, metric_type: MetricType, clusters: &[Cluster]) {
+        let max_degree = self.m * 2;
+        self.neighbors = vec![Vec::new(); clusters.len()];
+        self.entry = 0;
+        for i in 1..clusters.len() {
+            let candidates = self.search_graph(
+                metric_type,
+                clusters,
+                &clusters[i].centroid,
+                0,
+                self.ef_construction,
+            );
+            let selected = Self::select_neighbors(metric_type, clusters, &candidates, self.m);
+            for &j in &selected {
+                let j = j as usize;
+                self.neighbors[j].push(i as u32);
+                if self.neighbors[j].len() > max_degree {
+                    let mut candidates: Vec<_> = self.neighbors[j]
+                        .iter()
+                        .map(|&k| {
+                            let k = k as usize;
+                            let d =
+                                metric_type.distance(&clusters[j].centroid, &clusters[k].centroid);
+                            (k, d)
+                        })
+                        .collect();
+                    candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+                    self.neighbors[j] =
+                        Self::select_neighbors(metric_type, clusters, &candidates, max_degree);
+                }
+            }
+            self.neighbors[i] = selected;
+        }
+
+        if let Some(first) = clusters.first() {
+            let mut mean = vec![0f32; first.centroid.len()];
+            for cluster in clusters {
+                for (m, v) in mean.iter_mut().zip(&cluster.centroid) {
+                    *m += v / clusters.len() as f32;
+                }
+            }
+            self.entry = self
+                .search_graph(metric_type, clusters, &mean, 0, self.ef_construction)
+                .first()
+                .map_or(0, |(i, _)| *i);
+        }
+    }
+
+    fn search(
+        &self,
+        metric_type: MetricType,
+        clusters: &[Cluster],
+        query: &[f32],
+        nprobe: usize,
+    ) -> Vec<(usize, f32)> {
+        if clusters.is_empty() || nprobe == 0 {
+            return Vec::new();
+        }
+
+        let ef = cmp::max(nprobe, self.ef_construction);
+        let mut result = self.search_graph(metric_type, clusters, query, self.entry, ef);
+        result.truncate(nprobe);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::quantizer::*;
+    use crate::test_util::gen_floats;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 1024;
+
+    #[test]
+    fn test_coarse_quantizer() {
+        let distances = vec![(0, 3.0), (1, 1.0), (2, 4.0), (3, 0.5), (4, 2.0)];
+        assert_eq!(
+            select_nearest(distances.clone(), 3),
+            vec![(3, 0.5), (1, 1.0), (4, 2.0)]
+        );
+        assert_eq!(select_nearest(distances.clone(), 10).len(), 5);
+        assert!(select_nearest(distances, 0).is_empty());
+
+        let data = gen_floats(CLUSTER_NUM * DIM);
+        let clusters: Vec<_> = data.chunks_exact(DIM).map(Cluster::with_centroid).collect();
+        let metric_type = MetricType::L2;
+        let flat = new(QuantizerType::Flat);
+        let mut graph = new(QuantizerType::Graph {
+            m: 16,
+            ef_construction: 64,
+        });
+        graph.build(metric_type, &clusters);
+
+        let nprobe = 8;
+        let queries = gen_floats(100 * DIM);
+        let mut hits = 0;
+        for query in queries.chunks_exact(DIM) {
+            let expected = flat.search(metric_type, &clusters, query, nprobe);
+            let result = graph.search(metric_type, &clusters, query, nprobe);
+            assert_eq!(result.len(), nprobe);
+            assert!(result.windows(2).all(|w| w[0].1 <= w[1].1));
+            hits += result.iter().filter(|r| expected.contains(r)).count();
+        }
+        let recall = hits as f32 / (100 * nprobe) as f32;
+        assert!(recall > 0.9, "recall {}", recall);
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{cluster::Cluster, util};
+use crate::{
+    error::{Error, Result},
+    metric::MetricType,
+    params::{Clustering, IvfBuildParams},
+    VectorAccessor,
+};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RebalanceOption {
+    // the lists larger than this are split
+    pub max_cluster_size: usize,
+    // the lists smaller than this are merged into their neighbors
+    pub min_cluster_size: usize,
+    // the k-means iterations of splitting a list
+    pub iteration_num: usize,
+}
+
+impl RebalanceOption {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_cluster_size < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "max_cluster_size must be at least 2, got {}",
+                self.max_cluster_size
+            )));
+        }
+        // the halves of a split list must not be merged again
+        if self.min_cluster_size * 2 > self.max_cluster_size {
+            return Err(Error::InvalidArgument(format!(
+                "min_cluster_size {} must be at most half of max_cluster_size {}",
+                self.min_cluster_size, self.max_cluster_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitReport {
+    // the indices after rebalancing, the larger half stays in the cluster
+    pub cluster: usize,
+    pub new_cluster: usize,
+    // the number of ids moved to the new cluster
+    pub moved: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeReport {
+    // the index before rebalancing, the cluster is removed
+    pub cluster: usize,
+    // the number of ids moved to the other clusters
+    pub moved: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RebalanceReport {
+    pub merges: Vec<MergeReport>,
+    pub splits: Vec<SplitReport>,
+}
+
+impl RebalanceReport {
+    pub fn moved(&self) -> usize {
+        self.merges.iter().map(|m| m.moved).sum::<usize>()
+            + self.splits.iter().map(|s| s.moved).sum::<usize>()
+    }
+}
+
+// the build params reporting the lists after rebalancing
+pub(crate) fn rebalanced_params(params: IvfBuildParams, nlist: usize) -> IvfBuildParams {
+    let clustering = match params.clustering {
+        Clustering::Hierarchical { super_nlist } => Clustering::Hierarchical {
+            super_nlist: super_nlist.min(nlist),
+        },
+        clustering => clustering,
+    };
+    IvfBuildParams {
+        nlist,
+        clustering,
+        ..params
+    }
+}
+
+// merges the tiny lists, then splits the oversize lists,
+// only the affected lists are re-clustered, the deleted elements are removed as well
+pub fn rebalance(
+    metric_type: MetricType,
+    clusters: &mut Vec<Cluster>,
+    vectors: Arc<dyn VectorAccessor>,
+    option: &RebalanceOption,
+) -> Result<RebalanceReport> {
+    if clusters.is_empty() {
+        return Err(Error::InvalidArgument(
+            "can't rebalance untrained index".to_string(),
+        ));
+    }
+    option.validate()?;
+    for cluster in clusters.iter_mut() {
+        cluster.compact();
+    }
+
+    let mut report = RebalanceReport::default();
+
+    // merge the smallest lists first, each into the nearest of the remaining lists
+    let mut order: Vec<_> = (0..clusters.len()).collect();
+    order.sort_by_key(|i| clusters[*i].len());
+    let mut removed = vec![false; clusters.len()];
+    for i in order {
+        let remaining = removed.iter().filter(|r| !**r).count();
+        if clusters[i].len() >= option.min_cluster_size || remaining <= 1 {
+            continue;
+        }
+
+        removed[i] = true;
+        let ids: Vec<_> = clusters[i].iter().collect();
+        let mut affected = Vec::new();
+        for id in &ids {
+            let vector = vectors.get(*id);
+            let target = (0..clusters.len())
+                .filter(|j| !removed[*j])
+                .min_by(|a, b| {
+                    let a = metric_type.distance(&clusters[*a].centroid, vector);
+                    let b = metric_type.distance(&clusters[*b].centroid, vector);
+                    a.total_cmp(&b)
+                })
+                .unwrap();
+            clusters[target].add(*id);
+            affected.push(target);
+        }
+
+        affected.sort_unstable();
+        affected.dedup();
+        for j in affected {
+            clusters[j].calc_centroid(vectors.clone());
+        }
+        report.merges.push(MergeReport {
+            cluster: i,
+            moved: ids.len(),
+        });
+    }
+    let mut index = 0;
+    clusters.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+
+    // split the lists until all of them fit
+    let mut i = 0;
+    while i < clusters.len() {
+        if clusters[i].len() <= option.max_cluster_size {
+            i += 1;
+            continue;
+        }
+
+        let new = split(
+            metric_type,
+            &mut clusters[i],
+            vectors.clone(),
+            option.iteration_num,
+        );
+        report.splits.push(SplitReport {
+            cluster: i,
+            new_cluster: clusters.len(),
+            moved: new.len(),
+        });
+        clusters.push(new);
+    }
+
+    Ok(report)
+}
+
+// splits the cluster by 2-means over its elements, returns the smaller part
+fn split(
+    metric_type: MetricType,
+    cluster: &mut Cluster,
+    vectors: Arc<dyn VectorAccessor>,
+    iteration_num: usize,
+) -> Cluster {
+    let ids: Vec<_> = cluster.iter().collect();
+    let first = rand::random::<usize>() % ids.len();
+    let second = (first + 1 + rand::random::<usize>() % (ids.len() - 1)) % ids.len();
+    let mut parts = vec![
+        Cluster::with_centroid(vectors.get(ids[first])),
+        Cluster::with_centroid(vectors.get(ids[second])),
+    ];
+
+    for _ in 0..iteration_num.max(1) {
+        let mut new_parts: Vec<_> = parts
+            .iter()
+            .map(|p| Cluster::with_centroid(&p.centroid))
+            .collect();
+        util::assign(
+            metric_type,
+            &mut new_parts,
+            vectors.as_ref(),
+            ids.iter().copied(),
+        );
+        if new_parts.iter().any(|p| p.len() == 0) {
+            break;
+        }
+        for part in new_parts.iter_mut() {
+            part.calc_centroid(vectors.clone());
+        }
+        parts = new_parts;
+    }
+
+    // the elements can't be separated by distance, e.g. they are duplicates
+    if parts.iter().any(|p| p.len() == 0) {
+        let mut new = cluster.split();
+        cluster.calc_centroid(vectors.clone());
+        new.calc_centroid(vectors);
+        return new;
+    }
+
+    parts.sort_by_key(|p| std::cmp::Reverse(p.len()));
+    let new = parts.pop().unwrap();
+    *cluster = parts.pop().unwrap();
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::ivf::Ivf;
+    use crate::index::rebalance::*;
+    use crate::params::*;
+    use crate::test_util::gen_floats;
+    use crate::{metric, AnnIndex, SearchOption, TrainOption};
+
+    const DIM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[test]
+    fn test_rebalance() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let mut ivf = Ivf::new(vectors.clone());
+        ivf.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: 4,
+                ..Default::default()
+            }),
+        });
+
+        let option = RebalanceOption {
+            max_cluster_size: 128,
+            min_cluster_size: 32,
+            iteration_num: 10,
+        };
+        assert!(ivf
+            .rebalance(&RebalanceOption {
+                min_cluster_size: 65,
+                ..option
+            })
+            .is_err());
+
+        let report = ivf.rebalance(&option).unwrap();
+        assert!(!report.splits.is_empty());
+        let sizes = ivf.cluster_sizes();
+        assert!(
+            sizes.iter().all(|s| *s <= option.max_cluster_size),
+            "{:?}",
+            sizes
+        );
+        assert_eq!(sizes.len(), 4 + report.splits.len() - report.merges.len());
+        assert_eq!(sizes.iter().sum::<usize>(), DATASET_SIZE);
+        let nlist = |ivf: &Ivf| ivf.build_params().unwrap().ivf().unwrap().nlist;
+        assert_eq!(nlist(&ivf), sizes.len());
+
+        // shrink a list below the min size
+        let target = sizes
+            .iter()
+            .position(|s| *s > option.min_cluster_size)
+            .unwrap();
+        let mut deleted = roaring::RoaringTreemap::new();
+        let ids: Vec<_> = ivf
+            .cluster_elements(target)
+            .skip(option.min_cluster_size - 1)
+            .collect();
+        for id in ids {
+            assert!(ivf.delete(id));
+            deleted.insert(id as u64);
+        }
+        let before = ivf.cluster_sizes().len();
+        let report = ivf.rebalance(&option).unwrap();
+        assert!(!report.merges.is_empty());
+        let sizes = ivf.cluster_sizes();
+        // the shrunk list and any other small one are merged away,
+        // only the halves of the splits may be smaller
+        let split: Vec<_> = report
+            .splits
+            .iter()
+            .flat_map(|s| [s.cluster, s.new_cluster])
+            .collect();
+        assert!(
+            (0..sizes.len())
+                .filter(|i| !split.contains(i))
+                .all(|i| sizes[i] >= option.min_cluster_size),
+            "{:?}",
+            sizes
+        );
+        assert_eq!(
+            sizes.len(),
+            before - report.merges.len() + report.splits.len()
+        );
+        assert_eq!(nlist(&ivf), sizes.len());
+
+        let params = rebalanced_params(
+            IvfBuildParams {
+                nlist: 16,
+                clustering: Clustering::Hierarchical { super_nlist: 8 },
+                ..Default::default()
+            },
+            4,
+        );
+        assert!(params.validate().is_ok());
+        assert_eq!(
+            params.clustering,
+            Clustering::Hierarchical { super_nlist: 4 }
+        );
+        assert_eq!(
+            sizes.iter().sum::<usize>(),
+            DATASET_SIZE - deleted.len() as usize
+        );
+
+        // every live vector is still reachable
+        let option = SearchOption {
+            topk: 1,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: sizes.len(),
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let no_deleted = roaring::RoaringTreemap::new();
+        for id in 0..DATASET_SIZE {
+            if deleted.contains(id as u64) {
+                continue;
+            }
+            let result = ivf.search(vectors.get(id), &no_deleted, &option);
+            assert_eq!(result, vec![id]);
+        }
+    }
+}
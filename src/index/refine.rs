@@ -0,0 +1,407 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::quantizer::select_nearest;
+use crate::{
+    error::{self, Error},
+    metric::MetricType,
+    *,
+};
+use log::warn;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u16 = 1;
+
+pub const DEFAULT_FACTOR: usize = 4;
+
+// asks the inner index for topk * factor candidates, and re-ranks them
+// by the exact distances to the full precision vectors, which could be
+// slower to access than the ones the inner index keeps. search_async
+// reads them by VectorAccessor::get_async
+pub struct Refine {
+    index: Box<dyn AnnIndex>,
+    vectors: Arc<dyn VectorAccessor>,
+    metric_type: MetricType,
+    factor: usize,
+}
+
+impl Refine {
+    pub fn new(index: Box<dyn AnnIndex>, vectors: Arc<dyn VectorAccessor>) -> Self {
+        Self {
+            index,
+            vectors,
+            metric_type: MetricType::None,
+            factor: DEFAULT_FACTOR,
+        }
+    }
+
+    pub fn index(&self) -> &dyn AnnIndex {
+        self.index.as_ref()
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    pub fn set_factor(&mut self, factor: usize) -> error::Result<()> {
+        if factor == 0 {
+            return Err(Error::InvalidArgument(
+                "refine factor must be positive".to_string(),
+            ));
+        }
+        self.factor = factor;
+        Ok(())
+    }
+
+    fn inner_option(&self, option: &SearchOption) -> error::Result<SearchOption> {
+        let factor = option.refine_factor.unwrap_or(self.factor);
+        let topk = option.topk.checked_mul(factor).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "topk {} times refine factor {} overflows",
+                option.topk, factor
+            ))
+        })?;
+        Ok(SearchOption {
+            topk,
+            params: option.params,
+            refine_factor: None,
+        })
+    }
+
+    fn rerank(&self, query_vector: &[f32], candidates: Vec<usize>, topk: usize) -> Vec<usize> {
+        let candidates = candidates
+            .into_iter()
+            .map(|id| {
+                let distance = self
+                    .metric_type
+                    .distance(query_vector, self.vectors.get(id));
+                (id, distance)
+            })
+            .collect();
+
+        select_nearest(candidates, topk)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    // the same as rerank, but the vectors are read concurrently
+    async fn rerank_async(
+        &self,
+        query_vector: &[f32],
+        candidates: Vec<usize>,
+        topk: usize,
+    ) -> Result<Vec<usize>, io::Error> {
+        let vectors =
+            futures::future::try_join_all(candidates.iter().map(|id| self.vectors.get_async(*id)))
+                .await?;
+        let candidates = candidates
+            .into_iter()
+            .zip(vectors)
+            .map(|(id, vector)| (id, self.metric_type.distance(query_vector, &vector)))
+            .collect();
+
+        Ok(select_nearest(candidates, topk)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl AnnIndex for Refine {
+    fn train(&mut self, option: &TrainOption) {
+        self.index.train(option);
+        self.metric_type = option.metric_type;
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        self.index.train_with_context(option, ctx)?;
+        self.metric_type = option.metric_type;
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        let inner = self
+            .inner_option(option)
+            .unwrap_or_else(|err| panic!("invalid search option: {}", err));
+        let candidates = self.index.search(query_vector, deleted, &inner);
+        self.rerank(query_vector, candidates, option.topk)
+    }
+
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        let inner = self
+            .inner_option(option)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        let candidates = self
+            .index
+            .search_async(query_vector, deleted, &inner)
+            .await?;
+        self.rerank_async(query_vector, candidates, option.topk)
+            .await
+    }
+
+    fn build_params(&self) -> Option<params::BuildParams> {
+        self.index.build_params()
+    }
+
+    fn validate_search(&self, option: &SearchOption) -> error::Result<()> {
+        option.validate()?;
+        self.index.validate_search(&self.inner_option(option)?)
+    }
+
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u8(self.metric_type as u8).await?;
+        writer.write_u32_le(self.factor as u32).await?;
+        self.index.serialize(writer).await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} refine index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        self.metric_type = MetricType::from(reader.read_u8().await?);
+        self.factor = reader.read_u32_le().await? as usize;
+        if self.factor == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "refine factor must be positive",
+            ));
+        }
+        self.index.deserialize(reader).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::{ivf_pq::IvfPq, refine::*};
+    use crate::params::*;
+    use crate::test_util::gen_floats;
+    use crate::tune;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[tokio::test]
+    async fn test_refine() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let option = TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::IvfPq(
+                IvfBuildParams {
+                    nlist: CLUSTER_NUM,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 4, nbits: 5 },
+            ),
+        };
+        let mut pq = IvfPq::new(vectors.clone());
+        pq.train(&option);
+        let mut refine = Refine::new(Box::new(IvfPq::new(vectors.clone())), vectors.clone());
+        assert!(refine.set_factor(0).is_err());
+        refine.set_factor(8).unwrap();
+        refine.train(&option);
+        assert_eq!(refine.build_params(), Some(option.params));
+
+        let topk = 10;
+        let queries = MemoryVectorAccessor::new(DIM, gen_floats(32 * DIM));
+        let truth = tune::ground_truth(MetricType::L2, vectors.as_ref(), &queries, topk);
+        let search_option = SearchOption {
+            topk,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let deleted = roaring::RoaringTreemap::new();
+        let recall = |index: &dyn AnnIndex| {
+            truth
+                .iter()
+                .enumerate()
+                .map(|(q, truth)| {
+                    let result = index.search(queries.get(q), &deleted, &search_option);
+                    assert_eq!(result.len(), topk);
+                    tune::recall(&result, truth)
+                })
+                .sum::<f32>()
+                / truth.len() as f32
+        };
+        let (pq_recall, refine_recall) = (recall(&pq), recall(&refine));
+        assert!(
+            refine_recall > pq_recall,
+            "pq recall {}, refine recall {}",
+            pq_recall,
+            refine_recall
+        );
+
+        // all vectors are candidates, the re-ranking is exact
+        refine.set_factor(DATASET_SIZE / topk + 1).unwrap();
+        assert_eq!(recall(&refine), 1.0);
+        refine.set_factor(8).unwrap();
+
+        // the inner index would get topk 0 or an overflowed topk
+        for refine_factor in [0, usize::MAX] {
+            assert!(refine
+                .validate_search(&SearchOption {
+                    refine_factor: Some(refine_factor),
+                    ..search_option
+                })
+                .is_err());
+        }
+        assert!(refine.validate_search(&search_option).is_ok());
+
+        // the candidates of a smaller factor are kept by a larger one
+        let result = tune::tune(
+            &refine,
+            vectors.as_ref(),
+            &queries,
+            &tune::TuneOption {
+                metric_type: MetricType::L2,
+                topk,
+                target_recall: 0.9,
+                candidates: vec![search_option.params],
+                refine_factors: tune::refine_factors(),
+            },
+        )
+        .unwrap();
+        assert_eq!(result.points.len(), tune::refine_factors().len());
+        assert_eq!(result.points[0].refine_factor, Some(1));
+        for pair in result.points.windows(2) {
+            assert!(pair[0].recall <= pair[1].recall);
+        }
+        assert_eq!(result.points[3].recall, refine_recall);
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("refine.index");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        refine
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let mut deserialized = Refine::new(Box::new(IvfPq::new(vectors.clone())), vectors.clone());
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        deserialized
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(deserialized.factor(), 8);
+        assert_eq!(recall(&deserialized), refine_recall);
+    }
+
+    // counts the async reads, and fails them once the limit is reached
+    struct AsyncAccessor {
+        inner: Arc<MemoryVectorAccessor>,
+        reads: AtomicUsize,
+        limit: usize,
+    }
+
+    #[async_trait]
+    impl VectorAccessor for AsyncAccessor {
+        fn dim(&self) -> usize {
+            self.inner.dim()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn get(&self, index: usize) -> &[f32] {
+            self.inner.get(index)
+        }
+
+        async fn get_async(&self, index: usize) -> io::Result<Vec<f32>> {
+            match self.reads.fetch_add(1, Ordering::Relaxed) < self.limit {
+                true => Ok(self.inner.get(index).to_vec()),
+                false => Err(io::Error::other("the read limit is reached")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refine_async() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let accessor = Arc::new(AsyncAccessor {
+            inner: vectors.clone(),
+            reads: AtomicUsize::new(0),
+            limit: 100,
+        });
+        let mut refine = Refine::new(Box::new(IvfPq::new(vectors.clone())), accessor.clone());
+        refine.train(&TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::IvfPq(
+                IvfBuildParams {
+                    nlist: CLUSTER_NUM,
+                    ..Default::default()
+                },
+                PqBuildParams { m: 4, nbits: 5 },
+            ),
+        });
+
+        let option = SearchOption {
+            topk: 10,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM,
+                ..Default::default()
+            }),
+            refine_factor: Some(8),
+        };
+        let deleted = roaring::RoaringTreemap::new();
+        let query = vectors.get(0);
+        let result = refine.search_async(query, &deleted, &option).await.unwrap();
+        assert_eq!(result, refine.search(query, &deleted, &option));
+        assert_eq!(accessor.reads.load(Ordering::Relaxed), 80);
+
+        // the read errors are returned
+        assert!(refine.search_async(query, &deleted, &option).await.is_err());
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    accessor::MemoryVectorAccessor,
+    error::{self, Error},
+    transform::{self, TransformType, VectorTransform},
+    *,
+};
+use log::warn;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u16 = 1;
+
+// creates the inner index over the transformed vectors
+pub type IndexBuilder = Box<dyn Fn(Arc<dyn VectorAccessor>) -> Box<dyn AnnIndex> + Send + Sync>;
+
+// applies the transforms in order to the vectors before indexing them,
+// and to the query vectors before searching the inner index
+pub struct TransformedIndex {
+    vectors: Arc<dyn VectorAccessor>,
+    transforms: Vec<Box<dyn VectorTransform>>,
+    new_index: IndexBuilder,
+    index: Option<Box<dyn AnnIndex>>,
+}
+
+impl TransformedIndex {
+    pub fn try_new(
+        vectors: Arc<dyn VectorAccessor>,
+        transforms: Vec<Box<dyn VectorTransform>>,
+        new_index: IndexBuilder,
+    ) -> error::Result<Self> {
+        let mut dim = vectors.dim();
+        for transform in &transforms {
+            if transform.input_dim() != dim {
+                return Err(Error::InvalidArgument(format!(
+                    "{:?} transform expects {}-d vectors, got {}-d",
+                    transform.transform_type(),
+                    transform.input_dim(),
+                    dim
+                )));
+            }
+            dim = transform.output_dim();
+        }
+
+        Ok(Self {
+            vectors,
+            transforms,
+            new_index,
+            index: None,
+        })
+    }
+
+    pub fn transforms(&self) -> &[Box<dyn VectorTransform>] {
+        &self.transforms
+    }
+
+    pub fn index(&self) -> Option<&dyn AnnIndex> {
+        self.index.as_deref()
+    }
+
+    pub fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        let mut vector = vector.to_vec();
+        for transform in &self.transforms {
+            vector = transform.apply(&vector);
+        }
+        vector
+    }
+
+    fn build_index(&mut self) {
+        let dim = self
+            .transforms
+            .last()
+            .map_or(self.vectors.dim(), |t| t.output_dim());
+        let mut data = Vec::with_capacity(self.vectors.len() * dim);
+        for i in 0..self.vectors.len() {
+            data.extend(self.apply(self.vectors.get(i)));
+        }
+
+        let vectors = Arc::new(MemoryVectorAccessor::new(dim, data));
+        self.index = Some((self.new_index)(vectors));
+    }
+}
+
+#[async_trait]
+impl AnnIndex for TransformedIndex {
+    fn train(&mut self, option: &TrainOption) {
+        self.train_with_context(option, &TrainContext::default())
+            .unwrap_or_else(|err| panic!("failed to train transformed index: {}", err));
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        // each transform is trained on the sample transformed by the previous ones
+        let mut sample = self.vectors.clone();
+        let total = self.transforms.len();
+        for (i, transform) in self.transforms.iter_mut().enumerate() {
+            transform.train(sample.clone())?;
+
+            let mut data = Vec::new();
+            for i in 0..transform::train_size(sample.as_ref()) {
+                data.extend(transform.apply(sample.get(i)));
+            }
+            sample = Arc::new(MemoryVectorAccessor::new(transform.output_dim(), data));
+            ctx.report(TrainProgress {
+                stage: TrainStage::Transform,
+                step: i + 1,
+                total,
+                inertia: None,
+            })?;
+        }
+
+        self.build_index();
+        match self.index.as_mut() {
+            Some(index) => index.train_with_context(option, ctx),
+            None => Ok(()),
+        }
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        match &self.index {
+            Some(index) => index.search(&self.apply(query_vector), deleted, option),
+            None => Vec::new(),
+        }
+    }
+
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        match &self.index {
+            Some(index) => {
+                index
+                    .search_async(&self.apply(query_vector), deleted, option)
+                    .await
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn build_params(&self) -> Option<params::BuildParams> {
+        self.index.as_ref().and_then(|index| index.build_params())
+    }
+
+    fn validate_search(&self, option: &SearchOption) -> error::Result<()> {
+        match &self.index {
+            Some(index) => index.validate_search(option),
+            None => Err(Error::InvalidArgument("index is not trained".to_string())),
+        }
+    }
+
+    async fn serialize(
+        &self,
+        mut writer: Pin<Box<dyn AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or_else(|| io::Error::other("index is not trained"))?;
+
+        writer.write_u16_le(VERSION).await?;
+        writer.write_u32_le(self.transforms.len() as u32).await?;
+        for transform in &self.transforms {
+            writer.write_u8(transform.transform_type() as u8).await?;
+            transform.serialize(&mut writer).await?;
+        }
+
+        index.serialize(writer).await
+    }
+
+    async fn deserialize(
+        &mut self,
+        mut reader: Pin<Box<dyn AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        let version = reader.read_u16_le().await?;
+        if version > VERSION {
+            warn!(
+                "read newer version {} transformed index file, current version is {}",
+                version, VERSION
+            );
+        }
+
+        let len = reader.read_u32_le().await? as usize;
+        let mut transforms = Vec::with_capacity(len);
+        let mut dim = self.vectors.dim();
+        for _ in 0..len {
+            let typ = TransformType::try_from(reader.read_u8().await?)?;
+            let mut transform = transform::new(typ);
+            transform.deserialize(&mut reader).await?;
+            if transform.input_dim() != dim {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} transform expects {}-d vectors, got {}-d",
+                        typ,
+                        transform.input_dim(),
+                        dim
+                    ),
+                ));
+            }
+            dim = transform.output_dim();
+            transforms.push(transform);
+        }
+        self.transforms = transforms;
+
+        self.build_index();
+        match self.index.as_mut() {
+            Some(index) => index.deserialize(reader).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::ivf::Ivf;
+    use crate::index::transformed::*;
+    use crate::params::*;
+    use crate::test_util::gen_vectors;
+    use crate::transform::{opq::Opq, pca::Pca, rotation::RandomRotation};
+    use tokio::io::{BufReader, BufWriter};
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    fn new_index(vectors: Arc<dyn VectorAccessor>) -> TransformedIndex {
+        let transforms: Vec<Box<dyn VectorTransform>> = vec![
+            Box::new(RandomRotation::new(DIM)),
+            Box::new(Pca::new(DIM, DIM / 2).unwrap()),
+        ];
+        TransformedIndex::try_new(vectors, transforms, Box::new(|v| Box::new(Ivf::new(v)))).unwrap()
+    }
+
+    fn check_search(index: &TransformedIndex, vectors: &dyn VectorAccessor) {
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let bitmap = roaring::RoaringTreemap::new();
+        for i in 0..vectors.len() {
+            let query = vectors.get(i);
+            let result = index.search(query, &bitmap, &option);
+            assert_eq!(result.len(), option.topk);
+            for id in &result {
+                assert_eq!(metric::l2_distance(query, vectors.get(*id)), 0.0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transformed_index() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let transforms: Vec<Box<dyn VectorTransform>> =
+            vec![Box::new(Pca::new(DIM / 2, 2).unwrap())];
+        assert!(TransformedIndex::try_new(
+            vectors.clone(),
+            transforms,
+            Box::new(|v| Box::new(Ivf::new(v)))
+        )
+        .is_err());
+
+        // too few vectors to train the opq rotation
+        let transforms: Vec<Box<dyn VectorTransform>> = vec![Box::new(Opq::new(DIM, 8).unwrap())];
+        let mut index = TransformedIndex::try_new(
+            Arc::new(gen_vectors(16, DIM, 4)),
+            transforms,
+            Box::new(|v| Box::new(Ivf::new(v))),
+        )
+        .unwrap();
+        let option = TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: 4,
+                ..Default::default()
+            }),
+        };
+        assert!(matches!(
+            index.train_with_context(&option, &TrainContext::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(index.index().is_none());
+
+        let mut index = new_index(vectors.clone());
+        index.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+        assert!(index.index().is_some());
+        check_search(&index, vectors.as_ref());
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("transformed.index");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        index
+            .serialize(Box::pin(BufWriter::new(file)))
+            .await
+            .unwrap();
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let mut deserialized = new_index(vectors.clone());
+        deserialized
+            .deserialize(Box::pin(BufReader::new(file)))
+            .await
+            .unwrap();
+        assert_eq!(deserialized.transforms().len(), 2);
+        check_search(&deserialized, vectors.as_ref());
+    }
+}
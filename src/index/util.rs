@@ -13,10 +13,18 @@
 // limitations under the License.
 
 use super::cluster::Cluster;
-use crate::{metric::MetricType, TrainOption, VectorAccessor};
-use std::{cmp, sync::Arc};
+use crate::{
+    error::{Error, Result},
+    metric::{self, MetricType},
+    params::{Clustering, IvfBuildParams, LearningSchedule, MiniBatchParams},
+    TrainContext, TrainProgress, TrainStage, VectorAccessor,
+};
+use std::{cmp, fs, io, sync::Arc};
 
-const MAX_CLUSTER_SIZE: usize = 256;
+pub(crate) const MAX_CLUSTER_SIZE: usize = 256;
+
+// a list is compacted once this ratio of its elements are deleted
+pub const DEFAULT_COMPACT_THRESHOLD: f32 = 0.2;
 
 pub fn rand_centroids(n: usize, vectors: Arc<dyn crate::VectorAccessor>) -> Vec<Cluster> {
     let vec_num = vectors.len();
@@ -26,32 +34,110 @@ pub fn rand_centroids(n: usize, vectors: Arc<dyn crate::VectorAccessor>) -> Vec<
         .collect()
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusteringReport {
+    // the iterations taken, batches for mini-batch k-means
+    pub iterations: usize,
+    // the sum of the distances between the vectors and their centroids
+    pub inertia: f64,
+}
+
 pub fn train_clusters(
     metric_type: MetricType,
     vectors: Arc<dyn VectorAccessor>,
-    option: &TrainOption,
+    params: &IvfBuildParams,
 ) -> Vec<Cluster> {
-    let mut clusters = rand_centroids(option.nlist, vectors.clone());
-
-    let iter_num = option.iteration_num.unwrap_or(25);
-    let train_size = cmp::min(option.nlist * MAX_CLUSTER_SIZE, vectors.len());
-    for _ in 0..iter_num {
-        let mut new_clusters: Vec<_> = (0..option.nlist).map(|_| Cluster::new()).collect();
-
-        for id in 0..train_size {
-            let vec = vectors.get(id);
-            let mut target = 0;
-            let mut min_distance = metric_type.distance(&clusters[0].centroid, vec);
-
-            for i in 1..clusters.len() {
-                let distance = metric_type.distance(&clusters[i].centroid, vec);
-                if distance < min_distance {
-                    target = i;
-                    min_distance = distance;
-                }
-            }
+    train_clusters_with_report(metric_type, vectors, params, &TrainContext::default())
+        .unwrap_or_else(|err| panic!("failed to train clusters: {}", err))
+        .0
+}
+
+// reports the progress per iteration to the context
+pub fn train_clusters_with_report(
+    metric_type: MetricType,
+    vectors: Arc<dyn VectorAccessor>,
+    params: &IvfBuildParams,
+    ctx: &TrainContext,
+) -> Result<(Vec<Cluster>, ClusteringReport)> {
+    let (clusters, iterations) = match params.clustering {
+        Clustering::KMeans => (
+            train_kmeans(metric_type, vectors.clone(), params, None, ctx)?,
+            params.iteration_num,
+        ),
+        Clustering::Balanced {
+            min_ratio,
+            max_ratio,
+        } => (
+            train_kmeans(
+                metric_type,
+                vectors.clone(),
+                params,
+                Some((min_ratio, max_ratio)),
+                ctx,
+            )?,
+            params.iteration_num,
+        ),
+        Clustering::Hierarchical { super_nlist } => {
+            train_hierarchical(metric_type, vectors.clone(), params, super_nlist, ctx)?
+        }
+        Clustering::MiniBatch(mini_batch) => {
+            train_mini_batch(metric_type, vectors.clone(), params.nlist, &mini_batch, ctx)?
+        }
+    };
+
+    let inertia = inertia(metric_type, &clusters, vectors.as_ref());
+    Ok((
+        clusters,
+        ClusteringReport {
+            iterations,
+            inertia,
+        },
+    ))
+}
+
+fn inertia(metric_type: MetricType, clusters: &[Cluster], vectors: &dyn VectorAccessor) -> f64 {
+    clusters
+        .iter()
+        .map(|c| {
+            c.iter()
+                .map(|id| metric_type.distance(&c.centroid, vectors.get(id)) as f64)
+                .sum::<f64>()
+        })
+        .sum()
+}
 
-            new_clusters[target].add(id);
+// the balance bounds the list sizes by capacity-constrained assignment
+fn train_kmeans(
+    metric_type: MetricType,
+    vectors: Arc<dyn VectorAccessor>,
+    params: &IvfBuildParams,
+    balance: Option<(f32, f32)>,
+    ctx: &TrainContext,
+) -> Result<Vec<Cluster>> {
+    let mut clusters = rand_centroids(params.nlist, vectors.clone());
+
+    let train_size = cmp::min(params.nlist * MAX_CLUSTER_SIZE, vectors.len());
+    let train_ids: Vec<_> = (0..train_size).collect();
+    for iteration in 0..params.iteration_num {
+        let mut new_clusters: Vec<_> = clusters
+            .iter()
+            .map(|c| Cluster::with_centroid(&c.centroid))
+            .collect();
+        match balance {
+            None => assign(
+                metric_type,
+                &mut new_clusters,
+                vectors.as_ref(),
+                train_ids.iter().copied(),
+            ),
+            Some((min_ratio, max_ratio)) => balanced_assign(
+                metric_type,
+                &mut new_clusters,
+                vectors.as_ref(),
+                &train_ids,
+                min_ratio,
+                max_ratio,
+            ),
         }
 
         // split larger cluster to reach the expected number of clusters
@@ -88,23 +174,568 @@ pub fn train_clusters(
         }
 
         clusters = new_clusters;
+        ctx.report(TrainProgress {
+            stage: TrainStage::Clustering,
+            step: iteration + 1,
+            total: params.iteration_num,
+            inertia: Some(inertia(metric_type, &clusters, vectors.as_ref())),
+        })?;
+    }
+
+    match balance {
+        // assign the vectors not in train set
+        None => assign(
+            metric_type,
+            &mut clusters,
+            vectors.as_ref(),
+            train_size..vectors.len(),
+        ),
+        // the bounds hold for all vectors only if they are assigned together
+        Some((min_ratio, max_ratio)) => {
+            for cluster in clusters.iter_mut() {
+                cluster.elements.clear();
+            }
+            let ids: Vec<_> = (0..vectors.len()).collect();
+            balanced_assign(
+                metric_type,
+                &mut clusters,
+                vectors.as_ref(),
+                &ids,
+                min_ratio,
+                max_ratio,
+            );
+        }
+    }
+    Ok(clusters)
+}
+
+// the vectors of a super-cluster, addressed by their offsets
+struct SubsetVectorAccessor {
+    vectors: Arc<dyn VectorAccessor>,
+    ids: Vec<usize>,
+}
+
+impl VectorAccessor for SubsetVectorAccessor {
+    fn dim(&self) -> usize {
+        self.vectors.dim()
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn get(&self, index: usize) -> &[f32] {
+        self.vectors.get(self.ids[index])
+    }
+}
+
+// each vector is assigned to the nearest sub-cluster of its super-cluster,
+// which costs O(n * (super_nlist + nlist / super_nlist)) instead of O(n * nlist),
+// the progress is reported once per clustered super-cluster
+fn train_hierarchical(
+    metric_type: MetricType,
+    vectors: Arc<dyn VectorAccessor>,
+    params: &IvfBuildParams,
+    super_nlist: usize,
+    ctx: &TrainContext,
+) -> Result<(Vec<Cluster>, usize)> {
+    // each sub-cluster needs a vector of its own
+    if params.nlist > vectors.len() {
+        return Err(Error::InvalidArgument(format!(
+            "nlist {} is larger than the number of vectors {}",
+            params.nlist,
+            vectors.len()
+        )));
+    }
+    let level_params = |nlist| IvfBuildParams {
+        nlist,
+        clustering: Clustering::KMeans,
+        ..*params
+    };
+    // the iterations of the levels would restart the steps
+    let nested = ctx.without_callback();
+    let (super_clusters, super_report) = train_clusters_with_report(
+        metric_type,
+        vectors.clone(),
+        &level_params(super_nlist),
+        &nested,
+    )?;
+    let sizes: Vec<_> = super_clusters.iter().map(|c| c.len()).collect();
+    let nlists = allocate_nlist(&sizes, params.nlist);
+    let total = nlists.iter().filter(|n| **n > 0).count();
+
+    let mut clusters = Vec::with_capacity(params.nlist);
+    let mut sub_iterations = 0;
+    let (mut step, mut sub_inertia) = (0, 0f64);
+    for (super_cluster, nlist) in super_clusters.into_iter().zip(nlists) {
+        if nlist == 0 {
+            continue;
+        }
+        let subset = Arc::new(SubsetVectorAccessor {
+            vectors: vectors.clone(),
+            ids: super_cluster.iter().collect(),
+        });
+        let (sub_clusters, report) =
+            train_clusters_with_report(metric_type, subset.clone(), &level_params(nlist), &nested)?;
+        sub_iterations = cmp::max(sub_iterations, report.iterations);
+        sub_inertia += report.inertia;
+        step += 1;
+        ctx.report(TrainProgress {
+            stage: TrainStage::Clustering,
+            step,
+            total,
+            // the inertia of all lists is known after the last super-cluster
+            inertia: (step == total).then_some(sub_inertia),
+        })?;
+        for cluster in sub_clusters {
+            let mut mapped = Cluster::with_centroid(&cluster.centroid);
+            mapped.elements = cluster.iter().map(|i| subset.ids[i] as u64).collect();
+            clusters.push(mapped);
+        }
     }
+    Ok((clusters, super_report.iterations + sub_iterations))
+}
+
+// the sculley's web-scale k-means, each batch moves the centroids
+// toward the vectors assigned to them
+fn train_mini_batch(
+    metric_type: MetricType,
+    vectors: Arc<dyn VectorAccessor>,
+    nlist: usize,
+    params: &MiniBatchParams,
+    ctx: &TrainContext,
+) -> Result<(Vec<Cluster>, usize)> {
+    // the smoothing factor of the batch inertia
+    const ALPHA: f64 = 0.1;
+
+    let mut clusters = rand_centroids(nlist, vectors.clone());
+    let mut counts = vec![0usize; nlist];
+    let mut smoothed_inertia = None;
+    let mut best_inertia = f64::MAX;
+    let mut no_improvement = 0;
+    let mut iterations = 0;
+    let mut batch = Vec::with_capacity(params.batch_size);
+    while iterations < params.max_iteration_num {
+        iterations += 1;
+
+        let mut batch_inertia = 0f64;
+        batch.clear();
+        for _ in 0..params.batch_size {
+            let id = rand::random::<usize>() % vectors.len();
+            let target = nearest_cluster(metric_type, &clusters, vectors.get(id));
+            batch_inertia +=
+                metric_type.distance(&clusters[target].centroid, vectors.get(id)) as f64;
+            batch.push((id, target));
+        }
+
+        let mut shift = 0f64;
+        let old_centroids: Vec<_> = clusters.iter().map(|c| c.centroid.clone()).collect();
+        for (id, target) in batch.iter() {
+            counts[*target] += 1;
+            let rate = match params.schedule {
+                LearningSchedule::InverseCount => 1.0 / counts[*target] as f32,
+                LearningSchedule::Constant(rate) => rate,
+            };
+            for (c, v) in clusters[*target].centroid.iter_mut().zip(vectors.get(*id)) {
+                *c += rate * (v - *c);
+            }
+        }
+        for (cluster, old) in clusters.iter().zip(old_centroids.iter()) {
+            shift += metric::l2_distance(&cluster.centroid, old) as f64;
+        }
 
-    // assign the vectors not in train set
-    for id in train_size..vectors.len() {
-        let vec = vectors.get(id);
-        let mut target = 0;
-        let mut min_distance = metric_type.distance(&clusters[0].centroid, vec);
+        let batch_inertia = batch_inertia / params.batch_size as f64;
+        let smoothed = match smoothed_inertia {
+            None => batch_inertia,
+            Some(smoothed) => smoothed * (1.0 - ALPHA) + batch_inertia * ALPHA,
+        };
+        smoothed_inertia = Some(smoothed);
+        // estimated from the batches
+        ctx.report(TrainProgress {
+            stage: TrainStage::Clustering,
+            step: iterations,
+            total: params.max_iteration_num,
+            inertia: Some(smoothed * vectors.len() as f64),
+        })?;
 
-        for i in 1..clusters.len() {
-            let distance = metric_type.distance(&clusters[i].centroid, vec);
-            if distance < min_distance {
-                target = i;
-                min_distance = distance;
+        if shift / nlist as f64 <= params.tolerance as f64 && params.tolerance > 0.0 {
+            break;
+        }
+        if smoothed < best_inertia {
+            best_inertia = smoothed;
+            no_improvement = 0;
+        } else {
+            no_improvement += 1;
+            if params.max_no_improvement > 0 && no_improvement >= params.max_no_improvement {
+                break;
             }
         }
+    }
+
+    assign(
+        metric_type,
+        &mut clusters,
+        vectors.as_ref(),
+        0..vectors.len(),
+    );
+    Ok((clusters, iterations))
+}
+
+// splits the nlist among the super-clusters in proportion to their sizes,
+// each non-empty one gets at least one and at most its size
+fn allocate_nlist(sizes: &[usize], nlist: usize) -> Vec<usize> {
+    let total = sizes.iter().sum::<usize>();
+    let mut nlists: Vec<_> = sizes
+        .iter()
+        .map(|size| match *size {
+            0 => 0,
+            size => (size * nlist / total).clamp(1, size),
+        })
+        .collect();
+
+    let mut allocated = nlists.iter().sum::<usize>();
+    // gives to the ones with the most vectors per list
+    while allocated < nlist {
+        let i = (0..sizes.len())
+            .filter(|i| nlists[*i] < sizes[*i])
+            .max_by(|a, b| {
+                let a = sizes[*a] as f32 / nlists[*a] as f32;
+                let b = sizes[*b] as f32 / nlists[*b] as f32;
+                a.total_cmp(&b)
+            })
+            .expect("nlist is checked against the number of vectors");
+        nlists[i] += 1;
+        allocated += 1;
+    }
+    // takes from the ones with the fewest vectors per list
+    while allocated > nlist {
+        let i = (0..sizes.len())
+            .filter(|i| nlists[*i] > 1)
+            .min_by(|a, b| {
+                let a = sizes[*a] as f32 / nlists[*a] as f32;
+                let b = sizes[*b] as f32 / nlists[*b] as f32;
+                a.total_cmp(&b)
+            })
+            .unwrap();
+        nlists[i] -= 1;
+        allocated -= 1;
+    }
+    nlists
+}
 
+pub fn nearest_cluster(metric_type: MetricType, clusters: &[Cluster], vector: &[f32]) -> usize {
+    let mut target = 0;
+    let mut min_distance = metric_type.distance(&clusters[0].centroid, vector);
+    for (i, cluster) in clusters.iter().enumerate().skip(1) {
+        let distance = metric_type.distance(&cluster.centroid, vector);
+        if distance < min_distance {
+            target = i;
+            min_distance = distance;
+        }
+    }
+    target
+}
+
+// adds the vectors to their nearest clusters
+pub fn assign(
+    metric_type: MetricType,
+    clusters: &mut [Cluster],
+    vectors: &dyn VectorAccessor,
+    ids: impl IntoIterator<Item = usize>,
+) {
+    for id in ids {
+        let target = nearest_cluster(metric_type, clusters, vectors.get(id));
         clusters[target].add(id);
     }
+}
+
+// adds the vectors to their nearest clusters which are not full,
+// then fills the clusters smaller than the min size with the nearest vectors
+// of the clusters larger than it, the sizes are relative to the average size
+pub fn balanced_assign(
+    metric_type: MetricType,
+    clusters: &mut [Cluster],
+    vectors: &dyn VectorAccessor,
+    ids: &[usize],
+    min_ratio: f32,
+    max_ratio: f32,
+) {
+    let avg = ids.len() as f32 / clusters.len() as f32;
+    let max_size = cmp::max((avg * max_ratio).ceil() as usize, avg.ceil() as usize);
+    let min_size = cmp::min((avg * min_ratio).floor() as usize, avg.floor() as usize);
+
+    // the vectors close to a centroid choose first
+    let mut order: Vec<_> = (0..ids.len())
+        .map(|i| {
+            let vector = vectors.get(ids[i]);
+            let target = nearest_cluster(metric_type, clusters, vector);
+            (metric_type.distance(&clusters[target].centroid, vector), i)
+        })
+        .collect();
+    order.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut targets = vec![0; ids.len()];
+    let mut sizes = vec![0; clusters.len()];
+    for (_, i) in order {
+        let vector = vectors.get(ids[i]);
+        let target = (0..clusters.len())
+            .filter(|c| sizes[*c] < max_size)
+            .min_by(|a, b| {
+                let a = metric_type.distance(&clusters[*a].centroid, vector);
+                let b = metric_type.distance(&clusters[*b].centroid, vector);
+                a.total_cmp(&b)
+            })
+            .unwrap();
+        targets[i] = target;
+        sizes[target] += 1;
+    }
+
+    for c in 0..clusters.len() {
+        if sizes[c] >= min_size {
+            continue;
+        }
+
+        let mut candidates: Vec<_> = (0..ids.len())
+            .filter(|i| sizes[targets[*i]] > min_size)
+            .map(|i| {
+                let distance = metric_type.distance(&clusters[c].centroid, vectors.get(ids[i]));
+                (distance, i)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (_, i) in candidates {
+            if sizes[c] >= min_size {
+                break;
+            }
+            if sizes[targets[i]] > min_size {
+                sizes[targets[i]] -= 1;
+                targets[i] = c;
+                sizes[c] += 1;
+            }
+        }
+    }
+
+    for (i, target) in targets.into_iter().enumerate() {
+        clusters[target].add(ids[i]);
+    }
+}
+
+// the threshold is a deleted ratio, 0 compacts on every delete, 1 never
+pub fn validate_compact_threshold(threshold: f32) -> Result<()> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(Error::InvalidArgument(format!(
+            "compact threshold must be in [0, 1], got {}",
+            threshold
+        )));
+    }
+    Ok(())
+}
+
+// rewrites all lists with deleted elements, returns the number of removed ids
+pub fn compact(clusters: &mut [Cluster]) -> usize {
+    clusters.iter_mut().map(|c| c.compact()).sum()
+}
+
+pub fn deleted_ratios(clusters: &[Cluster]) -> Vec<f32> {
+    clusters.iter().map(|c| c.deleted_ratio()).collect()
+}
+
+// the numbers of the live elements
+pub fn cluster_sizes(clusters: &[Cluster]) -> Vec<usize> {
     clusters
+        .iter()
+        .map(|c| c.len() - c.deleted.len() as usize)
+        .collect()
+}
+
+pub fn cluster_elements(cluster: &Cluster) -> impl Iterator<Item = usize> + '_ {
+    cluster.iter().filter(|id| !cluster.is_deleted(*id))
+}
+
+// marks the id deleted in its cluster, and compacts the cluster
+// if the deleted ratio exceeds the threshold
+pub fn delete(clusters: &mut [Cluster], id: usize, compact_threshold: f32) -> bool {
+    match clusters
+        .iter_mut()
+        .find_map(|c| if c.delete(id) { Some(c) } else { None })
+    {
+        Some(cluster) => {
+            if cluster.deleted_ratio() > compact_threshold {
+                cluster.compact();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+// fills the buffer from the offset of the file, without moving its cursor
+#[cfg(unix)]
+pub(crate) fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::util::*;
+    use crate::test_util::gen_floats;
+    use roaring::RoaringTreemap;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[test]
+    fn test_balanced_clusters() {
+        // half of the vectors are crowded around a single point
+        let mut data = gen_floats(DATASET_SIZE * DIM);
+        for (i, v) in data.iter_mut().enumerate().take(DATASET_SIZE / 2 * DIM) {
+            *v = *v * 0.01 + (i % DIM) as f32;
+        }
+        let vectors = Arc::new(MemoryVectorAccessor::new(DIM, data));
+
+        let (min_ratio, max_ratio) = (0.5, 1.5);
+        let params = IvfBuildParams {
+            nlist: CLUSTER_NUM,
+            iteration_num: 10,
+            clustering: Clustering::Balanced {
+                min_ratio,
+                max_ratio,
+            },
+            ..Default::default()
+        };
+        let clusters = train_clusters(MetricType::L2, vectors.clone(), &params);
+        assert_eq!(clusters.len(), CLUSTER_NUM);
+
+        let avg = (DATASET_SIZE / CLUSTER_NUM) as f32;
+        let mut ids = RoaringTreemap::new();
+        for cluster in &clusters {
+            assert!(cluster.len() as f32 <= avg * max_ratio, "{}", cluster.len());
+            assert!(cluster.len() as f32 >= avg * min_ratio, "{}", cluster.len());
+            ids |= &cluster.elements;
+        }
+        assert_eq!(ids.len() as usize, DATASET_SIZE);
+        assert_eq!(
+            clusters.iter().map(|c| c.len()).sum::<usize>(),
+            DATASET_SIZE
+        );
+    }
+
+    #[test]
+    fn test_hierarchical_clusters() {
+        assert_eq!(allocate_nlist(&[100, 0, 50, 2], 8), vec![5, 0, 2, 1]);
+        assert_eq!(allocate_nlist(&[1, 1, 98], 4), vec![1, 1, 2]);
+
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let params = IvfBuildParams {
+            nlist: CLUSTER_NUM,
+            iteration_num: 10,
+            clustering: Clustering::Hierarchical { super_nlist: 4 },
+            ..Default::default()
+        };
+        let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let ctx = {
+            let progress = progress.clone();
+            TrainContext::new().with_callback(Arc::new(move |p: &TrainProgress| {
+                progress.lock().unwrap().push(*p)
+            }))
+        };
+        let (clusters, report) =
+            train_clusters_with_report(MetricType::L2, vectors.clone(), &params, &ctx).unwrap();
+        assert_eq!(clusters.len(), CLUSTER_NUM);
+        // one step per super-cluster
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.len(), progress[0].total);
+        assert!(progress.len() <= 4);
+        for (i, p) in progress.iter().enumerate() {
+            assert_eq!(p.step, i + 1);
+        }
+        let last = progress.last().unwrap().inertia.unwrap();
+        assert!((last - report.inertia).abs() <= 1e-3 * report.inertia);
+
+        let mut ids = RoaringTreemap::new();
+        for cluster in &clusters {
+            assert!(cluster.len() > 0);
+            for id in cluster.iter() {
+                assert!(ids.insert(id as u64));
+            }
+        }
+        assert_eq!(ids.len() as usize, DATASET_SIZE);
+
+        let subset = Arc::new(MemoryVectorAccessor::new(DIM, gen_floats(16 * DIM)));
+        assert!(matches!(
+            train_clusters_with_report(MetricType::L2, subset, &params, &TrainContext::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_mini_batch_clusters() {
+        let ctx = TrainContext::default();
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let kmeans = IvfBuildParams {
+            nlist: CLUSTER_NUM,
+            iteration_num: 10,
+            ..Default::default()
+        };
+        let (_, kmeans_report) =
+            train_clusters_with_report(MetricType::L2, vectors.clone(), &kmeans, &ctx).unwrap();
+        assert_eq!(kmeans_report.iterations, 10);
+
+        let mini_batch = MiniBatchParams {
+            batch_size: 256,
+            max_iteration_num: 200,
+            max_no_improvement: 5,
+            ..Default::default()
+        };
+        let params = IvfBuildParams {
+            clustering: Clustering::MiniBatch(mini_batch),
+            ..kmeans
+        };
+        let (clusters, report) =
+            train_clusters_with_report(MetricType::L2, vectors.clone(), &params, &ctx).unwrap();
+        assert_eq!(clusters.len(), CLUSTER_NUM);
+        assert_eq!(
+            clusters.iter().map(|c| c.len()).sum::<usize>(),
+            DATASET_SIZE
+        );
+        // stops before the max iterations as the inertia stops improving
+        assert!(report.iterations < mini_batch.max_iteration_num);
+        assert!(report.inertia < kmeans_report.inertia * 1.2);
+
+        // the large tolerance stops it after the first batch
+        let params = IvfBuildParams {
+            clustering: Clustering::MiniBatch(MiniBatchParams {
+                tolerance: f32::MAX,
+                ..mini_batch
+            }),
+            ..kmeans
+        };
+        let (_, report) =
+            train_clusters_with_report(MetricType::L2, vectors, &params, &ctx).unwrap();
+        assert_eq!(report.iterations, 1);
+    }
 }
@@ -0,0 +1,316 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    error::Result, metric::MetricType, params::VamanaBuildParams, TrainContext, TrainProgress,
+    TrainStage, VectorAccessor,
+};
+use rand::seq::SliceRandom;
+use std::{cmp, collections::HashSet};
+
+// the nodes with their distances to the query
+pub type Candidates = Vec<(usize, f32)>;
+
+// the graph of the vamana index, each node links to at most max_degree
+// neighbors, and the searches start from the medoid
+pub struct VamanaGraph {
+    neighbors: Vec<Vec<u32>>,
+    medoid: usize,
+}
+
+impl VamanaGraph {
+    // builds the graph in two passes, the first pass prunes without relaxing
+    // which links the near nodes, the second one adds the long edges by alpha.
+    // the nodes are inserted in batches doubling up to batch_size, the nodes
+    // of a batch search the graph built by the previous batches
+    pub fn build(
+        metric_type: MetricType,
+        vectors: &dyn VectorAccessor,
+        params: &VamanaBuildParams,
+        ctx: &TrainContext,
+    ) -> Result<Self> {
+        let n = vectors.len();
+        let mut graph = Self {
+            neighbors: vec![Vec::new(); n],
+            medoid: medoid(metric_type, vectors),
+        };
+        if n <= 1 {
+            return Ok(graph);
+        }
+
+        let mut step = 0;
+        for alpha in [1.0, params.alpha] {
+            let mut order: Vec<_> = (0..n).collect();
+            order.shuffle(&mut rand::thread_rng());
+
+            let (mut start, mut size) = (0, 1);
+            while start < n {
+                let end = cmp::min(start + size, n);
+                let batch = &order[start..end];
+                let pruned: Vec<_> = batch
+                    .iter()
+                    .map(|&node| {
+                        let vector = vectors.get(node);
+                        let (_, mut candidates) =
+                            graph.search(metric_type, vectors, vector, params.search_list_size);
+                        candidates.extend(graph.neighbors[node].iter().map(|&j| {
+                            let j = j as usize;
+                            (j, metric_type.distance(vector, vectors.get(j)))
+                        }));
+                        prune(
+                            metric_type,
+                            vectors,
+                            node,
+                            candidates,
+                            alpha,
+                            params.max_degree,
+                        )
+                    })
+                    .collect();
+
+                for (&node, neighbors) in batch.iter().zip(pruned) {
+                    graph.neighbors[node] = neighbors;
+                }
+                for &node in batch {
+                    for j in graph.neighbors[node].clone() {
+                        graph.link(metric_type, vectors, j as usize, node, alpha, params);
+                    }
+                }
+
+                step += end - start;
+                ctx.report(TrainProgress {
+                    stage: TrainStage::Graph,
+                    step,
+                    total: n * 2,
+                    inertia: None,
+                })?;
+                start = end;
+                size = cmp::min(size * 2, params.batch_size);
+            }
+        }
+
+        Ok(graph)
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    pub fn medoid(&self) -> usize {
+        self.medoid
+    }
+
+    pub fn neighbors(&self, node: usize) -> &[u32] {
+        &self.neighbors[node]
+    }
+
+    // the greedy search from the medoid, keeps the list_size nearest candidates,
+    // returns them nearest first, and all visited nodes with their distances
+    pub fn search(
+        &self,
+        metric_type: MetricType,
+        vectors: &dyn VectorAccessor,
+        query: &[f32],
+        list_size: usize,
+    ) -> (Candidates, Candidates) {
+        if self.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+
+        let distance = |i: usize| metric_type.distance(vectors.get(i), query);
+        let mut seen = HashSet::from([self.medoid]);
+        // sorted by the distance, with whether it's visited
+        let mut list = vec![(self.medoid, distance(self.medoid), false)];
+        let mut visited = Vec::new();
+        while let Some(pos) = list.iter().position(|c| !c.2) {
+            list[pos].2 = true;
+            let (node, d, _) = list[pos];
+            visited.push((node, d));
+
+            for &neighbor in &self.neighbors[node] {
+                let neighbor = neighbor as usize;
+                if !seen.insert(neighbor) {
+                    continue;
+                }
+                let d = distance(neighbor);
+                if list.len() >= list_size && d >= list.last().unwrap().1 {
+                    continue;
+                }
+                let at = list.partition_point(|c| c.1 <= d);
+                list.insert(at, (neighbor, d, false));
+                list.truncate(list_size);
+            }
+        }
+
+        let list = list.into_iter().map(|(i, d, _)| (i, d)).collect();
+        (list, visited)
+    }
+
+    // adds the edge from the node to the neighbor, prunes the neighbors if overflow
+    fn link(
+        &mut self,
+        metric_type: MetricType,
+        vectors: &dyn VectorAccessor,
+        node: usize,
+        neighbor: usize,
+        alpha: f32,
+        params: &VamanaBuildParams,
+    ) {
+        if self.neighbors[node].contains(&(neighbor as u32)) {
+            return;
+        }
+        self.neighbors[node].push(neighbor as u32);
+        if self.neighbors[node].len() > params.max_degree {
+            let vector = vectors.get(node);
+            let candidates = self.neighbors[node]
+                .iter()
+                .map(|&j| {
+                    let j = j as usize;
+                    (j, metric_type.distance(vector, vectors.get(j)))
+                })
+                .collect();
+            self.neighbors[node] = prune(
+                metric_type,
+                vectors,
+                node,
+                candidates,
+                alpha,
+                params.max_degree,
+            );
+        }
+    }
+}
+
+// selects the nearest candidate, and drops the candidates alpha times
+// nearer to it than to the node, until max_degree ones are selected
+fn prune(
+    metric_type: MetricType,
+    vectors: &dyn VectorAccessor,
+    node: usize,
+    mut candidates: Candidates,
+    alpha: f32,
+    max_degree: usize,
+) -> Vec<u32> {
+    candidates.retain(|c| c.0 != node);
+    candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    candidates.dedup_by_key(|c| c.0);
+
+    let mut selected = Vec::with_capacity(max_degree);
+    let mut pruned = vec![false; candidates.len()];
+    for i in 0..candidates.len() {
+        if selected.len() == max_degree {
+            break;
+        }
+        if pruned[i] {
+            continue;
+        }
+
+        let nearest = vectors.get(candidates[i].0);
+        selected.push(candidates[i].0 as u32);
+        // the distances are squared, and so is alpha
+        for j in i + 1..candidates.len() {
+            if !pruned[j]
+                && alpha * alpha * metric_type.distance(nearest, vectors.get(candidates[j].0))
+                    <= candidates[j].1
+            {
+                pruned[j] = true;
+            }
+        }
+    }
+    selected
+}
+
+// the node nearest to the mean of all vectors
+fn medoid(metric_type: MetricType, vectors: &dyn VectorAccessor) -> usize {
+    if vectors.len() == 0 {
+        return 0;
+    }
+
+    let mut mean = vec![0f64; vectors.dim()];
+    for i in 0..vectors.len() {
+        for (m, v) in mean.iter_mut().zip(vectors.get(i)) {
+            *m += *v as f64;
+        }
+    }
+    let mean: Vec<_> = mean
+        .into_iter()
+        .map(|m| (m / vectors.len() as f64) as f32)
+        .collect();
+
+    (0..vectors.len())
+        .min_by(|a, b| {
+            let a = metric_type.distance(vectors.get(*a), &mean);
+            let b = metric_type.distance(vectors.get(*b), &mean);
+            a.total_cmp(&b)
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::error::Error;
+    use crate::index::vamana::*;
+    use crate::test_util::gen_floats;
+    use crate::tune;
+
+    const DIM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[test]
+    fn test_vamana_graph() {
+        let vectors = MemoryVectorAccessor::new(DIM, gen_floats(DATASET_SIZE * DIM));
+        let params = VamanaBuildParams {
+            max_degree: 16,
+            search_list_size: 32,
+            alpha: 1.2,
+            batch_size: 128,
+        };
+
+        let ctx = TrainContext::new();
+        ctx.cancel();
+        assert!(matches!(
+            VamanaGraph::build(MetricType::L2, &vectors, &params, &ctx),
+            Err(Error::Cancelled)
+        ));
+
+        let graph =
+            VamanaGraph::build(MetricType::L2, &vectors, &params, &TrainContext::new()).unwrap();
+        assert_eq!(graph.len(), DATASET_SIZE);
+        for node in 0..graph.len() {
+            let neighbors = graph.neighbors(node);
+            assert!(!neighbors.is_empty() && neighbors.len() <= params.max_degree);
+            assert!(!neighbors.contains(&(node as u32)));
+        }
+
+        let topk = 10;
+        let queries = MemoryVectorAccessor::new(DIM, gen_floats(32 * DIM));
+        let truth = tune::ground_truth(MetricType::L2, &vectors, &queries, topk);
+        let recall = truth
+            .iter()
+            .enumerate()
+            .map(|(q, truth)| {
+                let (list, _) = graph.search(MetricType::L2, &vectors, queries.get(q), 64);
+                let result: Vec<_> = list.into_iter().take(topk).map(|(i, _)| i).collect();
+                tune::recall(&result, truth)
+            })
+            .sum::<f32>()
+            / truth.len() as f32;
+        assert!(recall > 0.9, "recall: {}", recall);
+    }
+}
@@ -3,26 +3,143 @@
 
 pub mod accessor;
 pub mod error;
+pub mod id_map;
 pub mod index;
 pub mod metric;
+pub mod params;
+pub mod search;
+pub mod sql;
 pub mod test_util;
+pub mod transform;
+pub mod tune;
 
 use async_trait::async_trait;
-use std::{io, pin::Pin};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use tokio::io::AsyncReadExt;
 
 #[derive(Debug, Clone, Copy)]
 pub struct TrainOption {
-    pub iteration_num: Option<usize>,
-    pub nlist: usize,
-    pub metric_type: metric::MetricType, // ... index related options
+    pub metric_type: metric::MetricType,
+    pub params: params::BuildParams,
+}
+
+impl TrainOption {
+    pub fn validate(&self, dim: usize) -> error::Result<()> {
+        if self.metric_type == metric::MetricType::None {
+            return Err(error::Error::InvalidArgument(
+                "metric type is not set".to_string(),
+            ));
+        }
+        self.params.validate(dim)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct SearchOption {
-    pub nprobe: usize,
     pub topk: usize,
-    // ... another index related options
+    pub params: params::SearchParams,
+    // overrides the factor of the refine index for this search,
+    // ignored by the indexes which aren't refined
+    pub refine_factor: Option<usize>,
+}
+
+impl SearchOption {
+    pub fn validate(&self) -> error::Result<()> {
+        if self.refine_factor == Some(0) {
+            return Err(error::Error::InvalidArgument(
+                "refine factor must be positive".to_string(),
+            ));
+        }
+        self.params.validate()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrainStage {
+    Transform,
+    Clustering,
+    Quantization,
+    // inserting the nodes into the graph
+    Graph,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrainProgress {
+    pub stage: TrainStage,
+    // the finished steps of the stage, e.g. the k-means iterations
+    pub step: usize,
+    // the max steps of the stage, the stage may stop earlier
+    pub total: usize,
+    // the inertia of the clustering after the step
+    pub inertia: Option<f64>,
+}
+
+pub type ProgressCallback = Arc<dyn Fn(&TrainProgress) + Send + Sync>;
+
+// the clones share the cancellation, so the training could be cancelled
+// from another thread, the index checks it between the steps
+#[derive(Clone, Default)]
+pub struct TrainContext {
+    cancelled: Arc<AtomicBool>,
+    callback: Option<ProgressCallback>,
+}
+
+impl TrainContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_callback(mut self, callback: ProgressCallback) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // shares the cancellation only, for the nested steps
+    // which shouldn't be reported as the progress of the stage
+    pub(crate) fn without_callback(&self) -> Self {
+        Self {
+            cancelled: self.cancelled.clone(),
+            callback: None,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> error::Result<()> {
+        match self.is_cancelled() {
+            true => Err(error::Error::Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    // notifies the progress, returns the cancelled error if it's cancelled
+    pub fn report(&self, progress: TrainProgress) -> error::Result<()> {
+        if let Some(callback) = &self.callback {
+            callback(&progress);
+        }
+        self.check()
+    }
+}
+
+impl fmt::Debug for TrainContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrainContext")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
 }
 
 // T could be f16, f32, f64, u8
@@ -30,13 +147,51 @@ pub struct SearchOption {
 pub trait AnnIndex: Send + Sync {
     fn train(&mut self, option: &TrainOption);
 
+    // trains with the progress reported to the context,
+    // the index must be trained again if it's cancelled
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        ctx.check()?;
+        self.train(option);
+        Ok(())
+    }
+
     fn search(
         &self,
         query_vector: &[f32],
-        deleted: &roaring::RoaringBitmap,
+        deleted: &roaring::RoaringTreemap,
         option: &SearchOption,
     ) -> Vec<usize>;
 
+    // the disk-resident indexes await the reads of their own files, and refine
+    // awaits the vectors by VectorAccessor::get_async, they stop at the next
+    // read once the future is dropped. the others search in place and block
+    // the runtime, see search::SearchExecutor to run them on the blocking pool
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        Ok(self.search(query_vector, deleted, option))
+    }
+
+    // the params the index was trained with, None if it's not trained
+    fn build_params(&self) -> Option<params::BuildParams>;
+
+    // checks the index could be searched with the option,
+    // the search panics on the options which don't pass it
+    fn validate_search(&self, option: &SearchOption) -> error::Result<()> {
+        option.validate()?;
+        let params = self
+            .build_params()
+            .ok_or_else(|| error::Error::InvalidArgument("index is not trained".to_string()))?;
+        option.params.compatible_with(&params)
+    }
+
     async fn serialize(
         &self,
         mut writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
@@ -48,9 +203,70 @@ pub trait AnnIndex: Send + Sync {
     ) -> Result<(), io::Error>;
 }
 
+#[async_trait]
+impl<T: AnnIndex + ?Sized> AnnIndex for Box<T> {
+    fn train(&mut self, option: &TrainOption) {
+        (**self).train(option)
+    }
+
+    fn train_with_context(
+        &mut self,
+        option: &TrainOption,
+        ctx: &TrainContext,
+    ) -> error::Result<()> {
+        (**self).train_with_context(option, ctx)
+    }
+
+    fn search(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Vec<usize> {
+        (**self).search(query_vector, deleted, option)
+    }
+
+    async fn search_async(
+        &self,
+        query_vector: &[f32],
+        deleted: &roaring::RoaringTreemap,
+        option: &SearchOption,
+    ) -> Result<Vec<usize>, io::Error> {
+        (**self).search_async(query_vector, deleted, option).await
+    }
+
+    fn build_params(&self) -> Option<params::BuildParams> {
+        (**self).build_params()
+    }
+
+    fn validate_search(&self, option: &SearchOption) -> error::Result<()> {
+        (**self).validate_search(option)
+    }
+
+    async fn serialize(
+        &self,
+        writer: Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+    ) -> Result<(), io::Error> {
+        (**self).serialize(writer).await
+    }
+
+    async fn deserialize(
+        &mut self,
+        reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+    ) -> Result<(), io::Error> {
+        (**self).deserialize(reader).await
+    }
+}
+
 #[async_trait]
 pub trait VectorAccessor: Send + Sync {
     fn dim(&self) -> usize;
     fn len(&self) -> usize;
     fn get(&self, index: usize) -> &[f32];
+
+    // the accessors backed by disk should await the read here,
+    // the default copies the vector in memory
+    async fn get_async(&self, index: usize) -> io::Result<Vec<f32>> {
+        Ok(self.get(index).to_vec())
+    }
 }
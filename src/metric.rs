@@ -55,6 +55,38 @@ pub fn l2_distance(a: &[f32], b: &[f32]) -> f32 {
     sum
 }
 
+pub fn inner_product(a: &[f32], b: &[f32]) -> f32 {
+    const LANES: usize = 8;
+
+    let mut sum = a
+        .array_chunks::<LANES>()
+        .map(|&a| Simd::<_, LANES>::from_array(a))
+        .zip(
+            b.array_chunks::<LANES>()
+                .map(|&b| Simd::<_, LANES>::from_array(b)),
+        )
+        .map(|(a, b)| a * b)
+        .fold(Simd::<_, LANES>::splat(0.0), std::ops::Add::add)
+        .reduce_sum();
+    let remain = a.len() - (a.len() % LANES);
+    sum += a[remain..]
+        .iter()
+        .zip(&b[remain..])
+        .map(|(a, b)| a * b)
+        .sum::<f32>();
+    sum
+}
+
+// returns 0 if any of the vectors is a zero vector
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm = (inner_product(a, a) * inner_product(b, b)).sqrt();
+    if norm == 0.0 {
+        return 0.0;
+    }
+
+    inner_product(a, b) / norm
+}
+
 impl From<u8> for MetricType {
     fn from(value: u8) -> Self {
         match value {
@@ -0,0 +1,803 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::{Error, Result};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Clustering {
+    #[default]
+    KMeans,
+    // k-means with capacity-constrained assignment, the list sizes are kept in
+    // [avg * min_ratio, avg * max_ratio] where avg is the average list size
+    Balanced {
+        min_ratio: f32,
+        max_ratio: f32,
+    },
+    // clusters into super_nlist clusters first, then splits each of them into
+    // sub-clusters, the number of which is proportional to its size
+    Hierarchical {
+        super_nlist: usize,
+    },
+    // updates the centroids from random batches until they converge
+    MiniBatch(MiniBatchParams),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LearningSchedule {
+    // the rate of a centroid is 1 / the number of vectors assigned to it so far
+    InverseCount,
+    Constant(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MiniBatchParams {
+    pub batch_size: usize,
+    pub max_iteration_num: usize,
+    pub schedule: LearningSchedule,
+    // stops once the mean squared shift of the centroids in a batch is
+    // at most this, 0 disables it
+    pub tolerance: f32,
+    // stops if the smoothed batch inertia doesn't improve for
+    // this number of batches, 0 disables it
+    pub max_no_improvement: usize,
+}
+
+impl Default for MiniBatchParams {
+    fn default() -> Self {
+        Self {
+            batch_size: 1024,
+            max_iteration_num: 100,
+            schedule: LearningSchedule::InverseCount,
+            tolerance: 0.0,
+            max_no_improvement: 10,
+        }
+    }
+}
+
+impl MiniBatchParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.batch_size == 0 {
+            return Err(Error::InvalidArgument(
+                "batch_size must be positive".to_string(),
+            ));
+        }
+        if self.max_iteration_num == 0 {
+            return Err(Error::InvalidArgument(
+                "max_iteration_num must be positive".to_string(),
+            ));
+        }
+        if let LearningSchedule::Constant(rate) = self.schedule {
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err(Error::InvalidArgument(format!(
+                    "learning rate must be in (0, 1], got {}",
+                    rate
+                )));
+            }
+        }
+        if !(0.0..).contains(&self.tolerance) {
+            return Err(Error::InvalidArgument(format!(
+                "tolerance must be non-negative, got {}",
+                self.tolerance
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Clustering {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Clustering::KMeans => Ok(()),
+            Clustering::Balanced {
+                min_ratio,
+                max_ratio,
+            } => {
+                if !(0.0..=1.0).contains(min_ratio) {
+                    return Err(Error::InvalidArgument(format!(
+                        "min_ratio must be in [0, 1], got {}",
+                        min_ratio
+                    )));
+                }
+                if !(1.0..).contains(max_ratio) {
+                    return Err(Error::InvalidArgument(format!(
+                        "max_ratio must be at least 1, got {}",
+                        max_ratio
+                    )));
+                }
+                Ok(())
+            }
+            Clustering::Hierarchical { super_nlist } if *super_nlist == 0 => Err(
+                Error::InvalidArgument("super_nlist must be positive".to_string()),
+            ),
+            Clustering::Hierarchical { .. } => Ok(()),
+            Clustering::MiniBatch(params) => params.validate(),
+        }
+    }
+}
+
+// the index over the centroids to find the nearest lists
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QuantizerType {
+    // scans all the centroids
+    #[default]
+    Flat,
+    // searches a navigable graph of the centroids, each links to
+    // at most 2 * m neighbors, the search explores at least
+    // ef_construction candidates
+    Graph {
+        m: usize,
+        ef_construction: usize,
+    },
+}
+
+impl QuantizerType {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            QuantizerType::Flat => Ok(()),
+            QuantizerType::Graph { m, ef_construction } => HnswBuildParams {
+                m: *m,
+                ef_construction: *ef_construction,
+            }
+            .validate(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IvfBuildParams {
+    pub nlist: usize,
+    // the k-means iterations
+    pub iteration_num: usize,
+    pub clustering: Clustering,
+    pub quantizer: QuantizerType,
+}
+
+impl Default for IvfBuildParams {
+    fn default() -> Self {
+        Self {
+            nlist: 1024,
+            iteration_num: 25,
+            clustering: Clustering::KMeans,
+            quantizer: QuantizerType::Flat,
+        }
+    }
+}
+
+impl IvfBuildParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.nlist == 0 {
+            return Err(Error::InvalidArgument("nlist must be positive".to_string()));
+        }
+        if self.iteration_num == 0 {
+            return Err(Error::InvalidArgument(
+                "iteration_num must be positive".to_string(),
+            ));
+        }
+        if let Clustering::Hierarchical { super_nlist } = self.clustering {
+            if super_nlist > self.nlist {
+                return Err(Error::InvalidArgument(format!(
+                    "super_nlist {} must be at most nlist {}",
+                    super_nlist, self.nlist
+                )));
+            }
+        }
+        self.clustering.validate()?;
+        self.quantizer.validate()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PqBuildParams {
+    // the number of subspaces, must divide the dim
+    pub m: usize,
+    // the bits of each code, in [1, 8]
+    pub nbits: usize,
+}
+
+impl Default for PqBuildParams {
+    fn default() -> Self {
+        Self { m: 8, nbits: 8 }
+    }
+}
+
+impl PqBuildParams {
+    pub fn validate(&self, dim: usize) -> Result<()> {
+        if self.m == 0 || dim / self.m * self.m != dim {
+            return Err(Error::InvalidArgument(format!(
+                "dim {} is not divisible by the number of subspaces {}",
+                dim, self.m
+            )));
+        }
+        if !(1..=8).contains(&self.nbits) {
+            return Err(Error::InvalidArgument(format!(
+                "nbits must be in [1, 8], got {}",
+                self.nbits
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswBuildParams {
+    // the max number of neighbors of each node
+    pub m: usize,
+    pub ef_construction: usize,
+}
+
+impl Default for HnswBuildParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+        }
+    }
+}
+
+impl HnswBuildParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.m < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "m must be at least 2, got {}",
+                self.m
+            )));
+        }
+        if self.ef_construction < self.m {
+            return Err(Error::InvalidArgument(format!(
+                "ef_construction {} must be at least m {}",
+                self.ef_construction, self.m
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VamanaBuildParams {
+    // the max number of neighbors of each node
+    pub max_degree: usize,
+    // the candidate list size of the searches inserting the nodes
+    pub search_list_size: usize,
+    // the pruning keeps the neighbor unless a selected one is alpha times nearer,
+    // larger alpha keeps more long edges
+    pub alpha: f32,
+    // the max number of nodes inserted against the same graph
+    pub batch_size: usize,
+}
+
+impl Default for VamanaBuildParams {
+    fn default() -> Self {
+        Self {
+            max_degree: 64,
+            search_list_size: 128,
+            alpha: 1.2,
+            batch_size: 4096,
+        }
+    }
+}
+
+impl VamanaBuildParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_degree < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "max_degree must be at least 2, got {}",
+                self.max_degree
+            )));
+        }
+        if self.search_list_size < self.max_degree {
+            return Err(Error::InvalidArgument(format!(
+                "search_list_size {} must be at least max_degree {}",
+                self.search_list_size, self.max_degree
+            )));
+        }
+        if !(1.0..).contains(&self.alpha) {
+            return Err(Error::InvalidArgument(format!(
+                "alpha must be at least 1, got {}",
+                self.alpha
+            )));
+        }
+        if self.batch_size == 0 {
+            return Err(Error::InvalidArgument(
+                "batch_size must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuildParams {
+    Flat,
+    Ivf(IvfBuildParams),
+    IvfPq(IvfBuildParams, PqBuildParams),
+    Hnsw(HnswBuildParams),
+    // the graph on disk, guided by the pq codes in memory
+    Vamana(VamanaBuildParams, PqBuildParams),
+}
+
+impl BuildParams {
+    pub fn validate(&self, dim: usize) -> Result<()> {
+        match self {
+            BuildParams::Flat => Ok(()),
+            BuildParams::Ivf(ivf) => ivf.validate(),
+            BuildParams::IvfPq(ivf, pq) => {
+                ivf.validate()?;
+                pq.validate(dim)
+            }
+            BuildParams::Hnsw(hnsw) => hnsw.validate(),
+            BuildParams::Vamana(vamana, pq) => {
+                vamana.validate()?;
+                pq.validate(dim)
+            }
+        }
+    }
+
+    pub fn ivf(&self) -> Option<&IvfBuildParams> {
+        match self {
+            BuildParams::Ivf(ivf) | BuildParams::IvfPq(ivf, _) => Some(ivf),
+            _ => None,
+        }
+    }
+
+    pub fn pq(&self) -> Option<&PqBuildParams> {
+        match self {
+            BuildParams::IvfPq(_, pq) | BuildParams::Vamana(_, pq) => Some(pq),
+            _ => None,
+        }
+    }
+
+    pub async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        match self {
+            BuildParams::Flat => writer.write_u8(1).await,
+            BuildParams::Ivf(ivf) => {
+                writer.write_u8(2).await?;
+                write_ivf(writer, ivf).await
+            }
+            BuildParams::IvfPq(ivf, pq) => {
+                writer.write_u8(3).await?;
+                write_ivf(writer, ivf).await?;
+                writer.write_u32_le(pq.m as u32).await?;
+                writer.write_u8(pq.nbits as u8).await
+            }
+            BuildParams::Hnsw(hnsw) => {
+                writer.write_u8(4).await?;
+                writer.write_u32_le(hnsw.m as u32).await?;
+                writer.write_u32_le(hnsw.ef_construction as u32).await
+            }
+            BuildParams::Vamana(vamana, pq) => {
+                writer.write_u8(5).await?;
+                writer.write_u32_le(vamana.max_degree as u32).await?;
+                writer.write_u32_le(vamana.search_list_size as u32).await?;
+                writer.write_f32_le(vamana.alpha).await?;
+                writer.write_u64_le(vamana.batch_size as u64).await?;
+                writer.write_u32_le(pq.m as u32).await?;
+                writer.write_u8(pq.nbits as u8).await
+            }
+        }
+    }
+
+    pub async fn deserialize(reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<Self> {
+        Self::deserialize_format(reader, FORMAT).await
+    }
+
+    // reads the params written in the older format
+    pub(crate) async fn deserialize_format(
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        format: u16,
+    ) -> io::Result<Self> {
+        match reader.read_u8().await? {
+            1 => Ok(BuildParams::Flat),
+            2 => Ok(BuildParams::Ivf(read_ivf(reader, format).await?)),
+            3 => {
+                let ivf = read_ivf(reader, format).await?;
+                let pq = PqBuildParams {
+                    m: reader.read_u32_le().await? as usize,
+                    nbits: reader.read_u8().await? as usize,
+                };
+                Ok(BuildParams::IvfPq(ivf, pq))
+            }
+            4 => Ok(BuildParams::Hnsw(HnswBuildParams {
+                m: reader.read_u32_le().await? as usize,
+                ef_construction: reader.read_u32_le().await? as usize,
+            })),
+            5 => {
+                let vamana = VamanaBuildParams {
+                    max_degree: reader.read_u32_le().await? as usize,
+                    search_list_size: reader.read_u32_le().await? as usize,
+                    alpha: reader.read_f32_le().await?,
+                    batch_size: reader.read_u64_le().await? as usize,
+                };
+                let pq = PqBuildParams {
+                    m: reader.read_u32_le().await? as usize,
+                    nbits: reader.read_u8().await? as usize,
+                };
+                Ok(BuildParams::Vamana(vamana, pq))
+            }
+            typ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown build params type {}", typ),
+            )),
+        }
+    }
+}
+
+// format 1: the ivf params are nlist and iteration_num
+// format 2: the ivf params contain the clustering
+// format 3: the ivf params contain the coarse quantizer
+pub(crate) const FORMAT: u16 = 3;
+
+async fn write_ivf(
+    writer: &mut (dyn AsyncWrite + Send + Unpin),
+    params: &IvfBuildParams,
+) -> io::Result<()> {
+    writer.write_u64_le(params.nlist as u64).await?;
+    writer.write_u32_le(params.iteration_num as u32).await?;
+    match params.clustering {
+        Clustering::KMeans => writer.write_u8(1).await?,
+        Clustering::Balanced {
+            min_ratio,
+            max_ratio,
+        } => {
+            writer.write_u8(2).await?;
+            writer.write_f32_le(min_ratio).await?;
+            writer.write_f32_le(max_ratio).await?;
+        }
+        Clustering::Hierarchical { super_nlist } => {
+            writer.write_u8(3).await?;
+            writer.write_u64_le(super_nlist as u64).await?;
+        }
+        Clustering::MiniBatch(params) => {
+            writer.write_u8(4).await?;
+            writer.write_u64_le(params.batch_size as u64).await?;
+            writer.write_u32_le(params.max_iteration_num as u32).await?;
+            match params.schedule {
+                LearningSchedule::InverseCount => writer.write_u8(1).await?,
+                LearningSchedule::Constant(rate) => {
+                    writer.write_u8(2).await?;
+                    writer.write_f32_le(rate).await?;
+                }
+            }
+            writer.write_f32_le(params.tolerance).await?;
+            writer
+                .write_u32_le(params.max_no_improvement as u32)
+                .await?;
+        }
+    }
+    match params.quantizer {
+        QuantizerType::Flat => writer.write_u8(1).await,
+        QuantizerType::Graph { m, ef_construction } => {
+            writer.write_u8(2).await?;
+            writer.write_u32_le(m as u32).await?;
+            writer.write_u32_le(ef_construction as u32).await
+        }
+    }
+}
+
+async fn read_ivf(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    format: u16,
+) -> io::Result<IvfBuildParams> {
+    let nlist = reader.read_u64_le().await? as usize;
+    let iteration_num = reader.read_u32_le().await? as usize;
+    let clustering = match format {
+        1 => Clustering::KMeans,
+        _ => match reader.read_u8().await? {
+            1 => Clustering::KMeans,
+            2 => Clustering::Balanced {
+                min_ratio: reader.read_f32_le().await?,
+                max_ratio: reader.read_f32_le().await?,
+            },
+            3 => Clustering::Hierarchical {
+                super_nlist: reader.read_u64_le().await? as usize,
+            },
+            4 => Clustering::MiniBatch(read_mini_batch(reader).await?),
+            typ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown clustering type {}", typ),
+                ))
+            }
+        },
+    };
+    let quantizer = match format {
+        1 | 2 => QuantizerType::Flat,
+        _ => match reader.read_u8().await? {
+            1 => QuantizerType::Flat,
+            2 => QuantizerType::Graph {
+                m: reader.read_u32_le().await? as usize,
+                ef_construction: reader.read_u32_le().await? as usize,
+            },
+            typ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown quantizer type {}", typ),
+                ))
+            }
+        },
+    };
+    Ok(IvfBuildParams {
+        nlist,
+        iteration_num,
+        clustering,
+        quantizer,
+    })
+}
+
+async fn read_mini_batch(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+) -> io::Result<MiniBatchParams> {
+    let batch_size = reader.read_u64_le().await? as usize;
+    let max_iteration_num = reader.read_u32_le().await? as usize;
+    let schedule = match reader.read_u8().await? {
+        1 => LearningSchedule::InverseCount,
+        2 => LearningSchedule::Constant(reader.read_f32_le().await?),
+        typ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown learning schedule type {}", typ),
+            ))
+        }
+    };
+    Ok(MiniBatchParams {
+        batch_size,
+        max_iteration_num,
+        schedule,
+        tolerance: reader.read_f32_le().await?,
+        max_no_improvement: reader.read_u32_le().await? as usize,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IvfSearchParams {
+    // the number of nearest clusters to scan, the max number if adaptive
+    pub nprobe: usize,
+    // skips the clusters which can't contain a vector nearer than the
+    // current k-th result, and stops once the rest are all too far,
+    // only ivf with L2 metric supports it
+    pub adaptive: bool,
+}
+
+impl Default for IvfSearchParams {
+    fn default() -> Self {
+        Self {
+            nprobe: 8,
+            adaptive: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswSearchParams {
+    pub ef: usize,
+}
+
+impl Default for HnswSearchParams {
+    fn default() -> Self {
+        Self { ef: 64 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VamanaSearchParams {
+    // the candidate list size, at least topk is used
+    pub search_list_size: usize,
+    // the number of nodes read from disk concurrently in each step
+    pub beam_width: usize,
+}
+
+impl Default for VamanaSearchParams {
+    fn default() -> Self {
+        Self {
+            search_list_size: 64,
+            beam_width: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchParams {
+    Flat,
+    Ivf(IvfSearchParams),
+    Hnsw(HnswSearchParams),
+    Vamana(VamanaSearchParams),
+}
+
+impl SearchParams {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            SearchParams::Flat => Ok(()),
+            SearchParams::Ivf(ivf) if ivf.nprobe == 0 => Err(Error::InvalidArgument(
+                "nprobe must be positive".to_string(),
+            )),
+            SearchParams::Hnsw(hnsw) if hnsw.ef == 0 => {
+                Err(Error::InvalidArgument("ef must be positive".to_string()))
+            }
+            SearchParams::Vamana(vamana) if vamana.search_list_size == 0 => Err(
+                Error::InvalidArgument("search_list_size must be positive".to_string()),
+            ),
+            SearchParams::Vamana(vamana) if vamana.beam_width == 0 => Err(Error::InvalidArgument(
+                "beam_width must be positive".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // checks the params could search an index trained with the build params,
+    // the index panics on the params which don't pass it
+    pub fn compatible_with(&self, params: &BuildParams) -> Result<()> {
+        match (self, params) {
+            (SearchParams::Ivf(ivf), BuildParams::IvfPq(..)) if ivf.adaptive => Err(
+                Error::InvalidArgument("ivf-pq index doesn't support adaptive nprobe".to_string()),
+            ),
+            (SearchParams::Flat, BuildParams::Flat)
+            | (SearchParams::Ivf(_), BuildParams::Ivf(_) | BuildParams::IvfPq(..))
+            | (SearchParams::Hnsw(_), BuildParams::Hnsw(_))
+            | (SearchParams::Vamana(_), BuildParams::Vamana(..)) => Ok(()),
+            (search, build) => Err(Error::InvalidArgument(format!(
+                "search params {:?} don't match build params {:?}",
+                search, build
+            ))),
+        }
+    }
+
+    pub fn ivf(&self) -> Option<&IvfSearchParams> {
+        match self {
+            SearchParams::Ivf(ivf) => Some(ivf),
+            _ => None,
+        }
+    }
+
+    pub fn hnsw(&self) -> Option<&HnswSearchParams> {
+        match self {
+            SearchParams::Hnsw(hnsw) => Some(hnsw),
+            _ => None,
+        }
+    }
+
+    pub fn vamana(&self) -> Option<&VamanaSearchParams> {
+        match self {
+            SearchParams::Vamana(vamana) => Some(vamana),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::*;
+
+    #[tokio::test]
+    async fn test_build_params() {
+        assert!(BuildParams::Ivf(IvfBuildParams::default())
+            .validate(32)
+            .is_ok());
+        assert!(BuildParams::Ivf(IvfBuildParams {
+            nlist: 0,
+            ..Default::default()
+        })
+        .validate(32)
+        .is_err());
+        assert!(
+            BuildParams::IvfPq(Default::default(), PqBuildParams { m: 5, nbits: 8 })
+                .validate(32)
+                .is_err()
+        );
+        assert!(
+            BuildParams::IvfPq(Default::default(), PqBuildParams { m: 4, nbits: 9 })
+                .validate(32)
+                .is_err()
+        );
+        assert!(BuildParams::Hnsw(HnswBuildParams {
+            m: 32,
+            ef_construction: 16,
+        })
+        .validate(32)
+        .is_err());
+        assert!(BuildParams::Ivf(IvfBuildParams {
+            clustering: Clustering::Balanced {
+                min_ratio: 0.5,
+                max_ratio: 0.9,
+            },
+            ..Default::default()
+        })
+        .validate(32)
+        .is_err());
+        assert!(BuildParams::Ivf(IvfBuildParams {
+            nlist: 16,
+            clustering: Clustering::Hierarchical { super_nlist: 32 },
+            ..Default::default()
+        })
+        .validate(32)
+        .is_err());
+        assert!(BuildParams::Ivf(IvfBuildParams {
+            clustering: Clustering::MiniBatch(MiniBatchParams {
+                schedule: LearningSchedule::Constant(0.0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .validate(32)
+        .is_err());
+        assert!(BuildParams::Vamana(
+            VamanaBuildParams {
+                alpha: 0.9,
+                ..Default::default()
+            },
+            PqBuildParams { m: 4, nbits: 8 }
+        )
+        .validate(32)
+        .is_err());
+        assert!(SearchParams::Vamana(VamanaSearchParams {
+            beam_width: 0,
+            ..Default::default()
+        })
+        .validate()
+        .is_err());
+        assert!(SearchParams::Ivf(IvfSearchParams {
+            nprobe: 0,
+            ..Default::default()
+        })
+        .validate()
+        .is_err());
+        let adaptive = SearchParams::Ivf(IvfSearchParams {
+            adaptive: true,
+            ..Default::default()
+        });
+        assert!(adaptive
+            .compatible_with(&BuildParams::Ivf(Default::default()))
+            .is_ok());
+        assert!(adaptive
+            .compatible_with(&BuildParams::IvfPq(Default::default(), Default::default()))
+            .is_err());
+        assert!(SearchParams::Flat
+            .compatible_with(&BuildParams::Ivf(Default::default()))
+            .is_err());
+
+        for params in [
+            BuildParams::Flat,
+            BuildParams::Ivf(IvfBuildParams {
+                nlist: 4096,
+                iteration_num: 10,
+                ..Default::default()
+            }),
+            BuildParams::Ivf(IvfBuildParams {
+                clustering: Clustering::Balanced {
+                    min_ratio: 0.5,
+                    max_ratio: 1.5,
+                },
+                ..Default::default()
+            }),
+            BuildParams::Ivf(IvfBuildParams {
+                clustering: Clustering::Hierarchical { super_nlist: 32 },
+                quantizer: QuantizerType::Graph {
+                    m: 16,
+                    ef_construction: 64,
+                },
+                ..Default::default()
+            }),
+            BuildParams::IvfPq(Default::default(), PqBuildParams { m: 32, nbits: 4 }),
+            BuildParams::Hnsw(Default::default()),
+            BuildParams::Vamana(Default::default(), PqBuildParams { m: 8, nbits: 8 }),
+        ] {
+            let mut data = Vec::new();
+            params.serialize(&mut data).await.unwrap();
+            let deserialized = BuildParams::deserialize(&mut std::io::Cursor::new(data))
+                .await
+                .unwrap();
+            assert_eq!(deserialized, params);
+        }
+    }
+}
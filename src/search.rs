@@ -0,0 +1,259 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    error::{Error, Result},
+    AnnIndex, SearchOption,
+};
+use roaring::RoaringTreemap;
+use std::{panic, sync::Arc, thread};
+use tokio::{
+    runtime::Handle,
+    sync::{oneshot, RwLock, Semaphore},
+};
+
+// runs the searches on the blocking pool of tokio, so they don't block the
+// async tasks, at most max_concurrency searches run at the same time.
+// dropping the future cancels the search if it's not started, the
+// disk-resident indexes and the re-ranking of refine stop at the next read,
+// the others finish in the pool
+#[derive(Clone)]
+pub struct SearchExecutor {
+    permits: Arc<Semaphore>,
+    max_concurrency: usize,
+}
+
+impl Default for SearchExecutor {
+    // one search per core
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl SearchExecutor {
+    pub fn new(max_concurrency: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            max_concurrency,
+        }
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    // the number of searches could be started now
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    pub async fn search(
+        &self,
+        index: Arc<RwLock<dyn AnnIndex>>,
+        query_vector: Vec<f32>,
+        deleted: Arc<RoaringTreemap>,
+        option: SearchOption,
+    ) -> Result<Vec<usize>> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+
+        // the sender is dropped with the future
+        let (_cancel, mut cancelled) = oneshot::channel::<()>();
+        let handle = Handle::current();
+        let task = tokio::task::spawn_blocking(move || {
+            // the permit is released once the search returns, even if it's cancelled
+            let _permit = permit;
+            let index = index.blocking_read();
+            if cancelled.try_recv() != Err(oneshot::error::TryRecvError::Empty) {
+                return Err(Error::Cancelled);
+            }
+            // the index may be trained again until it's locked
+            index.validate_search(&option)?;
+
+            handle.block_on(async {
+                tokio::select! {
+                    result = index.search_async(&query_vector, &deleted, &option) => {
+                        result.map_err(Error::from)
+                    }
+                    _ = &mut cancelled => Err(Error::Cancelled),
+                }
+            })
+        });
+
+        match task.await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            Err(_) => Err(Error::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::flat::Flat;
+    use crate::params::{BuildParams, SearchParams};
+    use crate::search::*;
+    use crate::test_util::gen_floats;
+    use crate::{metric, TrainOption, VectorAccessor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const DIM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    // counts the searches of the inner index
+    struct CountedIndex {
+        index: Flat,
+        count: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl AnnIndex for CountedIndex {
+        fn train(&mut self, option: &TrainOption) {
+            self.index.train(option)
+        }
+
+        fn search(
+            &self,
+            query_vector: &[f32],
+            deleted: &RoaringTreemap,
+            option: &SearchOption,
+        ) -> Vec<usize> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.index.search(query_vector, deleted, option)
+        }
+
+        fn build_params(&self) -> Option<BuildParams> {
+            self.index.build_params()
+        }
+
+        async fn serialize(
+            &self,
+            writer: std::pin::Pin<Box<dyn tokio::io::AsyncWrite + Send>>,
+        ) -> std::io::Result<()> {
+            self.index.serialize(writer).await
+        }
+
+        async fn deserialize(
+            &mut self,
+            reader: std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+        ) -> std::io::Result<()> {
+            self.index.deserialize(reader).await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_search_executor() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut index = CountedIndex {
+            index: Flat::new(vectors.clone()),
+            count: count.clone(),
+        };
+        index.train(&TrainOption {
+            metric_type: metric::MetricType::L2,
+            params: BuildParams::Flat,
+        });
+        let index: Arc<RwLock<dyn AnnIndex>> = Arc::new(RwLock::new(index));
+
+        let executor = SearchExecutor::new(2);
+        let option = SearchOption {
+            topk: 4,
+            params: SearchParams::Flat,
+            refine_factor: None,
+        };
+        let deleted = Arc::new(RoaringTreemap::new());
+        let results = futures::future::join_all((0..16).map(|id| {
+            executor.search(
+                index.clone(),
+                vectors.get(id).to_vec(),
+                deleted.clone(),
+                option,
+            )
+        }))
+        .await;
+        for (id, result) in results.into_iter().enumerate() {
+            assert!(result.unwrap().contains(&id));
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 16);
+        assert_eq!(executor.available(), 2);
+
+        // the search waiting for the index is cancelled by dropping it
+        let executor = SearchExecutor::new(1);
+        let guard = index.write().await;
+        let search = executor.search(
+            index.clone(),
+            vectors.get(0).to_vec(),
+            deleted.clone(),
+            option,
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(50), search)
+            .await
+            .is_err());
+        drop(guard);
+        // waits for the permit of the cancelled search
+        let result = executor
+            .search(
+                index.clone(),
+                vectors.get(1).to_vec(),
+                deleted.clone(),
+                option,
+            )
+            .await;
+        assert!(result.unwrap().contains(&1));
+        assert_eq!(count.load(Ordering::Relaxed), 17);
+
+        let option = SearchOption {
+            topk: 4,
+            params: SearchParams::Ivf(crate::params::IvfSearchParams {
+                nprobe: 0,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        assert!(matches!(
+            executor
+                .search(
+                    index.clone(),
+                    vectors.get(0).to_vec(),
+                    deleted.clone(),
+                    option
+                )
+                .await,
+            Err(Error::InvalidArgument(_))
+        ));
+        // the flat index can't be searched with ivf params
+        let option = SearchOption {
+            topk: 4,
+            params: SearchParams::Ivf(Default::default()),
+            refine_factor: None,
+        };
+        assert!(matches!(
+            executor
+                .search(index, vectors.get(0).to_vec(), deleted, option)
+                .await,
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(count.load(Ordering::Relaxed), 17);
+    }
+}
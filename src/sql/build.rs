@@ -0,0 +1,232 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::accessor::{self, ChunkedVectorAccessor};
+use crate::id_map::{ExternalId, IdMap, MappedIndex};
+use crate::index;
+use crate::metric::MetricType;
+use crate::{AnnIndex, TrainContext, TrainOption, VectorAccessor};
+use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::compute::concat;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::prelude::{DataFrame, ParquetReadOptions, SessionContext};
+use datafusion::scalar::ScalarValue;
+use futures::TryStreamExt;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// an index built from a DataFrame,
+// the id of a vector in the index is its offset in the vector column
+pub struct BuiltIndex {
+    pub index: Arc<RwLock<dyn AnnIndex>>,
+    // the vector column, zero-copy over the record batches
+    pub vectors: Arc<ChunkedVectorAccessor>,
+    // primary_keys[id] is the primary key of the row of vector id
+    pub primary_keys: ArrayRef,
+}
+
+impl BuiltIndex {
+    pub fn primary_key(&self, id: usize) -> Result<ScalarValue> {
+        ScalarValue::try_from_array(&self.primary_keys, id)
+    }
+
+    // maps the index ids to the primary keys
+    pub fn into_mapped<K: ExternalId>(self) -> Result<MappedIndex<K>> {
+        let ids = IdMap::try_from_array(self.primary_keys.as_ref())
+            .map_err(|err| DataFusionError::External(Box::new(err)))?;
+        Ok(MappedIndex::new(self.index, ids))
+    }
+}
+
+pub async fn build_index_from_parquet(
+    ctx: &SessionContext,
+    path: &str,
+    vector_column: &str,
+    primary_key: &str,
+    spec: &str,
+    metric_type: MetricType,
+) -> Result<BuiltIndex> {
+    let df = ctx
+        .read_parquet(path, ParquetReadOptions::default())
+        .await?;
+    build_index(df, vector_column, primary_key, spec, metric_type).await
+}
+
+// streams the vector column and the primary key column of the DataFrame,
+// then trains the index described by the spec over the vectors,
+// see index::factory::IndexSpec,
+// the vectors are kept in the record batches instead of being copied out
+pub async fn build_index(
+    df: DataFrame,
+    vector_column: &str,
+    primary_key: &str,
+    spec: &str,
+    metric_type: MetricType,
+) -> Result<BuiltIndex> {
+    let mut stream = df
+        .select_columns(&[vector_column, primary_key])?
+        .execute_stream()
+        .await?;
+
+    let mut chunks: Vec<Arc<dyn VectorAccessor>> = Vec::new();
+    let mut keys = Vec::new();
+    while let Some(batch) = stream.try_next().await? {
+        if batch.num_rows() == 0 {
+            continue;
+        }
+
+        let vectors = accessor::from_arrow(batch.column(0))
+            .map_err(|err| DataFusionError::Plan(format!("column {}: {}", vector_column, err)))?;
+        chunks.push(vectors);
+        keys.push(batch.column(1).clone());
+    }
+
+    if chunks.is_empty() {
+        return Err(DataFusionError::Plan(
+            "can't build index on empty DataFrame".to_string(),
+        ));
+    }
+    let vectors = Arc::new(
+        ChunkedVectorAccessor::try_new(chunks)
+            .map_err(|err| DataFusionError::Plan(format!("column {}: {}", vector_column, err)))?,
+    );
+    let keys: Vec<_> = keys.iter().map(|key| key.as_ref()).collect();
+    let primary_keys = concat(&keys)?;
+
+    // the training is cpu-bound, which would stall the runtime
+    let (index, params) = index::from_spec(spec, vectors.clone())
+        .map_err(|err| DataFusionError::Plan(format!("index spec {}: {}", spec, err)))?;
+    let option = TrainOption {
+        metric_type,
+        params,
+    };
+    let trained = index.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        trained
+            .blocking_write()
+            .train_with_context(&option, &TrainContext::default())
+    })
+    .await;
+    match result {
+        Ok(result) => result.map_err(|err| DataFusionError::External(Box::new(err)))?,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => return Err(DataFusionError::External(Box::new(err))),
+    }
+
+    Ok(BuiltIndex {
+        index,
+        vectors,
+        primary_keys,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::*;
+    use crate::sql::build::*;
+    use crate::test_util::gen_vectors;
+    use crate::SearchOption;
+    use datafusion::arrow::array::*;
+    use datafusion::arrow::datatypes::Float32Type;
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::parquet::arrow::ArrowWriter;
+
+    const DIM: usize = 8;
+    const CLUSTER_NUM: usize = 8;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_build_index_from_parquet() {
+        let vectors = gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM);
+        // the parquet writer doesn't support FixedSizeList
+        let embedding = ListArray::from_iter_primitive::<Float32Type, _, _>(
+            (0..DATASET_SIZE).map(|i| Some(vectors.get(i).iter().map(|v| Some(*v)))),
+        );
+        let keys = StringArray::from_iter_values((0..DATASET_SIZE).map(|i| format!("key-{}", i)));
+        let batch = RecordBatch::try_from_iter(vec![
+            ("key", Arc::new(keys) as ArrayRef),
+            ("embedding", Arc::new(embedding) as ArrayRef),
+        ])
+        .unwrap();
+
+        let temp_dir = temp_dir::TempDir::new().unwrap();
+        let path = temp_dir.path().join("items.parquet");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+        // write multiple row groups
+        for offset in (0..DATASET_SIZE).step_by(DATASET_SIZE / 4) {
+            writer
+                .write(&batch.slice(offset, DATASET_SIZE / 4))
+                .unwrap();
+            writer.flush().unwrap();
+        }
+        writer.close().unwrap();
+
+        let ctx = SessionContext::new();
+        assert!(build_index_from_parquet(
+            &ctx,
+            path.to_str().unwrap(),
+            "embedding",
+            "key",
+            "HNSW32",
+            MetricType::L2,
+        )
+        .await
+        .is_err());
+        let built = build_index_from_parquet(
+            &ctx,
+            path.to_str().unwrap(),
+            "embedding",
+            "key",
+            &format!("IVF{},Flat", CLUSTER_NUM),
+            MetricType::L2,
+        )
+        .await
+        .unwrap();
+        assert_eq!(built.vectors.len(), DATASET_SIZE);
+        assert_eq!(built.primary_keys.len(), DATASET_SIZE);
+
+        let option = SearchOption {
+            topk: CLUSTER_NUM,
+            params: SearchParams::Ivf(IvfSearchParams {
+                nprobe: CLUSTER_NUM / 2,
+                ..Default::default()
+            }),
+            refine_factor: None,
+        };
+        let index = built.index.read().await;
+        for i in 0..DATASET_SIZE {
+            let query = vectors.get(i);
+            let result = index.search(query, &roaring::RoaringTreemap::new(), &option);
+            for id in result {
+                assert_eq!(built.vectors.get(id), query);
+                let key = match built.primary_key(id).unwrap() {
+                    ScalarValue::Utf8(Some(key)) => key,
+                    other => panic!("unexpected key {:?}", other),
+                };
+                let i: usize = key.strip_prefix("key-").unwrap().parse().unwrap();
+                assert_eq!(vectors.get(i), query);
+            }
+        }
+        drop(index);
+
+        let mapped = built.into_mapped::<String>().unwrap();
+        let result = mapped.search(vectors.get(0), &option).await;
+        assert_eq!(result.len(), CLUSTER_NUM);
+        for key in result {
+            let i: usize = key.strip_prefix("key-").unwrap().parse().unwrap();
+            assert_eq!(vectors.get(i), vectors.get(0));
+        }
+    }
+}
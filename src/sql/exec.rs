@@ -0,0 +1,236 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::knn::IndexedTable;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::TableProvider;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::{
+    expressions::PhysicalSortExpr, memory::MemoryExec, project_schema,
+    stream::RecordBatchStreamAdapter, DisplayFormatType, ExecutionPlan, Partitioning,
+    SendableRecordBatchStream, Statistics,
+};
+use std::{any::Any, fmt, sync::Arc};
+
+// full scan of an IndexedTable, the optimizer rule replaces it with an AnnScanExec if possible
+pub struct IndexedScanExec {
+    table: Arc<IndexedTable>,
+    projection: Option<Vec<usize>>,
+    inner: MemoryExec,
+}
+
+impl IndexedScanExec {
+    pub fn try_new(table: Arc<IndexedTable>, projection: Option<Vec<usize>>) -> Result<Self> {
+        let inner = MemoryExec::try_new(
+            &[vec![table.batch().clone()]],
+            table.schema(),
+            projection.clone(),
+        )?;
+
+        Ok(Self {
+            table,
+            projection,
+            inner,
+        })
+    }
+
+    pub fn table(&self) -> &Arc<IndexedTable> {
+        &self.table
+    }
+
+    pub fn projection(&self) -> Option<&Vec<usize>> {
+        self.projection.as_ref()
+    }
+}
+
+impl fmt::Debug for IndexedScanExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexedScanExec")
+            .field("column", &self.table.column())
+            .field("projection", &self.projection)
+            .finish()
+    }
+}
+
+impl ExecutionPlan for IndexedScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.inner.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.inner.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.inner.output_ordering()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Internal(format!(
+            "Children cannot be replaced in {:?}",
+            self
+        )))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        self.inner.execute(partition, context)
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IndexedScanExec: column={}", self.table.column())
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.inner.statistics()
+    }
+}
+
+// scans only the k nearest rows of the query vector by the ANN index,
+// the rows are ordered by distance
+pub struct AnnScanExec {
+    table: Arc<IndexedTable>,
+    projection: Option<Vec<usize>>,
+    schema: SchemaRef,
+    query: Vec<f32>,
+    k: usize,
+}
+
+impl AnnScanExec {
+    pub fn try_new(
+        table: Arc<IndexedTable>,
+        projection: Option<Vec<usize>>,
+        query: Vec<f32>,
+        k: usize,
+    ) -> Result<Self> {
+        if query.len() != table.dim() {
+            return Err(DataFusionError::Plan(format!(
+                "query vector dimension mismatch: {} vs {}",
+                query.len(),
+                table.dim()
+            )));
+        }
+
+        let schema = project_schema(&table.schema(), projection.as_ref())?;
+        Ok(Self {
+            table,
+            projection,
+            schema,
+            query,
+            k,
+        })
+    }
+}
+
+impl fmt::Debug for AnnScanExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnnScanExec")
+            .field("column", &self.table.column())
+            .field("projection", &self.projection)
+            .field("k", &self.k)
+            .finish()
+    }
+}
+
+impl ExecutionPlan for AnnScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Internal(format!(
+            "Children cannot be replaced in {:?}",
+            self
+        )))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        if partition != 0 {
+            return Err(DataFusionError::Internal(format!(
+                "AnnScanExec has only 1 partition, got partition {}",
+                partition
+            )));
+        }
+
+        let table = self.table.clone();
+        let projection = self.projection.clone();
+        let query = self.query.clone();
+        let k = self.k;
+        let stream = futures::stream::once(async move {
+            let (indices, _) = table.search(&query, k).await;
+            let batch = RecordBatch::try_new(table.schema(), table.take(&indices)?)?;
+            match projection {
+                Some(projection) => Ok(batch.project(&projection)?),
+                None => Ok(batch),
+            }
+        });
+
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema.clone(),
+            stream,
+        )))
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "AnnScanExec: column={}, k={}",
+            self.table.column(),
+            self.k
+        )
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::exec::IndexedScanExec;
+use crate::{metric::MetricType, *};
+use datafusion::arrow::array::*;
+use datafusion::arrow::compute::take;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::{TableProvider, TableType};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::SessionState;
+use datafusion::logical_expr::Expr;
+use datafusion::physical_plan::{memory::MemoryExec, ExecutionPlan};
+use datafusion::prelude::{DataFrame, SessionContext};
+use datafusion::sql::parser::Statement as DFStatement;
+use datafusion::sql::sqlparser::ast;
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::RwLock;
+
+pub const KNN_FUNCTION: &str = "knn";
+pub const DISTANCE_COLUMN: &str = "_distance";
+
+static NEXT_TABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// a record batch with an ANN index built over one of its vector columns,
+// it could be registered as a regular table to accelerate the ORDER BY distance LIMIT k queries
+#[derive(Clone)]
+pub struct IndexedTable {
+    batch: RecordBatch,
+    column: String,
+    accessor: Arc<dyn VectorAccessor>,
+    index: Arc<RwLock<dyn AnnIndex>>,
+    metric_type: MetricType,
+    search_option: SearchOption,
+}
+
+impl IndexedTable {
+    // the topk of search_option is overridden by the k of each knn call
+    pub fn try_new(
+        batch: RecordBatch,
+        column: &str,
+        index: Arc<RwLock<dyn AnnIndex>>,
+        metric_type: MetricType,
+        search_option: SearchOption,
+    ) -> Result<Self> {
+        let vectors = batch.column(batch.schema().index_of(column)?);
+        let accessor = accessor::from_arrow(vectors)
+            .map_err(|err| DataFusionError::Plan(format!("column {}: {}", column, err)))?;
+
+        Ok(Self {
+            column: column.to_string(),
+            accessor,
+            batch,
+            index,
+            metric_type,
+            search_option,
+        })
+    }
+
+    pub fn batch(&self) -> &RecordBatch {
+        &self.batch
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn dim(&self) -> usize {
+        self.accessor.dim()
+    }
+
+    pub fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    // the value of the distance function of the metric, e.g. l2_distance
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self.metric_type {
+            MetricType::L2 => super::udf::euclidean_distance(a, b),
+            MetricType::None => self.metric_type.distance(a, b),
+        }
+    }
+
+    // returns the row indices and distances of the k nearest rows, ordered by distance
+    pub(crate) async fn search(&self, query: &[f32], k: usize) -> (UInt64Array, Float32Array) {
+        let option = SearchOption {
+            topk: k,
+            ..self.search_option
+        };
+        let ids = self
+            .index
+            .read()
+            .await
+            .search(query, &roaring::RoaringTreemap::new(), &option);
+
+        let mut neighbors: Vec<_> = ids
+            .into_iter()
+            .map(|id| (self.distance(query, self.accessor.get(id)), id))
+            .collect();
+        neighbors.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        (
+            UInt64Array::from_iter_values(neighbors.iter().map(|(_, id)| *id as u64)),
+            Float32Array::from_iter_values(neighbors.iter().map(|(distance, _)| *distance)),
+        )
+    }
+
+    pub(crate) fn take(&self, indices: &UInt64Array) -> Result<Vec<ArrayRef>> {
+        Ok(self
+            .batch
+            .columns()
+            .iter()
+            .map(|column| take(column.as_ref(), indices, None))
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+#[async_trait]
+impl TableProvider for IndexedTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.batch.schema()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(IndexedScanExec::try_new(
+            Arc::new(self.clone()),
+            projection.cloned(),
+        )?))
+    }
+}
+
+// the result of knn(table, column, query, k):
+// the k nearest rows of the table, ordered by the _distance column
+pub struct KnnTable {
+    table: Arc<IndexedTable>,
+    schema: SchemaRef,
+    query: Vec<f32>,
+    k: usize,
+}
+
+impl KnnTable {
+    pub fn try_new(table: Arc<IndexedTable>, query: Vec<f32>, k: usize) -> Result<Self> {
+        if query.len() != table.accessor.dim() {
+            return Err(DataFusionError::Plan(format!(
+                "query vector dimension mismatch: {} vs {}",
+                query.len(),
+                table.accessor.dim()
+            )));
+        }
+
+        let mut fields: Vec<_> = table.batch.schema().fields().iter().cloned().collect();
+        fields.push(Arc::new(Field::new(
+            DISTANCE_COLUMN,
+            DataType::Float32,
+            false,
+        )));
+        Ok(Self {
+            table,
+            schema: Arc::new(Schema::new(fields)),
+            query,
+            k,
+        })
+    }
+}
+
+#[async_trait]
+impl TableProvider for KnnTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Temporary
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let (indices, distances) = self.table.search(&self.query, self.k).await;
+        let mut columns = self.table.take(&indices)?;
+        columns.push(Arc::new(distances));
+
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        Ok(Arc::new(MemoryExec::try_new(
+            &[vec![batch]],
+            self.schema.clone(),
+            projection.cloned(),
+        )?))
+    }
+}
+
+// DataFusion doesn't plan table functions,
+// so the knn calls in the FROM clauses are replaced by temporary KnnTables before planning:
+//   SELECT * FROM knn('items', 'embedding', [0.1, 0.2, ...], 10) WHERE ...
+#[derive(Default)]
+pub struct KnnFunction {
+    // (table, column) -> indexed table
+    tables: std::sync::RwLock<HashMap<(String, String), Arc<IndexedTable>>>,
+}
+
+impl KnnFunction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_table(&self, name: &str, table: IndexedTable) -> Option<Arc<IndexedTable>> {
+        self.tables
+            .write()
+            .unwrap()
+            .insert((name.to_string(), table.column.clone()), Arc::new(table))
+    }
+
+    pub fn deregister_table(&self, name: &str, column: &str) -> Option<Arc<IndexedTable>> {
+        self.tables
+            .write()
+            .unwrap()
+            .remove(&(name.to_string(), column.to_string()))
+    }
+
+    pub fn call(&self, table: &str, column: &str, query: Vec<f32>, k: usize) -> Result<KnnTable> {
+        let indexed_table = self
+            .tables
+            .read()
+            .unwrap()
+            .get(&(table.to_string(), column.to_string()))
+            .cloned()
+            .ok_or_else(|| {
+                DataFusionError::Plan(format!("no index on column {} of table {}", column, table))
+            })?;
+
+        KnnTable::try_new(indexed_table, query, k)
+    }
+
+    pub async fn sql(&self, ctx: &SessionContext, sql: &str) -> Result<DataFrame> {
+        let state = ctx.state();
+        let dialect = state.config_options().sql_parser.dialect.clone();
+        let mut statement = state.sql_to_statement(sql, &dialect)?;
+
+        let mut tables = Vec::new();
+        if let DFStatement::Statement(statement) = &mut statement {
+            if let ast::Statement::Query(query) = statement.as_mut() {
+                self.rewrite_query(query, &mut tables)?;
+            }
+        }
+
+        for (name, table) in &tables {
+            ctx.register_table(name.as_str(), table.clone())?;
+        }
+        let plan = ctx.state().statement_to_plan(statement).await;
+        for (name, _) in &tables {
+            ctx.deregister_table(name.as_str())?;
+        }
+
+        ctx.execute_logical_plan(plan?).await
+    }
+
+    fn rewrite_query(
+        &self,
+        query: &mut ast::Query,
+        tables: &mut Vec<(String, Arc<KnnTable>)>,
+    ) -> Result<()> {
+        if let Some(with) = &mut query.with {
+            for cte in &mut with.cte_tables {
+                self.rewrite_query(&mut cte.query, tables)?;
+            }
+        }
+        self.rewrite_set_expr(&mut query.body, tables)
+    }
+
+    fn rewrite_set_expr(
+        &self,
+        expr: &mut ast::SetExpr,
+        tables: &mut Vec<(String, Arc<KnnTable>)>,
+    ) -> Result<()> {
+        match expr {
+            ast::SetExpr::Select(select) => {
+                for from in &mut select.from {
+                    self.rewrite_table_with_joins(from, tables)?;
+                }
+                Ok(())
+            }
+            ast::SetExpr::Query(query) => self.rewrite_query(query, tables),
+            ast::SetExpr::SetOperation { left, right, .. } => {
+                self.rewrite_set_expr(left, tables)?;
+                self.rewrite_set_expr(right, tables)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn rewrite_table_with_joins(
+        &self,
+        from: &mut ast::TableWithJoins,
+        tables: &mut Vec<(String, Arc<KnnTable>)>,
+    ) -> Result<()> {
+        self.rewrite_table_factor(&mut from.relation, tables)?;
+        for join in &mut from.joins {
+            self.rewrite_table_factor(&mut join.relation, tables)?;
+        }
+        Ok(())
+    }
+
+    fn rewrite_table_factor(
+        &self,
+        relation: &mut ast::TableFactor,
+        tables: &mut Vec<(String, Arc<KnnTable>)>,
+    ) -> Result<()> {
+        match relation {
+            ast::TableFactor::Table {
+                name, alias, args, ..
+            } => {
+                let is_knn =
+                    name.0.len() == 1 && name.0[0].value.eq_ignore_ascii_case(KNN_FUNCTION);
+                let args = match args.take() {
+                    Some(args) if is_knn => args,
+                    other => {
+                        *args = other;
+                        return Ok(());
+                    }
+                };
+
+                let table = self.call_with_args(&args)?;
+                let table_name = format!(
+                    "__anna_knn_{}",
+                    NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed)
+                );
+                *name = ast::ObjectName(vec![ast::Ident::new(&table_name)]);
+                if alias.is_none() {
+                    *alias = Some(ast::TableAlias {
+                        name: ast::Ident::new(KNN_FUNCTION),
+                        columns: vec![],
+                    });
+                }
+                tables.push((table_name, Arc::new(table)));
+                Ok(())
+            }
+            ast::TableFactor::Derived { subquery, .. } => self.rewrite_query(subquery, tables),
+            ast::TableFactor::NestedJoin {
+                table_with_joins, ..
+            } => self.rewrite_table_with_joins(table_with_joins, tables),
+            _ => Ok(()),
+        }
+    }
+
+    // knn('table', 'column', [query vector], k)
+    fn call_with_args(&self, args: &[ast::FunctionArg]) -> Result<KnnTable> {
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(expr)) => Ok(expr),
+                _ => Err(DataFusionError::Plan(format!(
+                    "unsupported argument {} of {}",
+                    arg, KNN_FUNCTION
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if args.len() != 4 {
+            return Err(DataFusionError::Plan(format!(
+                "{}(table, column, query, k) expects 4 arguments, got {}",
+                KNN_FUNCTION,
+                args.len()
+            )));
+        }
+
+        let table = string_arg(args[0])?;
+        let column = string_arg(args[1])?;
+        let query = match args[2] {
+            ast::Expr::Array(array) => array
+                .elem
+                .iter()
+                .map(|v| number_arg(v).map(|v| v as f32))
+                .collect::<Result<Vec<_>>>()?,
+            other => {
+                return Err(DataFusionError::Plan(format!(
+                    "query of {} must be an array literal, got {}",
+                    KNN_FUNCTION, other
+                )))
+            }
+        };
+        let k = number_arg(args[3])?;
+        if k < 1.0 || k.fract() != 0.0 {
+            return Err(DataFusionError::Plan(format!(
+                "k of {} must be a positive integer, got {}",
+                KNN_FUNCTION, k
+            )));
+        }
+
+        self.call(&table, &column, query, k as usize)
+    }
+}
+
+fn string_arg(expr: &ast::Expr) -> Result<String> {
+    match expr {
+        ast::Expr::Value(ast::Value::SingleQuotedString(s))
+        | ast::Expr::Value(ast::Value::DoubleQuotedString(s)) => Ok(s.clone()),
+        ast::Expr::Identifier(ident) => Ok(ident.value.clone()),
+        other => Err(DataFusionError::Plan(format!(
+            "expected a string literal, got {}",
+            other
+        ))),
+    }
+}
+
+fn number_arg(expr: &ast::Expr) -> Result<f64> {
+    match expr {
+        ast::Expr::Value(ast::Value::Number(n, _)) => n
+            .parse()
+            .map_err(|_| DataFusionError::Plan(format!("invalid number {}", n))),
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Minus,
+            expr,
+        } => number_arg(expr).map(|v| -v),
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Plus,
+            expr,
+        } => number_arg(expr),
+        other => Err(DataFusionError::Plan(format!(
+            "expected a number literal, got {}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::*;
+    use crate::sql::knn::*;
+    use crate::test_util::{gen_record_batch, gen_vectors};
+    use datafusion::arrow::datatypes::Int64Type;
+
+    const DIM: usize = 8;
+    const CLUSTER_NUM: usize = 8;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_knn_sql() {
+        let vectors = gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM);
+        let batch = gen_record_batch(&vectors);
+
+        let accessor = accessor::from_arrow(batch.column(1)).unwrap();
+        let index = index::new(index::IndexType::IvfFlat, accessor);
+        index.write().await.train(&TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+
+        let knn = KnnFunction::new();
+        let table = IndexedTable::try_new(
+            batch,
+            "embedding",
+            index,
+            MetricType::L2,
+            SearchOption {
+                topk: 0,
+                params: SearchParams::Ivf(IvfSearchParams {
+                    nprobe: CLUSTER_NUM / 2,
+                    ..Default::default()
+                }),
+                refine_factor: None,
+            },
+        )
+        .unwrap();
+        knn.register_table("items", table);
+
+        let query = vectors
+            .get(3)
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT id, _distance FROM knn('items', 'embedding', [{}], {}) WHERE id >= 0",
+            query, CLUSTER_NUM
+        );
+        let ctx = SessionContext::new();
+        let batches = knn.sql(&ctx, &sql).await.unwrap().collect().await.unwrap();
+
+        let ids: Vec<_> = batches
+            .iter()
+            .flat_map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec())
+            .collect();
+        assert_eq!(ids.len(), CLUSTER_NUM);
+        assert!(
+            ids.iter().all(|id| *id as usize % CLUSTER_NUM == 3),
+            "ids: {:?}",
+            ids
+        );
+    }
+}
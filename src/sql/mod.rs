@@ -0,0 +1,32 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod build;
+pub mod exec;
+pub mod knn;
+pub mod optimizer;
+pub mod udf;
+
+use datafusion::execution::{context::SessionState, runtime_env::RuntimeEnv};
+use datafusion::prelude::{SessionConfig, SessionContext};
+use std::sync::Arc;
+
+// creates a session context with the vector functions and the ANN optimizer rule
+pub fn new_context(config: SessionConfig) -> SessionContext {
+    let state = SessionState::with_config_rt(config, Arc::new(RuntimeEnv::default()))
+        .add_physical_optimizer_rule(Arc::new(optimizer::AnnTopKRule::new()));
+    let ctx = SessionContext::with_state(state);
+    udf::register_udfs(&ctx);
+    ctx
+}
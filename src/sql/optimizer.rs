@@ -0,0 +1,300 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::exec::{AnnScanExec, IndexedScanExec};
+use super::udf;
+use crate::metric::MetricType;
+use datafusion::common::tree_node::{Transformed, TreeNode};
+use datafusion::config::ConfigOptions;
+use datafusion::error::Result;
+use datafusion::physical_expr::ScalarFunctionExpr;
+use datafusion::physical_optimizer::PhysicalOptimizerRule;
+use datafusion::physical_plan::{
+    coalesce_batches::CoalesceBatchesExec,
+    coalesce_partitions::CoalescePartitionsExec,
+    expressions::{Column, Literal},
+    projection::ProjectionExec,
+    repartition::RepartitionExec,
+    sorts::sort::SortExec,
+    ExecutionPlan, PhysicalExpr,
+};
+use std::sync::Arc;
+
+// rewrites
+//   ORDER BY l2_distance(column, [query vector]) LIMIT k
+// over an IndexedTable into a top-k scan of the ANN index,
+// the full sort is kept for the plans that may drop rows between the sort and the scan (e.g. filters),
+// or the tables without index on the column
+#[derive(Default)]
+pub struct AnnTopKRule {}
+
+impl AnnTopKRule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn rewrite(&self, plan: Arc<dyn ExecutionPlan>) -> Result<Transformed<Arc<dyn ExecutionPlan>>> {
+        let sort = match plan.as_any().downcast_ref::<SortExec>() {
+            Some(sort) => sort,
+            None => return Ok(Transformed::No(plan)),
+        };
+        let (k, sort_expr) = match (sort.fetch(), sort.expr()) {
+            (Some(k), [sort_expr]) if !sort_expr.options.descending => (k, sort_expr),
+            _ => return Ok(Transformed::No(plan)),
+        };
+
+        let (function, input) = match find_distance_function(&sort_expr.expr, sort.input().clone())
+        {
+            Some(found) => found,
+            None => return Ok(Transformed::No(plan)),
+        };
+        let function = function
+            .as_any()
+            .downcast_ref::<ScalarFunctionExpr>()
+            .unwrap();
+        let (column, query) = match function.args() {
+            [column, query] => (column, query),
+            _ => return Ok(Transformed::No(plan)),
+        };
+        let (column, query) = match (
+            column.as_any().downcast_ref::<Column>(),
+            query.as_any().downcast_ref::<Literal>(),
+        ) {
+            (Some(column), Some(query)) => (column, query),
+            _ => return Ok(Transformed::No(plan)),
+        };
+
+        let scan = match find_indexed_scan(input, column.index()) {
+            Some(scan) => scan,
+            None => return Ok(Transformed::No(plan)),
+        };
+        let scan = scan.as_any().downcast_ref::<IndexedScanExec>().unwrap();
+        let table = scan.table();
+        if metric_function(table.metric_type()) != Some(function.name()) {
+            return Ok(Transformed::No(plan));
+        }
+        let query = match udf::scalar_to_vector(query.value())? {
+            Some(query) if query.len() == table.dim() => query,
+            _ => return Ok(Transformed::No(plan)),
+        };
+
+        let ann_scan = Arc::new(AnnScanExec::try_new(
+            table.clone(),
+            scan.projection().cloned(),
+            query,
+            k,
+        )?);
+        let input = replace_scan(sort.input().clone(), ann_scan)?;
+        Ok(Transformed::Yes(plan.with_new_children(vec![input])?))
+    }
+}
+
+impl PhysicalOptimizerRule for AnnTopKRule {
+    fn optimize(
+        &self,
+        plan: Arc<dyn ExecutionPlan>,
+        _config: &ConfigOptions,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        plan.transform_down(&|plan| self.rewrite(plan))
+    }
+
+    fn name(&self) -> &str {
+        "ann_topk"
+    }
+
+    fn schema_check(&self) -> bool {
+        true
+    }
+}
+
+fn metric_function(metric_type: MetricType) -> Option<&'static str> {
+    match metric_type {
+        MetricType::L2 => Some(udf::L2_DISTANCE),
+        MetricType::None => None,
+    }
+}
+
+// the nodes which output all the rows of their input, with the same schema
+fn is_pass_through(plan: &dyn ExecutionPlan) -> bool {
+    let plan = plan.as_any();
+    plan.is::<CoalesceBatchesExec>()
+        || plan.is::<CoalescePartitionsExec>()
+        || plan.is::<RepartitionExec>()
+}
+
+// returns the distance function of the sort expression,
+// and the plan whose schema the function arguments refer to
+fn find_distance_function(
+    expr: &Arc<dyn PhysicalExpr>,
+    mut input: Arc<dyn ExecutionPlan>,
+) -> Option<(Arc<dyn PhysicalExpr>, Arc<dyn ExecutionPlan>)> {
+    if expr.as_any().is::<ScalarFunctionExpr>() {
+        return Some((expr.clone(), input));
+    }
+
+    // the distance is computed by a projection, e.g. ORDER BY the alias of the distance
+    let column = expr.as_any().downcast_ref::<Column>()?;
+    while is_pass_through(input.as_ref()) {
+        input = input.children().pop()?;
+    }
+    let projection = input.as_any().downcast_ref::<ProjectionExec>()?;
+    let (expr, _) = projection.expr().get(column.index())?;
+    expr.as_any()
+        .is::<ScalarFunctionExpr>()
+        .then(|| (expr.clone(), projection.input().clone()))
+}
+
+// follows the column down to the IndexedScanExec,
+// the column must be the indexed column of the table
+fn find_indexed_scan(
+    mut plan: Arc<dyn ExecutionPlan>,
+    mut column: usize,
+) -> Option<Arc<dyn ExecutionPlan>> {
+    loop {
+        if let Some(scan) = plan.as_any().downcast_ref::<IndexedScanExec>() {
+            let is_indexed = scan.schema().field(column).name() == scan.table().column();
+            return is_indexed.then_some(plan);
+        }
+
+        if let Some(projection) = plan.as_any().downcast_ref::<ProjectionExec>() {
+            let (expr, _) = projection.expr().get(column)?;
+            column = expr.as_any().downcast_ref::<Column>()?.index();
+            plan = projection.input().clone();
+        } else if is_pass_through(plan.as_ref()) {
+            plan = plan.children().pop()?;
+        } else {
+            return None;
+        }
+    }
+}
+
+fn replace_scan(
+    plan: Arc<dyn ExecutionPlan>,
+    ann_scan: Arc<AnnScanExec>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if plan.as_any().is::<IndexedScanExec>() {
+        return Ok(ann_scan);
+    }
+
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| replace_scan(child, ann_scan.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    plan.with_new_children(children)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::*;
+    use crate::sql::{knn::IndexedTable, new_context, optimizer::*};
+    use crate::test_util::{gen_record_batch, gen_vectors};
+    use crate::*;
+    use datafusion::arrow::array::AsArray;
+    use datafusion::arrow::datatypes::Int64Type;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use datafusion::prelude::SessionConfig;
+
+    const DIM: usize = 8;
+    const CLUSTER_NUM: usize = 8;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    #[tokio::test]
+    async fn test_ann_topk_rule() {
+        let vectors = gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM);
+        let batch = gen_record_batch(&vectors);
+        let accessor = accessor::from_arrow(batch.column(1)).unwrap();
+        let index = index::new(index::IndexType::IvfFlat, accessor);
+        index.write().await.train(&TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+        let table = IndexedTable::try_new(
+            batch,
+            "embedding",
+            index,
+            MetricType::L2,
+            SearchOption {
+                topk: 0,
+                params: SearchParams::Ivf(IvfSearchParams {
+                    nprobe: CLUSTER_NUM / 2,
+                    ..Default::default()
+                }),
+                refine_factor: None,
+            },
+        )
+        .unwrap();
+
+        let ctx = new_context(SessionConfig::new().with_target_partitions(4));
+        ctx.register_table("items", Arc::new(table)).unwrap();
+
+        let query = vectors
+            .get(3)
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let cases = [
+            (
+                format!(
+                    "SELECT id FROM items ORDER BY l2_distance(embedding, [{}]) LIMIT {}",
+                    query, CLUSTER_NUM
+                ),
+                true,
+            ),
+            (
+                format!(
+                    "SELECT id, l2_distance(embedding, [{}]) AS d FROM items ORDER BY d LIMIT {}",
+                    query, CLUSTER_NUM
+                ),
+                true,
+            ),
+            // the filter may drop the nearest rows, falls back to the full sort
+            (
+                format!(
+                    "SELECT id FROM items WHERE id >= 0 ORDER BY l2_distance(embedding, [{}]) LIMIT {}",
+                    query, CLUSTER_NUM
+                ),
+                false,
+            ),
+        ];
+
+        for (sql, indexed) in cases {
+            let explain = ctx
+                .sql(&format!("EXPLAIN {}", sql))
+                .await
+                .unwrap()
+                .collect()
+                .await
+                .unwrap();
+            let explain = pretty_format_batches(&explain).unwrap().to_string();
+            assert_eq!(explain.contains("AnnScanExec"), indexed, "{}", explain);
+
+            let batches = ctx.sql(&sql).await.unwrap().collect().await.unwrap();
+            let ids: Vec<_> = batches
+                .iter()
+                .flat_map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec())
+                .collect();
+            assert_eq!(ids.len(), CLUSTER_NUM);
+            assert!(
+                ids.iter().all(|id| *id as usize % CLUSTER_NUM == 3),
+                "ids: {:?}",
+                ids
+            );
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::metric;
+use datafusion::arrow::array::*;
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Float32Type};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_expr::{
+    ReturnTypeFunction, ScalarFunctionImplementation, ScalarUDF, Signature, Volatility,
+};
+use datafusion::physical_plan::ColumnarValue;
+use datafusion::prelude::SessionContext;
+use datafusion::scalar::ScalarValue;
+use std::{borrow::Cow, sync::Arc};
+
+pub const L2_DISTANCE: &str = "l2_distance";
+pub const INNER_PRODUCT: &str = "inner_product";
+pub const COSINE_SIMILARITY: &str = "cosine_similarity";
+
+pub fn register_udfs(ctx: &SessionContext) {
+    ctx.register_udf(l2_distance());
+    ctx.register_udf(inner_product());
+    ctx.register_udf(cosine_similarity());
+}
+
+// the euclidean distance, the indexes compare its square which has the same order
+pub fn l2_distance() -> ScalarUDF {
+    vector_udf(L2_DISTANCE, euclidean_distance)
+}
+
+pub(crate) fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    metric::l2_distance(a, b).sqrt()
+}
+
+pub fn inner_product() -> ScalarUDF {
+    vector_udf(INNER_PRODUCT, metric::inner_product)
+}
+
+pub fn cosine_similarity() -> ScalarUDF {
+    vector_udf(COSINE_SIMILARITY, metric::cosine_similarity)
+}
+
+// all vector functions take two vectors and return a Float32,
+// the vectors could be FixedSizeBinary (little-endian f32), FixedSizeList or List of floats
+fn vector_udf(name: &str, kernel: fn(&[f32], &[f32]) -> f32) -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float32)));
+    let fun: ScalarFunctionImplementation = Arc::new(move |args| evaluate(args, kernel));
+    ScalarUDF::new(
+        name,
+        &Signature::any(2, Volatility::Immutable),
+        &return_type,
+        &fun,
+    )
+}
+
+fn evaluate(args: &[ColumnarValue], kernel: fn(&[f32], &[f32]) -> f32) -> Result<ColumnarValue> {
+    if args.len() != 2 {
+        return Err(DataFusionError::Plan(format!(
+            "vector function expects 2 arguments, got {}",
+            args.len()
+        )));
+    }
+
+    let a = VectorColumn::try_new(&args[0])?;
+    let b = VectorColumn::try_new(&args[1])?;
+    if let (Some(a_dim), Some(b_dim)) = (a.dim, b.dim) {
+        if a_dim != b_dim {
+            return Err(DataFusionError::Execution(format!(
+                "vector dimension mismatch: {} vs {}",
+                a_dim, b_dim
+            )));
+        }
+    }
+
+    let num_rows = match (a.len(), b.len()) {
+        (None, None) => {
+            let value = a.get(0).zip(b.get(0)).map(|(a, b)| kernel(a, b));
+            return Ok(ColumnarValue::Scalar(ScalarValue::Float32(value)));
+        }
+        (Some(len), _) | (None, Some(len)) => len,
+    };
+
+    let result: Float32Array = (0..num_rows)
+        .map(|i| a.get(i).zip(b.get(i)).map(|(a, b)| kernel(a, b)))
+        .collect();
+    Ok(ColumnarValue::Array(Arc::new(result)))
+}
+
+// converts a literal vector, e.g. [0.1, 0.2, ...], returns None for null
+pub(crate) fn scalar_to_vector(scalar: &ScalarValue) -> Result<Option<Vec<f32>>> {
+    let value = ColumnarValue::Scalar(scalar.clone());
+    let vector = VectorColumn::try_new(&value)?;
+    Ok(vector.get(0).map(|v| v.to_vec()))
+}
+
+// a column of vectors flattened into contiguous f32 values,
+// borrows the input buffer when it's already a FixedSizeList<Float32>
+struct VectorColumn<'a> {
+    // None if all the vectors are null
+    dim: Option<usize>,
+    values: Cow<'a, [f32]>,
+    // None for a scalar vector, which is broadcast to every row
+    array: Option<&'a ArrayRef>,
+}
+
+impl<'a> VectorColumn<'a> {
+    fn try_new(value: &'a ColumnarValue) -> Result<Self> {
+        match value {
+            ColumnarValue::Array(array) => {
+                let (dim, values) = flatten(array.as_ref())?;
+                Ok(Self {
+                    dim,
+                    values,
+                    array: Some(array),
+                })
+            }
+            ColumnarValue::Scalar(scalar) => {
+                let array = scalar.to_array();
+                let (dim, values) = if array.is_null(0) {
+                    (None, Cow::Owned(Vec::new()))
+                } else {
+                    let (dim, values) = flatten(array.as_ref())?;
+                    (dim, Cow::Owned(values.into_owned()))
+                };
+                Ok(Self {
+                    dim,
+                    values,
+                    array: None,
+                })
+            }
+        }
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.array.map(|array| array.len())
+    }
+
+    fn get(&self, index: usize) -> Option<&[f32]> {
+        let dim = self.dim?;
+        let index = match self.array {
+            Some(array) if array.is_null(index) => return None,
+            Some(_) => index,
+            None => 0,
+        };
+        Some(&self.values[index * dim..(index + 1) * dim])
+    }
+}
+
+fn flatten(array: &dyn Array) -> Result<(Option<usize>, Cow<'_, [f32]>)> {
+    match array.data_type() {
+        DataType::FixedSizeList(_, dim) => {
+            let array = array.as_any().downcast_ref::<FixedSizeListArray>().unwrap();
+            let dim = *dim as usize;
+            if array.is_empty() {
+                return Ok((Some(dim), Cow::Owned(Vec::new())));
+            }
+
+            let start = array.value_offset(0) as usize;
+            let end = start + array.len() * dim;
+            let values = array.values();
+            let values = match values.data_type() {
+                DataType::Float32 => {
+                    Cow::Borrowed(&values.as_primitive::<Float32Type>().values()[start..end])
+                }
+                _ => Cow::Owned(to_f32(&values.slice(start, end - start))?),
+            };
+            Ok((Some(dim), values))
+        }
+
+        DataType::FixedSizeBinary(len) => {
+            let dim = *len as usize / std::mem::size_of::<f32>();
+            if dim * std::mem::size_of::<f32>() != *len as usize {
+                return Err(DataFusionError::Execution(format!(
+                    "FixedSizeBinary({}) is not a valid f32 vector",
+                    len
+                )));
+            }
+
+            let array = array
+                .as_any()
+                .downcast_ref::<FixedSizeBinaryArray>()
+                .unwrap();
+            let mut values = Vec::with_capacity(array.len() * dim);
+            for i in 0..array.len() {
+                values.extend(
+                    array
+                        .value(i)
+                        .array_chunks::<4>()
+                        .map(|bytes| f32::from_le_bytes(*bytes)),
+                );
+            }
+            Ok((Some(dim), Cow::Owned(values)))
+        }
+
+        DataType::List(_) => flatten_list(array.as_list::<i32>()),
+        DataType::LargeList(_) => flatten_list(array.as_list::<i64>()),
+
+        other => Err(DataFusionError::Execution(format!(
+            "unsupported vector type {}",
+            other
+        ))),
+    }
+}
+
+// all the non-null lists must have the same length,
+// the null lists are filled with zeros
+fn flatten_list<O: OffsetSizeTrait>(
+    array: &GenericListArray<O>,
+) -> Result<(Option<usize>, Cow<'_, [f32]>)> {
+    let dim = match (0..array.len()).find(|i| array.is_valid(*i)) {
+        Some(i) => array.value_length(i).as_usize(),
+        None => return Ok((None, Cow::Owned(Vec::new()))),
+    };
+
+    let mut values = Vec::with_capacity(array.len() * dim);
+    for i in 0..array.len() {
+        if array.is_null(i) {
+            values.resize(values.len() + dim, 0f32);
+            continue;
+        }
+
+        let vector = array.value(i);
+        if vector.len() != dim {
+            return Err(DataFusionError::Execution(format!(
+                "vector dimension mismatch: {} vs {}",
+                vector.len(),
+                dim
+            )));
+        }
+        values.extend(to_f32(&vector)?);
+    }
+    Ok((Some(dim), Cow::Owned(values)))
+}
+
+fn to_f32(array: &ArrayRef) -> Result<Vec<f32>> {
+    let array = cast(array, &DataType::Float32)?;
+    Ok(array.as_primitive::<Float32Type>().values().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sql::udf::*;
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::datasource::MemTable;
+
+    #[tokio::test]
+    async fn test_vector_udfs() {
+        let embedding = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![
+                Some(vec![Some(0.0), Some(0.0)]),
+                Some(vec![Some(3.0), Some(4.0)]),
+                Some(vec![Some(1.0), Some(1.0)]),
+            ],
+            2,
+        );
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "embedding",
+            embedding.data_type().clone(),
+            true,
+        )]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(embedding)]).unwrap();
+
+        let ctx = SessionContext::new();
+        register_udfs(&ctx);
+        let table = MemTable::try_new(schema, vec![vec![batch]]).unwrap();
+        ctx.register_table("items", Arc::new(table)).unwrap();
+
+        let batches = ctx
+            .sql(
+                "SELECT l2_distance(embedding, [0.0, 0.0]), inner_product(embedding, [1.0, 1.0]), \
+                cosine_similarity(embedding, [1, 1]) FROM items",
+            )
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        let columns: Vec<_> = (0..3)
+            .map(|i| {
+                batches[0]
+                    .column(i)
+                    .as_primitive::<Float32Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(columns[0], vec![0.0, 5.0, 2f32.sqrt()]);
+        assert_eq!(columns[1], vec![0.0, 7.0, 2.0]);
+        assert_eq!(columns[2][0], 0.0);
+        assert!((columns[2][2] - 1.0).abs() < 1e-6);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::accessor::MemoryVectorAccessor;
 use crate::VectorAccessor;
+use datafusion::arrow::array::*;
+use datafusion::arrow::record_batch::RecordBatch;
+use std::sync::Arc;
 
 pub fn gen_vectors(n: usize, dim: usize, cluster_num: usize) -> impl VectorAccessor {
     let centroids: Vec<_> = (0..cluster_num).map(|_| gen_floats(dim)).collect();
@@ -15,3 +18,24 @@ pub fn gen_vectors(n: usize, dim: usize, cluster_num: usize) -> impl VectorAcces
 pub fn gen_floats(n: usize) -> Vec<f32> {
     (0..n).map(|_| rand::random()).collect()
 }
+
+// generates a record batch with columns:
+// id: Int64, the offset of the vector
+// embedding: FixedSizeBinary, the vector in little-endian f32
+pub fn gen_record_batch(vectors: &dyn VectorAccessor) -> RecordBatch {
+    let ids = Int64Array::from_iter_values(0..vectors.len() as i64);
+    let embedding = FixedSizeBinaryArray::try_from_iter((0..vectors.len()).map(|i| {
+        vectors
+            .get(i)
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>()
+    }))
+    .unwrap();
+
+    RecordBatch::try_from_iter(vec![
+        ("id", Arc::new(ids) as ArrayRef),
+        ("embedding", Arc::new(embedding) as ArrayRef),
+    ])
+    .unwrap()
+}
@@ -0,0 +1,350 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// dense row-major matrices in f64
+
+// c = a * b^T, a is m x k, b is n x k, c is m x n
+pub fn matmul_transposed(a: &[f64], b: &[f64], m: usize, n: usize, k: usize) -> Vec<f64> {
+    let mut c = vec![0f64; m * n];
+    for i in 0..m {
+        let row = &a[i * k..(i + 1) * k];
+        for j in 0..n {
+            c[i * n + j] = row
+                .iter()
+                .zip(&b[j * k..(j + 1) * k])
+                .map(|(x, y)| x * y)
+                .sum();
+        }
+    }
+    c
+}
+
+pub fn transpose(a: &[f64], m: usize, n: usize) -> Vec<f64> {
+    let mut t = vec![0f64; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            t[j * m + i] = a[i * n + j];
+        }
+    }
+    t
+}
+
+// eigen decomposition of the n x n symmetric matrix,
+// returns the eigenvalues in descending order and the corresponding eigenvectors as rows,
+// by Householder tridiagonalization and the QL algorithm (tred2 and tql2 of EISPACK)
+pub fn symmetric_eigen(a: &[f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut v = a.to_vec();
+    let mut d = vec![0f64; n];
+    let mut e = vec![0f64; n];
+    if n == 0 {
+        return (d, v);
+    }
+
+    tred2(&mut v, &mut d, &mut e, n);
+    tql2(&mut v, &mut d, &mut e, n);
+
+    // the eigenvectors are the columns of v
+    let mut order: Vec<_> = (0..n).collect();
+    order.sort_by(|i, j| d[*j].total_cmp(&d[*i]));
+    let values = order.iter().map(|i| d[*i]).collect();
+    let mut vectors = vec![0f64; n * n];
+    for (row, i) in order.iter().enumerate() {
+        for k in 0..n {
+            vectors[row * n + k] = v[k * n + i];
+        }
+    }
+    (values, vectors)
+}
+
+#[allow(clippy::needless_range_loop)]
+fn tred2(v: &mut [f64], d: &mut [f64], e: &mut [f64], n: usize) {
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+    }
+
+    for i in (1..n).rev() {
+        let mut scale = 0f64;
+        let mut h = 0f64;
+        for k in 0..i {
+            scale += d[k].abs();
+        }
+
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+                v[j * n + i] = 0.0;
+            }
+        } else {
+            // generate the Householder vector
+            for k in 0..i {
+                d[k] /= scale;
+                h += d[k] * d[k];
+            }
+            let mut f = d[i - 1];
+            let mut g = h.sqrt();
+            if f > 0.0 {
+                g = -g;
+            }
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            for j in 0..i {
+                e[j] = 0.0;
+            }
+
+            // apply the similarity transformation to the remaining columns
+            for j in 0..i {
+                f = d[j];
+                v[j * n + i] = f;
+                g = e[j] + v[j * n + j] * f;
+                for k in j + 1..i {
+                    g += v[k * n + j] * d[k];
+                    e[k] += v[k * n + j] * f;
+                }
+                e[j] = g;
+            }
+            f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                f = d[j];
+                g = e[j];
+                for k in j..i {
+                    v[k * n + j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+
+    // accumulate the transformations
+    for i in 0..n - 1 {
+        v[(n - 1) * n + i] = v[i * n + i];
+        v[i * n + i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k * n + i + 1] / h;
+            }
+            for j in 0..=i {
+                let mut g = 0f64;
+                for k in 0..=i {
+                    g += v[k * n + i + 1] * v[k * n + j];
+                }
+                for k in 0..=i {
+                    v[k * n + j] -= g * d[k];
+                }
+            }
+        }
+        for k in 0..=i {
+            v[k * n + i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+        v[(n - 1) * n + j] = 0.0;
+    }
+    v[(n - 1) * n + n - 1] = 1.0;
+    e[0] = 0.0;
+}
+
+#[allow(clippy::needless_range_loop)]
+fn tql2(v: &mut [f64], d: &mut [f64], e: &mut [f64], n: usize) {
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    e[n - 1] = 0.0;
+
+    let mut f = 0f64;
+    let mut tst1 = 0f64;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        // find the small subdiagonal element
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n - 1 && e[m].abs() > eps * tst1 {
+            m += 1;
+        }
+
+        if m > l {
+            loop {
+                // compute the implicit shift
+                let mut g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for i in l + 2..n {
+                    d[i] -= h;
+                }
+                f += h;
+
+                // implicit QL transformation
+                p = d[m];
+                let mut c = 1f64;
+                let mut c2 = c;
+                let mut c3 = c;
+                let el1 = e[l + 1];
+                let mut s = 0f64;
+                let mut s2 = 0f64;
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+
+                    // accumulate the transformation
+                    for k in 0..n {
+                        h = v[k * n + i + 1];
+                        v[k * n + i + 1] = s * v[k * n + i] + c * h;
+                        v[k * n + i] = c * v[k * n + i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+}
+
+// orthonormalizes the rows of the n x n matrix by the modified Gram-Schmidt process,
+// the rows must be linearly independent
+pub fn orthonormalize(a: &mut [f64], n: usize) {
+    for i in 0..n {
+        for j in 0..i {
+            let (prev, rest) = a.split_at_mut(i * n);
+            let prev = &prev[j * n..(j + 1) * n];
+            let row = &mut rest[..n];
+            let dot: f64 = row.iter().zip(prev).map(|(x, y)| x * y).sum();
+            row.iter_mut().zip(prev).for_each(|(x, y)| *x -= dot * y);
+        }
+
+        let row = &mut a[i * n..(i + 1) * n];
+        let norm = row.iter().map(|x| x * x).sum::<f64>().sqrt();
+        row.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// the orthogonal matrix r minimizing ||r * x - y||, where m = y * x^T is n x n,
+// r = u * v^T for the SVD m = u * s * v^T
+pub fn procrustes(m: &[f64], n: usize) -> Vec<f64> {
+    // m^T * m = v * s^2 * v^T
+    let mt = transpose(m, n, n);
+    let mtm = matmul_transposed(&mt, &mt, n, n, n);
+    let (values, v) = symmetric_eigen(&mtm, n);
+
+    // the columns of u = m * v * s^-1, stored as rows
+    let mut u = matmul_transposed(&v, m, n, n, n);
+    let max = values.first().copied().unwrap_or(0.0).max(0.0).sqrt();
+    for (i, value) in values.iter().enumerate() {
+        let s = value.max(0.0).sqrt();
+        let row = &mut u[i * n..(i + 1) * n];
+        if s > max * 1e-10 {
+            row.iter_mut().for_each(|x| *x /= s);
+        } else {
+            // degenerate singular value, any unit vector orthogonal to the others works
+            row.iter_mut()
+                .for_each(|x| *x = rand::random::<f64>() - 0.5);
+        }
+    }
+    orthonormalize(&mut u, n);
+
+    // r = u * v^T = sum of the outer products of the columns
+    let ut = transpose(&u, n, n);
+    let vt = transpose(&v, n, n);
+    matmul_transposed(&ut, &vt, n, n, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::linalg::*;
+
+    const N: usize = 16;
+
+    fn random_matrix(n: usize) -> Vec<f64> {
+        (0..n * n).map(|_| rand::random::<f64>() - 0.5).collect()
+    }
+
+    fn assert_orthogonal(r: &[f64], n: usize) {
+        let rrt = matmul_transposed(r, r, n, n, n);
+        for i in 0..n {
+            for j in 0..n {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((rrt[i * n + j] - expected).abs() < 1e-8, "{:?}", rrt);
+            }
+        }
+    }
+
+    #[test]
+    fn test_symmetric_eigen() {
+        let b = random_matrix(N);
+        let a = matmul_transposed(&b, &b, N, N, N);
+        let (values, vectors) = symmetric_eigen(&a, N);
+
+        assert!(values.windows(2).all(|w| w[0] >= w[1]));
+        assert_orthogonal(&vectors, N);
+        for i in 0..N {
+            let v = &vectors[i * N..(i + 1) * N];
+            for r in 0..N {
+                let av: f64 = (0..N).map(|k| a[r * N + k] * v[k]).sum();
+                assert!((av - values[i] * v[r]).abs() < 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_procrustes() {
+        let mut rotation = random_matrix(N);
+        orthonormalize(&mut rotation, N);
+        assert_orthogonal(&rotation, N);
+
+        // y = rotation * x, so m = y * x^T = rotation * x * x^T
+        let x = random_matrix(N);
+        let xxt = matmul_transposed(&x, &x, N, N, N);
+        let m = matmul_transposed(&rotation, &transpose(&xxt, N, N), N, N, N);
+        let r = procrustes(&m, N);
+        for (a, b) in r.iter().zip(&rotation) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod linalg;
+pub mod normalize;
+pub mod opq;
+pub mod pca;
+pub mod rotation;
+
+use crate::{accessor::MemoryVectorAccessor, error::Result, metric, VectorAccessor};
+use async_trait::async_trait;
+use std::{cmp, io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// the transforms are trained on the first vectors only
+pub const MAX_TRAIN_SIZE: usize = 65536;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformType {
+    L2Normalize = 1,
+    Pca = 2,
+    RandomRotation = 3,
+    Opq = 4,
+}
+
+impl TryFrom<u8> for TransformType {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            1 => Ok(TransformType::L2Normalize),
+            2 => Ok(TransformType::Pca),
+            3 => Ok(TransformType::RandomRotation),
+            4 => Ok(TransformType::Opq),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown transform type {}", value),
+            )),
+        }
+    }
+}
+
+// a trainable transform applied to the vectors before they are indexed,
+// and to the query vectors before searching
+#[async_trait]
+pub trait VectorTransform: Send + Sync {
+    fn transform_type(&self) -> TransformType;
+
+    fn input_dim(&self) -> usize;
+
+    fn output_dim(&self) -> usize;
+
+    fn train(&mut self, vectors: Arc<dyn VectorAccessor>) -> Result<()>;
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32>;
+
+    async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()>;
+
+    async fn deserialize(&mut self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()>;
+}
+
+// creates an untrained transform of the given type, to be deserialized
+pub fn new(typ: TransformType) -> Box<dyn VectorTransform> {
+    match typ {
+        TransformType::L2Normalize => Box::<normalize::L2Normalize>::default(),
+        TransformType::Pca => Box::<pca::Pca>::default(),
+        TransformType::RandomRotation => Box::<rotation::RandomRotation>::default(),
+        TransformType::Opq => Box::<opq::Opq>::default(),
+    }
+}
+
+// applies the transform to all vectors
+pub fn transform_vectors(
+    transform: &dyn VectorTransform,
+    vectors: &dyn VectorAccessor,
+) -> MemoryVectorAccessor {
+    let mut data = Vec::with_capacity(vectors.len() * transform.output_dim());
+    for i in 0..vectors.len() {
+        data.extend(transform.apply(vectors.get(i)));
+    }
+    MemoryVectorAccessor::new(transform.output_dim(), data)
+}
+
+pub(crate) fn train_size(vectors: &dyn VectorAccessor) -> usize {
+    cmp::min(vectors.len(), MAX_TRAIN_SIZE)
+}
+
+// y = matrix * x, the matrix is rows x x.len() in row-major
+pub(crate) fn mat_vec(matrix: &[f32], rows: usize, x: &[f32]) -> Vec<f32> {
+    matrix
+        .chunks_exact(x.len())
+        .take(rows)
+        .map(|row| metric::inner_product(row, x))
+        .collect()
+}
+
+pub(crate) async fn write_floats(
+    writer: &mut (dyn AsyncWrite + Send + Unpin),
+    floats: &[f32],
+) -> io::Result<()> {
+    writer.write_u64_le(floats.len() as u64).await?;
+    for v in floats {
+        writer.write_f32_le(*v).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn read_floats(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+) -> io::Result<Vec<f32>> {
+    let len = reader.read_u64_le().await? as usize;
+    let mut floats = Vec::with_capacity(len);
+    for _ in 0..len {
+        floats.push(reader.read_f32_le().await?);
+    }
+    Ok(floats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::gen_vectors;
+    use crate::transform::*;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = CLUSTER_NUM * CLUSTER_NUM;
+
+    async fn serde(transform: &dyn VectorTransform) -> Box<dyn VectorTransform> {
+        let mut data = Vec::new();
+        transform.serialize(&mut data).await.unwrap();
+        let mut deserialized = new(transform.transform_type());
+        deserialized
+            .deserialize(&mut std::io::Cursor::new(data))
+            .await
+            .unwrap();
+        deserialized
+    }
+
+    fn assert_preserves_distances(transform: &dyn VectorTransform, vectors: &dyn VectorAccessor) {
+        for i in 1..vectors.len() {
+            let (a, b) = (vectors.get(i - 1), vectors.get(i));
+            let expected = metric::l2_distance(a, b);
+            let distance = metric::l2_distance(&transform.apply(a), &transform.apply(b));
+            assert!((expected - distance).abs() < 1e-3 * expected.max(1.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_l2_normalize() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let mut transform = normalize::L2Normalize::new(DIM);
+        transform.train(vectors.clone()).unwrap();
+
+        let transform = serde(&transform).await;
+        assert_eq!(transform.output_dim(), DIM);
+        for i in 0..vectors.len() {
+            let v = transform.apply(vectors.get(i));
+            assert!((metric::inner_product(&v, &v) - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(transform.apply(&[0.0; DIM]), vec![0.0; DIM]);
+    }
+
+    #[tokio::test]
+    async fn test_pca() {
+        // the vectors lie in a 2-dimensional affine subspace
+        let basis: Vec<_> = (0..2).map(|_| crate::test_util::gen_floats(DIM)).collect();
+        let offset = crate::test_util::gen_floats(DIM);
+        let mut data = Vec::with_capacity(DATASET_SIZE * DIM);
+        for _ in 0..DATASET_SIZE {
+            let (a, b) = (rand::random::<f32>(), rand::random::<f32>());
+            data.extend((0..DIM).map(|i| offset[i] + a * basis[0][i] + b * basis[1][i]));
+        }
+        let vectors = Arc::new(MemoryVectorAccessor::new(DIM, data));
+
+        assert!(pca::Pca::new(DIM, DIM + 1).is_err());
+        let mut transform = pca::Pca::new(DIM, 2).unwrap();
+        transform.train(vectors.clone()).unwrap();
+
+        let transform = serde(&transform).await;
+        assert_eq!(transform.input_dim(), DIM);
+        assert_eq!(transform.output_dim(), 2);
+        assert_preserves_distances(transform.as_ref(), vectors.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_random_rotation() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        let mut transform = rotation::RandomRotation::new(DIM);
+        transform.train(vectors.clone()).unwrap();
+
+        let transform = serde(&transform).await;
+        assert_preserves_distances(transform.as_ref(), vectors.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_opq() {
+        let vectors = Arc::new(gen_vectors(DATASET_SIZE, DIM, CLUSTER_NUM));
+        assert!(opq::Opq::new(DIM, 5).is_err());
+        let mut transform = opq::Opq::new(DIM, 4).unwrap();
+        transform.set_nbits(4).unwrap();
+        transform.set_iteration_num(4);
+        transform.train(vectors.clone()).unwrap();
+
+        let transform = serde(&transform).await;
+        assert_eq!(transform.output_dim(), DIM);
+        assert_preserves_distances(transform.as_ref(), vectors.as_ref());
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{TransformType, VectorTransform};
+use crate::{error::Result, metric, VectorAccessor};
+use async_trait::async_trait;
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// scales the vectors to unit length, so that L2 distance ranks like cosine similarity
+#[derive(Default)]
+pub struct L2Normalize {
+    dim: usize,
+}
+
+impl L2Normalize {
+    pub fn new(dim: usize) -> Self {
+        Self { dim }
+    }
+}
+
+#[async_trait]
+impl VectorTransform for L2Normalize {
+    fn transform_type(&self) -> TransformType {
+        TransformType::L2Normalize
+    }
+
+    fn input_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn output_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn train(&mut self, _vectors: Arc<dyn VectorAccessor>) -> Result<()> {
+        Ok(())
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        let norm = metric::inner_product(vector, vector).sqrt();
+        if norm == 0.0 {
+            return vector.to_vec();
+        }
+        vector.iter().map(|v| v / norm).collect()
+    }
+
+    async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.dim as u32).await
+    }
+
+    async fn deserialize(&mut self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        self.dim = reader.read_u32_le().await? as usize;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{linalg, rotation, TransformType, VectorTransform};
+use crate::{
+    accessor::MemoryVectorAccessor,
+    error::{Error, Result},
+    index::pq::ProductQuantizer,
+    VectorAccessor,
+};
+use async_trait::async_trait;
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// the k-means iterations of the intermediate product quantizers
+const PQ_ITERATION_NUM: usize = 4;
+
+// the rotation minimizing the product quantization error of the rotated vectors,
+// trained by alternating between the codebooks and the rotation
+#[derive(Default)]
+pub struct Opq {
+    dim: usize,
+    m: usize,
+    nbits: usize,
+    iteration_num: usize,
+    // dim x dim
+    rotation: Vec<f32>,
+}
+
+impl Opq {
+    pub fn new(dim: usize, m: usize) -> Result<Self> {
+        // validates the arguments
+        ProductQuantizer::new(dim, m, 8)?;
+
+        Ok(Self {
+            dim,
+            m,
+            nbits: 8,
+            iteration_num: 25,
+            rotation: Vec::new(),
+        })
+    }
+
+    pub fn set_nbits(&mut self, nbits: usize) -> Result<()> {
+        ProductQuantizer::new(self.dim, self.m, nbits)?;
+        self.nbits = nbits;
+        Ok(())
+    }
+
+    pub fn set_iteration_num(&mut self, iteration_num: usize) {
+        self.iteration_num = iteration_num;
+    }
+
+    pub fn m(&self) -> usize {
+        self.m
+    }
+}
+
+#[async_trait]
+impl VectorTransform for Opq {
+    fn transform_type(&self) -> TransformType {
+        TransformType::Opq
+    }
+
+    fn input_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn output_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn train(&mut self, vectors: Arc<dyn VectorAccessor>) -> Result<()> {
+        let dim = self.dim;
+        let n = super::train_size(vectors.as_ref());
+        if n < 1 << self.nbits {
+            return Err(Error::InvalidArgument(format!(
+                "at least {} vectors are required to train opq, got {}",
+                1 << self.nbits,
+                n
+            )));
+        }
+
+        let mut rotation = rotation::random_orthogonal(dim);
+        for _ in 0..self.iteration_num {
+            let matrix: Vec<_> = rotation.iter().map(|v| *v as f32).collect();
+            let mut rotated = Vec::with_capacity(n * dim);
+            for i in 0..n {
+                rotated.extend(super::mat_vec(&matrix, dim, vectors.get(i)));
+            }
+            let rotated = Arc::new(MemoryVectorAccessor::new(dim, rotated));
+
+            let mut pq = ProductQuantizer::new(dim, self.m, self.nbits)?;
+            pq.train(rotated.clone(), PQ_ITERATION_NUM)?;
+
+            // m = sum of reconstructed(y) * x^T
+            let mut m = vec![0f64; dim * dim];
+            let mut code = vec![0u8; pq.code_size()];
+            let mut reconstructed = vec![0f32; dim];
+            for i in 0..n {
+                pq.encode(rotated.get(i), &mut code);
+                pq.decode(&code, &mut reconstructed);
+                let x = vectors.get(i);
+                for (r, y) in reconstructed.iter().enumerate() {
+                    let row = &mut m[r * dim..(r + 1) * dim];
+                    for (c, x) in x.iter().enumerate() {
+                        row[c] += (*y as f64) * (*x as f64);
+                    }
+                }
+            }
+
+            rotation = linalg::procrustes(&m, dim);
+        }
+
+        self.rotation = rotation.iter().map(|v| *v as f32).collect();
+        Ok(())
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        super::mat_vec(&self.rotation, self.dim, vector)
+    }
+
+    async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.dim as u32).await?;
+        writer.write_u32_le(self.m as u32).await?;
+        writer.write_u8(self.nbits as u8).await?;
+        writer.write_u32_le(self.iteration_num as u32).await?;
+        super::write_floats(writer, &self.rotation).await
+    }
+
+    async fn deserialize(&mut self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        self.dim = reader.read_u32_le().await? as usize;
+        self.m = reader.read_u32_le().await? as usize;
+        self.nbits = reader.read_u8().await? as usize;
+        self.iteration_num = reader.read_u32_le().await? as usize;
+        self.rotation = super::read_floats(reader).await?;
+        if self.rotation.len() != self.dim * self.dim {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "opq rotation size mismatch",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{linalg, TransformType, VectorTransform};
+use crate::{
+    error::{Error, Result},
+    VectorAccessor,
+};
+use async_trait::async_trait;
+use std::{io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// projects the centered vectors onto the principal components
+// with the largest variances
+#[derive(Default)]
+pub struct Pca {
+    input_dim: usize,
+    output_dim: usize,
+    mean: Vec<f32>,
+    // output_dim x input_dim, the principal components as rows
+    components: Vec<f32>,
+}
+
+impl Pca {
+    pub fn new(input_dim: usize, output_dim: usize) -> Result<Self> {
+        if output_dim == 0 || output_dim > input_dim {
+            return Err(Error::InvalidArgument(format!(
+                "can't reduce {}-d vectors to {}-d",
+                input_dim, output_dim
+            )));
+        }
+
+        Ok(Self {
+            input_dim,
+            output_dim,
+            mean: Vec::new(),
+            components: Vec::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl VectorTransform for Pca {
+    fn transform_type(&self) -> TransformType {
+        TransformType::Pca
+    }
+
+    fn input_dim(&self) -> usize {
+        self.input_dim
+    }
+
+    fn output_dim(&self) -> usize {
+        self.output_dim
+    }
+
+    fn train(&mut self, vectors: Arc<dyn VectorAccessor>) -> Result<()> {
+        let dim = self.input_dim;
+        let n = super::train_size(vectors.as_ref());
+        if n == 0 {
+            return Err(Error::InvalidArgument(
+                "no vectors to train pca".to_string(),
+            ));
+        }
+
+        let mut mean = vec![0f64; dim];
+        for i in 0..n {
+            for (m, v) in mean.iter_mut().zip(vectors.get(i)) {
+                *m += *v as f64;
+            }
+        }
+        mean.iter_mut().for_each(|m| *m /= n as f64);
+
+        // the upper triangle of the covariance matrix
+        let mut covariance = vec![0f64; dim * dim];
+        let mut centered = vec![0f64; dim];
+        for i in 0..n {
+            for ((c, v), m) in centered.iter_mut().zip(vectors.get(i)).zip(&mean) {
+                *c = *v as f64 - m;
+            }
+            for r in 0..dim {
+                let row = &mut covariance[r * dim..(r + 1) * dim];
+                for c in r..dim {
+                    row[c] += centered[r] * centered[c];
+                }
+            }
+        }
+        for r in 0..dim {
+            for c in r..dim {
+                covariance[r * dim + c] /= n as f64;
+                covariance[c * dim + r] = covariance[r * dim + c];
+            }
+        }
+
+        let (_, eigenvectors) = linalg::symmetric_eigen(&covariance, dim);
+        self.mean = mean.iter().map(|m| *m as f32).collect();
+        self.components = eigenvectors[..self.output_dim * dim]
+            .iter()
+            .map(|v| *v as f32)
+            .collect();
+        Ok(())
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        let centered: Vec<_> = vector.iter().zip(&self.mean).map(|(v, m)| v - m).collect();
+        super::mat_vec(&self.components, self.output_dim, &centered)
+    }
+
+    async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.input_dim as u32).await?;
+        writer.write_u32_le(self.output_dim as u32).await?;
+        super::write_floats(writer, &self.mean).await?;
+        super::write_floats(writer, &self.components).await
+    }
+
+    async fn deserialize(&mut self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        self.input_dim = reader.read_u32_le().await? as usize;
+        self.output_dim = reader.read_u32_le().await? as usize;
+        self.mean = super::read_floats(reader).await?;
+        self.components = super::read_floats(reader).await?;
+        if self.mean.len() != self.input_dim
+            || self.components.len() != self.input_dim * self.output_dim
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "pca matrix size mismatch",
+            ));
+        }
+        Ok(())
+    }
+}
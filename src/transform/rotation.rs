@@ -0,0 +1,95 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{linalg, TransformType, VectorTransform};
+use crate::{error::Result, VectorAccessor};
+use async_trait::async_trait;
+use std::{f64::consts::PI, io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// rotates the vectors by a random orthogonal matrix, which spreads the variance
+// evenly over the dimensions and preserves the distances
+#[derive(Default)]
+pub struct RandomRotation {
+    dim: usize,
+    // dim x dim
+    matrix: Vec<f32>,
+}
+
+impl RandomRotation {
+    pub fn new(dim: usize) -> Self {
+        Self {
+            dim,
+            matrix: Vec::new(),
+        }
+    }
+}
+
+// the orthonormalized gaussian matrix is uniformly distributed over the rotations
+pub(crate) fn random_orthogonal(dim: usize) -> Vec<f64> {
+    let mut matrix: Vec<_> = (0..dim * dim)
+        .map(|_| {
+            // Box-Muller transform
+            let u = 1.0 - rand::random::<f64>();
+            let v = rand::random::<f64>();
+            (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+        })
+        .collect();
+    linalg::orthonormalize(&mut matrix, dim);
+    matrix
+}
+
+#[async_trait]
+impl VectorTransform for RandomRotation {
+    fn transform_type(&self) -> TransformType {
+        TransformType::RandomRotation
+    }
+
+    fn input_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn output_dim(&self) -> usize {
+        self.dim
+    }
+
+    fn train(&mut self, _vectors: Arc<dyn VectorAccessor>) -> Result<()> {
+        self.matrix = random_orthogonal(self.dim)
+            .iter()
+            .map(|v| *v as f32)
+            .collect();
+        Ok(())
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        super::mat_vec(&self.matrix, self.dim, vector)
+    }
+
+    async fn serialize(&self, writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+        writer.write_u32_le(self.dim as u32).await?;
+        super::write_floats(writer, &self.matrix).await
+    }
+
+    async fn deserialize(&mut self, reader: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        self.dim = reader.read_u32_le().await? as usize;
+        self.matrix = super::read_floats(reader).await?;
+        if self.matrix.len() != self.dim * self.dim {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "rotation matrix size mismatch",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2023 yah01
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    error::{Error, Result},
+    index::quantizer::select_nearest,
+    metric::MetricType,
+    params::{BuildParams, IvfSearchParams, SearchParams, VamanaSearchParams},
+    AnnIndex, SearchOption, VectorAccessor,
+};
+use roaring::RoaringTreemap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct TuneOption {
+    pub metric_type: MetricType,
+    pub topk: usize,
+    // the min recall@topk of the chosen params
+    pub target_recall: f32,
+    // the params to sweep, see candidates
+    pub candidates: Vec<SearchParams>,
+    // the refine factors to sweep with each of the params, see refine_factors,
+    // empty to search with the factor of the index
+    pub refine_factors: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TunePoint {
+    pub params: SearchParams,
+    pub refine_factor: Option<usize>,
+    // the mean recall@topk of the queries
+    pub recall: f32,
+    // the mean latency of the queries
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TuneResult {
+    // all measured points, in the order of the candidates, then the refine factors
+    pub points: Vec<TunePoint>,
+    // the points no other point is both faster and more accurate than,
+    // ordered by latency
+    pub frontier: Vec<TunePoint>,
+    // the fastest point reaching the target recall
+    pub best: Option<TunePoint>,
+}
+
+// the search params to sweep for the index trained with the build params,
+// from the cheapest to the most accurate
+pub fn candidates(params: &BuildParams) -> Vec<SearchParams> {
+    let mut candidates = Vec::new();
+    match params {
+        BuildParams::Flat => candidates.push(SearchParams::Flat),
+        BuildParams::Ivf(ivf) | BuildParams::IvfPq(ivf, _) => {
+            let mut nprobe = 1;
+            while nprobe < ivf.nlist {
+                candidates.push(SearchParams::Ivf(IvfSearchParams {
+                    nprobe,
+                    ..Default::default()
+                }));
+                nprobe *= 2;
+            }
+            candidates.push(SearchParams::Ivf(IvfSearchParams {
+                nprobe: ivf.nlist,
+                ..Default::default()
+            }));
+        }
+        // no index is built with the hnsw params yet
+        BuildParams::Hnsw(_) => {}
+        BuildParams::Vamana(vamana, _) => {
+            let mut search_list_size = 16;
+            while search_list_size <= vamana.search_list_size.max(256) {
+                candidates.push(SearchParams::Vamana(VamanaSearchParams {
+                    search_list_size,
+                    ..Default::default()
+                }));
+                search_list_size *= 2;
+            }
+        }
+    }
+    candidates
+}
+
+// the refine factors to sweep for the refine index, from the cheapest
+pub fn refine_factors() -> Vec<usize> {
+    vec![1, 2, 4, 8, 16]
+}
+
+// the exact topk ids of each query by brute force, the nearest first
+pub fn ground_truth(
+    metric_type: MetricType,
+    vectors: &dyn VectorAccessor,
+    queries: &dyn VectorAccessor,
+    topk: usize,
+) -> Vec<Vec<usize>> {
+    (0..queries.len())
+        .map(|q| {
+            let query = queries.get(q);
+            let distances = (0..vectors.len())
+                .map(|i| (i, metric_type.distance(query, vectors.get(i))))
+                .collect();
+            select_nearest(distances, topk)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect()
+        })
+        .collect()
+}
+
+pub fn recall(result: &[usize], truth: &[usize]) -> f32 {
+    if truth.is_empty() {
+        return 1.0;
+    }
+    let hits = result.iter().filter(|id| truth.contains(id)).count();
+    hits as f32 / truth.len() as f32
+}
+
+// measures each candidate on the queries against the ground truth computed
+// from the vectors the index is built on
+pub fn tune(
+    index: &dyn AnnIndex,
+    vectors: &dyn VectorAccessor,
+    queries: &dyn VectorAccessor,
+    option: &TuneOption,
+) -> Result<TuneResult> {
+    if option.topk == 0 {
+        return Err(Error::InvalidArgument("topk must be positive".to_string()));
+    }
+    if queries.len() == 0 {
+        return Err(Error::InvalidArgument("no queries to tune".to_string()));
+    }
+    if queries.dim() != vectors.dim() {
+        return Err(Error::InvalidArgument(format!(
+            "expect {}-d queries, got {}-d",
+            vectors.dim(),
+            queries.dim()
+        )));
+    }
+    if index.build_params().is_none() {
+        return Err(Error::InvalidArgument(
+            "can't tune untrained index".to_string(),
+        ));
+    }
+    let refine_factors = match option.refine_factors.is_empty() {
+        true => vec![None],
+        false => option.refine_factors.iter().copied().map(Some).collect(),
+    };
+    let mut search_options = Vec::new();
+    for params in &option.candidates {
+        for refine_factor in &refine_factors {
+            let search_option = SearchOption {
+                topk: option.topk,
+                params: *params,
+                refine_factor: *refine_factor,
+            };
+            index.validate_search(&search_option)?;
+            search_options.push(search_option);
+        }
+    }
+
+    let truth = ground_truth(option.metric_type, vectors, queries, option.topk);
+    let deleted = RoaringTreemap::new();
+    let mut points = Vec::with_capacity(search_options.len());
+    for search_option in search_options {
+        let mut recall_sum = 0f32;
+        let start = Instant::now();
+        for (q, truth) in truth.iter().enumerate() {
+            let result = index.search(queries.get(q), &deleted, &search_option);
+            recall_sum += recall(&result, truth);
+        }
+        points.push(TunePoint {
+            params: search_option.params,
+            refine_factor: search_option.refine_factor,
+            recall: recall_sum / queries.len() as f32,
+            latency: start.elapsed() / queries.len() as u32,
+        });
+    }
+
+    let mut sorted = points.clone();
+    sorted.sort_by(|a, b| {
+        a.latency
+            .cmp(&b.latency)
+            .then(b.recall.total_cmp(&a.recall))
+    });
+    let mut frontier: Vec<TunePoint> = Vec::new();
+    for point in sorted {
+        if frontier
+            .last()
+            .is_none_or(|last| point.recall > last.recall)
+        {
+            frontier.push(point);
+        }
+    }
+    // the frontier holds the fastest point of each recall level
+    let best = frontier
+        .iter()
+        .find(|p| p.recall >= option.target_recall)
+        .copied();
+
+    Ok(TuneResult {
+        points,
+        frontier,
+        best,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accessor::MemoryVectorAccessor;
+    use crate::index::flat::Flat;
+    use crate::index::ivf::Ivf;
+    use crate::params::*;
+    use crate::test_util::gen_floats;
+    use crate::tune::*;
+    use crate::TrainOption;
+    use std::sync::Arc;
+
+    const DIM: usize = 32;
+    const CLUSTER_NUM: usize = 32;
+    const DATASET_SIZE: usize = 1024;
+
+    #[test]
+    fn test_tune() {
+        let vectors = Arc::new(MemoryVectorAccessor::new(
+            DIM,
+            gen_floats(DATASET_SIZE * DIM),
+        ));
+        let queries = MemoryVectorAccessor::new(DIM, gen_floats(64 * DIM));
+        let topk = 10;
+
+        // the flat index is exact
+        let mut flat = Flat::new(vectors.clone());
+        flat.train(&TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::Flat,
+        });
+        let truth = ground_truth(MetricType::L2, vectors.as_ref(), &queries, topk);
+        let deleted = RoaringTreemap::new();
+        for (q, truth) in truth.iter().enumerate() {
+            let option = SearchOption {
+                topk,
+                params: SearchParams::Flat,
+                refine_factor: None,
+            };
+            let result = flat.search(queries.get(q), &deleted, &option);
+            assert_eq!(recall(&result, truth), 1.0);
+        }
+
+        let mut ivf = Ivf::new(vectors.clone());
+        ivf.train(&TrainOption {
+            metric_type: MetricType::L2,
+            params: BuildParams::Ivf(IvfBuildParams {
+                nlist: CLUSTER_NUM,
+                ..Default::default()
+            }),
+        });
+        let option = TuneOption {
+            metric_type: MetricType::L2,
+            topk,
+            target_recall: 0.9,
+            candidates: candidates(&ivf.build_params().unwrap()),
+            refine_factors: Vec::new(),
+        };
+        assert_eq!(option.candidates.len(), 6);
+        let result = tune(&ivf, vectors.as_ref(), &queries, &option).unwrap();
+        assert_eq!(result.points.len(), option.candidates.len());
+        // scanning all lists is exact
+        assert_eq!(result.points.last().unwrap().recall, 1.0);
+
+        assert!(!result.frontier.is_empty());
+        for pair in result.frontier.windows(2) {
+            assert!(pair[0].latency <= pair[1].latency);
+            assert!(pair[0].recall < pair[1].recall);
+        }
+        let best = result.best.unwrap();
+        assert!(best.recall >= option.target_recall);
+        assert!(result
+            .points
+            .iter()
+            .all(|p| p.recall < option.target_recall || p.latency >= best.latency));
+
+        // the ivf index can't be searched with hnsw params
+        let mismatched = TuneOption {
+            candidates: vec![SearchParams::Hnsw(Default::default())],
+            ..option.clone()
+        };
+        assert!(tune(&ivf, vectors.as_ref(), &queries, &mismatched).is_err());
+
+        let untrained = Ivf::new(vectors.clone());
+        assert!(tune(&untrained, vectors.as_ref(), &queries, &option).is_err());
+    }
+}